@@ -1,142 +1,1050 @@
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::mem;
 
 use wasm_bindgen::prelude::*;
 
-use crate::parser::{parse_lc3_file, Operand, OperandTypes, ParsedOpCode};
-
-macro_rules! shrink_imm {
-    ($value:expr, $size:expr) => {{
-        // Shift the value to the left, discarding the higher bits, then shift back
-        // to preserve the sign extension for two's complement
-        ($value << (16 - $size)) >> (16 - $size)
-    }};
-}
-
-pub fn emit_error(
-    line_number: usize,
-    file_content: &Vec<String>,
-    error: &str,
-) -> Result<(), String> {
-    return Err(format!(
-        "Error at line {}: {}\n --> {}",
-        line_number,
-        file_content.get(line_number).unwrap(),
-        error
-    ));
-}
-
-pub fn check_imm_bounds(
-    line_number: usize,
-    file_content: &Vec<String>,
-    value: u16,
-    sign: bool,
-    size: u16,
-) -> Result<(), String> {
-    if ((value >> (size - 1)) & 1 == 1) == !sign {
-        // If value is negative BUT it's sign was positive it means the user overflowed.
-
-        let error = format!(
-            "Value overflow. Max values are in the range of -{} and {}. (Limited to {} bytes)",
-            (1 << (size - 1)) as i16,
-            (u16::MAX >> (16 - size + 1)),
-            size
-        );
+use crate::debuginfo::{DebugInfo, LabelRange, SourceLocation, WordKind};
+use crate::diagnostic::{render_all, Diagnostic};
+use crate::expr::{wrap16, Expr};
+use crate::link::{Module, Relocation, RelocationKind};
+use crate::opcode::OpCode;
+use crate::parser::{parse_files, Operand, OperandTypes, ParsedLine, ParsedOpCode};
+use crate::source::{MemoryFiles, SourceFile, SourceProvider};
+
+// Column of the offending operand, if any, and what is wrong.
+type EncodeError = (Option<usize>, String);
 
-        emit_error(line_number, file_content, &error)?;
+// Encodes value in a two's complement field of size bits.
+fn signed(value: i32, size: u32) -> Result<u16, String> {
+    let (min, max) = (-(1 << (size - 1)), (1 << (size - 1)) - 1);
+    if !(min..=max).contains(&value) {
+        return Err(format!(
+            "Value {} does not fit in {} bits (from {} to {}).",
+            value, size, min, max
+        ));
     }
-    dbg!(value, value >> size);
-    if (value >> size) != 0 && (value >> size) != (u16::MAX >> size) {
-        // If raw value is in bounds
-        let error = format!(
-            "Value overflow. Max values are in the range of -{} and {}. (Limited to {} bytes)",
-            (1 << (size - 1)) as i16,
-            (u16::MAX >> (16 - size + 1)),
-            size
-        );
+    Ok(value as u16 & (u16::MAX >> (16 - size)))
+}
+
+fn unsigned(value: i32, size: u32) -> Result<u16, String> {
+    let max = (1 << size) - 1;
+    if !(0..=max).contains(&value) {
+        return Err(format!(
+            "Value {} does not fit in {} bits (from 0 to {}).",
+            value, size, max
+        ));
+    }
+    Ok(value as u16)
+}
+
+// Full 16 bit words wrap, like the arithmetic of the LC-3: xFFFF+1 is 0.
+fn word(value: i32) -> u16 {
+    value as u16
+}
+
+// Labels and .EQU/.SET constants visible from the line being assembled.
+#[derive(Debug, Default)]
+struct Scope {
+    labels: HashMap<String, u16>,
+    constants: HashMap<String, i32>,
+    // Symbols of other modules, worth 0 until the linker adds their address
+    externals: HashSet<String>,
+    // Constants of the whole file in the second pass, only usable below their definition
+    defined: HashSet<String>,
+}
+
+impl Scope {
+    fn lookup(&self, name: &str) -> Option<i32> {
+        self.constants
+            .get(name)
+            .copied()
+            .or_else(|| self.labels.get(name).map(|&address| address as i32))
+            .or_else(|| self.externals.contains(name).then_some(0))
+    }
+
+    // How much the value of expr grows when the symbols picked by moved grow by one: 1 for
+    // an address moving along with them, 0 for a value not depending on them. None for
+    // anything else.
+    fn growth(&self, expr: &Expr, moved: &dyn Fn(&str) -> bool) -> Option<i32> {
+        let value = |shift: i32| {
+            expr.eval(&|name| {
+                let value = self.lookup(name)?;
+                Some(if moved(name) { value + shift } else { value })
+            })
+            .ok()
+        };
+        let base = value(0)?;
+        match (value(1)? - base, value(2)? - base) {
+            (0, 0) => Some(0),
+            (1, 2) => Some(1),
+            _ => None,
+        }
+    }
+
+    fn external_in<'a>(&self, operand: &'a Operand) -> Option<&'a str> {
+        match &operand.operand_type {
+            OperandTypes::Label(name) => Some(name.as_str()),
+            OperandTypes::Expression(e) => e
+                .symbols()
+                .into_iter()
+                .find(|s| self.externals.contains(*s)),
+            _ => None,
+        }
+        .filter(|name| self.externals.contains(*name))
+    }
+
+    fn value(&self, operand: &Operand) -> Result<i32, EncodeError> {
+        match &operand.operand_type {
+            OperandTypes::Immediate { value, sign } => Ok(if *sign {
+                *value as i16 as i32
+            } else {
+                *value as i32
+            }),
+            OperandTypes::Label(name) => self
+                .lookup(name)
+                .ok_or_else(|| format!("Undefined symbol {}.", name)),
+            OperandTypes::Expression(e) => e.eval(&|name| self.lookup(name)),
+            OperandTypes::Literal(_) => Err("Literals (=value) can only be loaded by LD.".into()),
+            _ => Err("Expected a value.".into()),
+        }
+        .map_err(|message| match self.defined_below(operand) {
+            Some(name) => format!("Constant {} is used before its definition.", name),
+            None => message,
+        })
+        .map_err(|message| (Some(operand.column), message))
+    }
+
+    // A constant used by operand but defined further down.
+    fn defined_below<'a>(&self, operand: &'a Operand) -> Option<&'a str> {
+        let symbols = match &operand.operand_type {
+            OperandTypes::Label(name) => vec![name.as_str()],
+            OperandTypes::Expression(e) => e.symbols(),
+            _ => Vec::new(),
+        };
+        symbols
+            .into_iter()
+            .find(|s| self.defined.contains(*s) && !self.constants.contains_key(*s))
+    }
+
+    // PC-relative operands referring to a label are addresses, other values are offsets.
+    // References to external symbols hold the offset to add to the symbol.
+    fn is_address(&self, operand: &Operand) -> bool {
+        self.external_in(operand).is_none()
+            && match &operand.operand_type {
+                OperandTypes::Label(name) => self.labels.contains_key(name),
+                OperandTypes::Expression(e) => {
+                    e.symbols().iter().any(|s| self.labels.contains_key(*s))
+                }
+                _ => false,
+            }
+    }
+}
+
+// A constant of a literal pool and where it is first loaded, errors being reported there.
+#[derive(Debug)]
+struct Literal {
+    expr: Expr,
+    position: (usize, usize),
+    column: usize,
+}
 
-        emit_error(line_number, file_content, &error)?;
+// Literals emitted together at a .LTORG, or at the end of the program.
+#[derive(Debug, Default)]
+struct Pool {
+    start: u16,
+    literals: Vec<Literal>,
+}
+
+impl Pool {
+    // The literals as .FILL lines, with the position of their first use.
+    fn fills(&self) -> impl Iterator<Item = ((usize, usize), ParsedLine)> + '_ {
+        self.literals.iter().map(|literal| {
+            let operand = Operand {
+                operand_type: OperandTypes::Expression(literal.expr.clone()),
+                column: literal.column,
+            };
+            let fill = ParsedLine::from(None, ParsedOpCode::FILL, vec![operand]);
+            (literal.position, fill)
+        })
+    }
+}
+
+// Operand as an expression of symbols, if it refers to any.
+fn expression(operand: &Operand) -> Option<Expr> {
+    match &operand.operand_type {
+        OperandTypes::Label(name) => Some(Expr::Symbol(name.clone())),
+        OperandTypes::Expression(e) => Some(e.clone()),
+        _ => None,
+    }
+}
+
+// What the linker must patch in the word emitted by the instruction: references to an
+// external symbol and, in a module placed by the linker, addresses stored by .FILL.
+fn relocation(
+    instruction: &ParsedLine,
+    scope: &Scope,
+    relocatable: bool,
+) -> Result<Option<(RelocationKind, Option<String>)>, EncodeError> {
+    let ops = &instruction.operands;
+    let (index, kind) = match instruction.opcode {
+        ParsedOpCode::BR
+        | ParsedOpCode::BRn
+        | ParsedOpCode::BRz
+        | ParsedOpCode::BRp
+        | ParsedOpCode::BRzp
+        | ParsedOpCode::BRzn
+        | ParsedOpCode::BRpn => (0, RelocationKind::PCOffset9),
+        ParsedOpCode::JSR => (0, RelocationKind::PCOffset11),
+        ParsedOpCode::LD
+        | ParsedOpCode::LDI
+        | ParsedOpCode::LEA
+        | ParsedOpCode::ST
+        | ParsedOpCode::STI => (1, RelocationKind::PCOffset9),
+        ParsedOpCode::FILL => (0, RelocationKind::Word),
+        ParsedOpCode::GLOBAL | ParsedOpCode::EXTERNAL | ParsedOpCode::LTORG => return Ok(None),
+        _ => {
+            if let Some((operand, name)) = ops
+                .iter()
+                .find_map(|o| scope.external_in(o).map(|name| (o, name)))
+            {
+                return Err((
+                    Some(operand.column),
+                    format!(
+                        "External symbol {} can only be used by .FILL and PC-relative instructions.",
+                        name
+                    ),
+                ));
+            }
+            return Ok(None);
+        }
+    };
+    let operand = &ops[index];
+    let Some(expr) = expression(operand) else {
+        return Ok(None);
+    };
+    let is_label = |name: &str| scope.labels.contains_key(name);
+    match scope.external_in(operand) {
+        Some(name) => {
+            let moves = scope.growth(&expr, &|s| s == name) == Some(1)
+                && scope.growth(&expr, &|s| s != name && scope.externals.contains(s)) == Some(0)
+                && scope.growth(&expr, &is_label) == Some(0);
+            if !moves {
+                return Err((
+                    Some(operand.column),
+                    format!("External symbol {} can only be offset by a constant.", name),
+                ));
+            }
+            Ok(Some((kind, Some(name.to_string()))))
+        }
+        None if kind == RelocationKind::Word && relocatable => {
+            match scope.growth(&expr, &is_label) {
+                Some(0) => Ok(None),
+                Some(_) => Ok(Some((kind, None))),
+                None => Err((
+                    Some(operand.column),
+                    "Only addresses plus or minus a constant can be relocated.".into(),
+                )),
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+fn name(opcode: &ParsedOpCode) -> String {
+    match opcode {
+        ParsedOpCode::FILL
+        | ParsedOpCode::BLKW
+        | ParsedOpCode::STRINGZ
+        | ParsedOpCode::EQU
+        | ParsedOpCode::SET
+        | ParsedOpCode::GLOBAL
+        | ParsedOpCode::EXTERNAL
+        | ParsedOpCode::LTORG => format!(".{:?}", opcode),
+        _ => format!("{:?}", opcode),
+    }
+}
+
+fn expect_operands(instruction: &ParsedLine, count: usize) -> Result<(), EncodeError> {
+    if instruction.operands.len() != count {
+        return Err((
+            None,
+            format!(
+                "{} expects {} operand(s), got {}.",
+                name(&instruction.opcode),
+                count,
+                instruction.operands.len()
+            ),
+        ));
     }
     Ok(())
 }
 
+fn register(operand: &Operand) -> Result<u16, EncodeError> {
+    match operand.operand_type {
+        OperandTypes::Register(reg) => Ok(reg as u16),
+        _ => Err((Some(operand.column), "Expected a register.".into())),
+    }
+}
+
+// Number of words emitted by the instruction.
+fn size(instruction: &ParsedLine, scope: &Scope) -> Result<u16, EncodeError> {
+    match instruction.opcode {
+        ParsedOpCode::EQU | ParsedOpCode::SET | ParsedOpCode::GLOBAL | ParsedOpCode::EXTERNAL => {
+            Ok(0)
+        }
+        // The pool is counted by the assembler
+        ParsedOpCode::LTORG => expect_operands(instruction, 0).map(|_| 0),
+        ParsedOpCode::BLKW => {
+            expect_operands(instruction, 1)?;
+            let operand = &instruction.operands[0];
+            unsigned(scope.value(operand)?, 16).map_err(|e| (Some(operand.column), e))
+        }
+        ParsedOpCode::STRINGZ => match instruction.operands.as_slice() {
+            [Operand {
+                operand_type: OperandTypes::String(s),
+                ..
+            }] => Ok(s.len() as u16 + 1),
+            [operand] => Err((Some(operand.column), "Expected a string.".into())),
+            _ => expect_operands(instruction, 1).map(|_| 1),
+        },
+        _ => Ok(1),
+    }
+}
+
+// Words emitted by the instruction located at pc.
+fn encode(instruction: &ParsedLine, pc: u16, scope: &Scope) -> Result<Vec<u16>, EncodeError> {
+    let ops = &instruction.operands;
+    let field = |i: usize, encode: fn(i32, u32) -> Result<u16, String>, size: u32| {
+        encode(scope.value(&ops[i])?, size).map_err(|e| (Some(ops[i].column), e))
+    };
+    let pc_offset = |i: usize, size: u32| {
+        let operand = &ops[i];
+        if !scope.is_address(operand) {
+            return field(i, signed, size);
+        }
+        let target = word(scope.value(operand)?);
+        let offset = target.wrapping_sub(pc.wrapping_add(1)) as i16 as i32;
+        signed(offset, size).map_err(|_| {
+            (
+                Some(operand.column),
+                format!(
+                    "x{:04X} is too far: offset {} does not fit in {} bits.",
+                    target, offset, size
+                ),
+            )
+        })
+    };
+    let trap = |vector: u16| u16::from(OpCode::TRAP) | vector;
+
+    let assembled = match instruction.opcode {
+        ParsedOpCode::ADD | ParsedOpCode::AND => {
+            expect_operands(instruction, 3)?;
+            let base = if instruction.opcode == ParsedOpCode::ADD {
+                OpCode::ADD
+            } else {
+                OpCode::AND
+            };
+            let last = match ops[2].operand_type {
+                OperandTypes::Register(reg) => reg as u16,
+                _ => 1 << 5 | field(2, signed, 5)?,
+            };
+            u16::from(base) | register(&ops[0])? << 9 | register(&ops[1])? << 6 | last
+        }
+        ParsedOpCode::NOT => {
+            expect_operands(instruction, 2)?;
+            u16::from(OpCode::NOT) | register(&ops[0])? << 9 | register(&ops[1])? << 6 | 0x3F
+        }
+        ParsedOpCode::BR
+        | ParsedOpCode::BRn
+        | ParsedOpCode::BRz
+        | ParsedOpCode::BRp
+        | ParsedOpCode::BRzp
+        | ParsedOpCode::BRzn
+        | ParsedOpCode::BRpn => {
+            expect_operands(instruction, 1)?;
+            let nzp: u16 = match instruction.opcode {
+                ParsedOpCode::BRn => 0b100,
+                ParsedOpCode::BRz => 0b010,
+                ParsedOpCode::BRp => 0b001,
+                ParsedOpCode::BRzp => 0b011,
+                ParsedOpCode::BRzn => 0b110,
+                ParsedOpCode::BRpn => 0b101,
+                _ => 0b111,
+            };
+            u16::from(OpCode::BR) | nzp << 9 | pc_offset(0, 9)?
+        }
+        ParsedOpCode::JMP => {
+            expect_operands(instruction, 1)?;
+            u16::from(OpCode::JMP) | register(&ops[0])? << 6
+        }
+        ParsedOpCode::RET => {
+            expect_operands(instruction, 0)?;
+            u16::from(OpCode::JMP) | 7 << 6
+        }
+        ParsedOpCode::JSR => {
+            expect_operands(instruction, 1)?;
+            u16::from(OpCode::JSR) | 1 << 11 | pc_offset(0, 11)?
+        }
+        ParsedOpCode::JSRR => {
+            expect_operands(instruction, 1)?;
+            u16::from(OpCode::JSR) | register(&ops[0])? << 6
+        }
+        ParsedOpCode::LD
+        | ParsedOpCode::LDI
+        | ParsedOpCode::LEA
+        | ParsedOpCode::ST
+        | ParsedOpCode::STI => {
+            expect_operands(instruction, 2)?;
+            let base = match instruction.opcode {
+                ParsedOpCode::LD => OpCode::LD,
+                ParsedOpCode::LDI => OpCode::LDI,
+                ParsedOpCode::LEA => OpCode::LEA,
+                ParsedOpCode::ST => OpCode::ST,
+                _ => OpCode::STI,
+            };
+            u16::from(base) | register(&ops[0])? << 9 | pc_offset(1, 9)?
+        }
+        ParsedOpCode::LDR | ParsedOpCode::STR => {
+            expect_operands(instruction, 3)?;
+            let base = if instruction.opcode == ParsedOpCode::LDR {
+                OpCode::LDR
+            } else {
+                OpCode::STR
+            };
+            u16::from(base)
+                | register(&ops[0])? << 9
+                | register(&ops[1])? << 6
+                | field(2, signed, 6)?
+        }
+        ParsedOpCode::RTI => {
+            expect_operands(instruction, 0)?;
+            u16::from(OpCode::RTI)
+        }
+        ParsedOpCode::TRAP => {
+            expect_operands(instruction, 1)?;
+            trap(field(0, unsigned, 8)?)
+        }
+        ParsedOpCode::GETC
+        | ParsedOpCode::OUT
+        | ParsedOpCode::PUTS
+        | ParsedOpCode::IN
+        | ParsedOpCode::PUTSP
+        | ParsedOpCode::HALT => {
+            expect_operands(instruction, 0)?;
+            trap(match instruction.opcode {
+                ParsedOpCode::GETC => 0x20,
+                ParsedOpCode::OUT => 0x21,
+                ParsedOpCode::PUTS => 0x22,
+                ParsedOpCode::IN => 0x23,
+                ParsedOpCode::PUTSP => 0x24,
+                _ => 0x25,
+            })
+        }
+        ParsedOpCode::FILL => {
+            expect_operands(instruction, 1)?;
+            word(scope.value(&ops[0])?)
+        }
+        ParsedOpCode::BLKW => return Ok(vec![0; size(instruction, scope)? as usize]),
+        ParsedOpCode::STRINGZ => {
+            size(instruction, scope)?;
+            let OperandTypes::String(s) = &ops[0].operand_type else {
+                unreachable!("size checks the operand is a string")
+            };
+            return Ok(s.bytes().map(u16::from).chain([0]).collect());
+        }
+        ParsedOpCode::EQU
+        | ParsedOpCode::SET
+        | ParsedOpCode::GLOBAL
+        | ParsedOpCode::EXTERNAL
+        | ParsedOpCode::LTORG => return Ok(Vec::new()),
+    };
+    Ok(vec![assembled])
+}
+
+/// A label and the address it stands for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Symbol {
+    pub name: String,
+    pub address: u16,
+}
+
+/// A word emitted by the assembler and the file and (zero based) source line it comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListingLine {
+    pub address: u16,
+    pub word: u16,
+    pub file: usize,
+    // None for the literals emitted at the end of a program without .END
+    pub line: Option<usize>,
+    pub kind: WordKind,
+    // Index in Assembly::expansions of the macro expanded line the word comes from
+    pub expansion: Option<usize>,
+}
+
+/// Result of assembling a file: the object and what is needed to trace it back to the source.
 #[wasm_bindgen]
-pub fn assemble_file(file_content: Vec<String>) -> Result<Vec<u16>, String> {
-    let mut output: Vec<u16> = Vec::new();
-    let parsed_file = parse_lc3_file(file_content.clone())?;
-    output.push(parsed_file.orig);
-    let mut symbol_table = HashMap::new();
-    // First pass: Labels
-    println!("First pass");
-    parsed_file
-        .instructions
-        .iter()
-        .enumerate()
-        .filter(|(_, p)| p.is_some())
-        .map(|(i, p)| (i, p.as_ref().unwrap()))
-        .for_each(|(index, instruction)| {
-            if let Some(label) = instruction.label.as_ref() {
-                symbol_table.insert(label, index - 1); // ORIG counts as an index
-                println!("Label {} at position {}", label, index);
+#[derive(Debug, Clone)]
+pub struct Assembly {
+    object: Vec<u16>,
+    symbols: Vec<Symbol>,
+    listing: Vec<ListingLine>,
+    // Lines produced by macro expansions
+    expansions: Vec<String>,
+    // The main file followed by the files it includes
+    files: Vec<SourceFile>,
+    // File index and line of every source line, in the order they were read
+    positions: Vec<(usize, usize)>,
+    // False for a module without .ORIG, assembled at x0000 and placed by the linker
+    has_orig: bool,
+    globals: Vec<String>,
+    externals: Vec<String>,
+    relocations: Vec<Relocation>,
+}
+
+impl Assembly {
+    // Symbols in definition order
+    pub fn symbols(&self) -> &[Symbol] {
+        &self.symbols
+    }
+    pub fn symbol(&self, name: &str) -> Option<u16> {
+        self.symbols
+            .iter()
+            .find(|s| s.name == name)
+            .map(|s| s.address)
+    }
+    pub fn listing(&self) -> &[ListingLine] {
+        &self.listing
+    }
+    pub fn files(&self) -> &[SourceFile] {
+        &self.files
+    }
+
+    // Whether the object must go through the linker rather than be loaded as is.
+    pub fn is_module(&self) -> bool {
+        !self.has_orig || !self.globals.is_empty() || !self.externals.is_empty()
+    }
+
+    // Relocatable object for the linker, name being used in link errors.
+    pub fn module(&self, name: &str) -> Module {
+        let base = self.object[0];
+        Module {
+            name: name.to_string(),
+            orig: self.has_orig.then_some(base),
+            words: self.object[1..].to_vec(),
+            symbols: self
+                .symbols
+                .iter()
+                .map(|s| Symbol {
+                    name: s.name.clone(),
+                    address: s.address.wrapping_sub(base),
+                })
+                .collect(),
+            globals: self.globals.clone(),
+            externals: self.externals.clone(),
+            relocations: self.relocations.clone(),
+        }
+    }
+
+    // Error at the first word emitted at or after offset in the object.
+    fn error_at(&self, offset: usize, message: &str) -> String {
+        let address = self.object[0].wrapping_add(offset as u16);
+        let entry = self.listing.iter().find(|l| l.address >= address);
+        let diagnostic = match entry.and_then(|l| Some((l.file, l.line?))) {
+            Some((file, line)) => {
+                Diagnostic::error(line + 1, indent(&self.files[file].lines[line]), message)
+                    .in_file(file)
             }
-        });
-    println!("Second pass");
-    let fc = &file_content;
-    for (ln, instruction) in parsed_file
-        .instructions
-        .iter()
-        .enumerate()
-        .filter(|(_, p)| p.is_some())
-        .map(|(i, p)| (i + 1, p.as_ref().unwrap()))
-    {
-        match instruction.opcode {
-            ParsedOpCode::ADD => {
-                let mut assembled: u16 = crate::OpCode::ADD.into();
-                println!("OP: {:#b}", assembled);
-                dbg!(&instruction.operands);
-                if instruction.operands.len() != 3 {
-                    emit_error(ln, fc, "Add should have 3 operands.")?;
-                }
+            None => Diagnostic::error(1, 1, message),
+        };
+        render_all(&[diagnostic], &self.files)
+    }
 
-                match instruction.operands.get(0).unwrap().operand_type {
-                    OperandTypes::Register(reg) => {
-                        assembled = assembled | ((reg as u16) << 9);
-                    }
-                    _ => emit_error(ln, fc, "First argument of ADD must be a register.")?, // TODO: INVALID DR
+    // Checks the object can be loaded without linking.
+    fn absolute(self) -> Result<Assembly, String> {
+        if !self.has_orig {
+            return Err(self.error_at(0, "The file should start with a .ORIG directive"));
+        }
+        if let Some(r) = self.relocations.iter().find(|r| r.symbol.is_some()) {
+            return Err(self.error_at(
+                r.offset as usize,
+                &format!(
+                    "{} is external: assemble the file as a module and link it.",
+                    r.symbol.as_deref().unwrap_or_default()
+                ),
+            ));
+        }
+        Ok(self)
+    }
+}
+
+// Symbol table in the format of lc3as .sym files
+pub fn symbol_file(symbols: &[Symbol]) -> String {
+    let mut out = String::from("// Symbol table\n");
+    out.push_str("// Scope level 0:\n");
+    out.push_str("//\tSymbol Name       Page Address\n");
+    out.push_str("//\t----------------  ------------\n");
+    for symbol in symbols {
+        out.push_str(&format!(
+            "//\t{:<16}  {:04X}\n",
+            symbol.name, symbol.address
+        ));
+    }
+    out.push('\n');
+    out
+}
+
+#[wasm_bindgen]
+impl Assembly {
+    // Origin followed by the assembled words, as expected by Core::load_obj
+    pub fn object(&self) -> Vec<u16> {
+        self.object.clone()
+    }
+
+    // Symbol table in the format of lc3as .sym files
+    pub fn symbol_file(&self) -> String {
+        symbol_file(&self.symbols)
+    }
+
+    // Every source line, preceded by the address, hex and binary of the words it emitted.
+    // Macro invocations are followed by their expansion, one line per instruction, marked by +.
+    // Included files are listed after their .INCLUDE, with their own line numbers. The literals
+    // emitted at the end of a program without .END come last, without a line.
+    pub fn listing_file(&self) -> String {
+        let mut out = format!(
+            "{:<6} {:<4} {:<16} {:>5}  {}\n",
+            "Addr", "Hex", "Binary", "Line", "Source"
+        );
+        let mut words = self.listing.iter().peekable();
+        for &(file, i) in &self.positions {
+            let source = &self.files[file].lines[i];
+            let mut first = true;
+            let mut expansion = None;
+            while let Some(entry) = words.next_if(|w| (w.file, w.line) == (file, Some(i))) {
+                if first && entry.expansion.is_some() {
+                    out.push_str(&format!("{:<28} {:>5}  {}\n", "", i + 1, source));
                 }
-                match instruction.operands.get(1).unwrap().operand_type {
-                    OperandTypes::Register(reg) => {
-                        assembled = assembled | ((reg as u16) << 6);
+                let text = match entry.expansion {
+                    None if first => source.clone(),
+                    Some(e) if expansion != Some(e) => format!("+ {}", self.expansions[e]),
+                    _ => String::new(),
+                };
+                expansion = entry.expansion;
+                out.push_str(&format!(
+                    "x{:04X}  {:04X} {:016b} {:>5}  {}\n",
+                    entry.address,
+                    entry.word,
+                    entry.word,
+                    i + 1,
+                    text
+                ));
+                first = false;
+            }
+            if first {
+                out.push_str(&format!("{:<28} {:>5}  {}\n", "", i + 1, source));
+            }
+        }
+        for entry in words {
+            out.push_str(&format!(
+                "x{:04X}  {:04X} {:016b}\n",
+                entry.address, entry.word, entry.word
+            ));
+        }
+        out
+    }
+
+    // Source map of the object, file_name being the name of the assembled file. Included
+    // files are named relative to the directory of the main file.
+    pub fn debug_info(&self, file_name: &str) -> DebugInfo {
+        let main = &self.files[0].name;
+        let dir = &main[..main.rfind('/').map_or(0, |i| i + 1)];
+        let mut files: Vec<String> = self
+            .files
+            .iter()
+            .map(|f| f.name.strip_prefix(dir).unwrap_or(&f.name).to_string())
+            .collect();
+        files[0] = file_name.to_string();
+        let locations = self
+            .listing
+            .iter()
+            .filter_map(|entry| {
+                let line = entry.line?;
+                let source = &self.files[entry.file].lines[line];
+                Some(SourceLocation {
+                    address: entry.address,
+                    file: entry.file,
+                    line: line + 1,
+                    column: source.len() - source.trim_start().len() + 1,
+                    kind: entry.kind,
+                })
+            })
+            .collect();
+        // A label spans until the next label or the end of the object
+        let end = self.object[0].wrapping_add(self.object.len() as u16 - 1);
+        let mut starts: Vec<u16> = self.symbols.iter().map(|s| s.address).collect();
+        starts.sort();
+        let labels = self
+            .symbols
+            .iter()
+            .map(|symbol| LabelRange {
+                name: symbol.name.clone(),
+                start: symbol.address,
+                end: starts
+                    .iter()
+                    .copied()
+                    .find(|&start| start > symbol.address)
+                    .unwrap_or(end),
+            })
+            .collect();
+        DebugInfo::new(files, locations, labels)
+    }
+}
+
+// Reads back the symbols of a .sym file written by symbol_file (or lc3as).
+pub fn parse_symbol_file(content: &str) -> Vec<Symbol> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.strip_prefix("//")?.split_whitespace();
+            let name = fields.next()?;
+            let address = u16::from_str_radix(fields.next()?, 16).ok()?;
+            if fields.next().is_some() {
+                return None;
+            }
+            Some(Symbol {
+                name: name.to_string(),
+                address,
+            })
+        })
+        .collect()
+}
+
+#[wasm_bindgen]
+pub fn assemble_file(file_content: Vec<String>) -> Result<Vec<u16>, String> {
+    assemble(file_content).map(|assembly| assembly.object)
+}
+
+// Column of the first character of the line, where errors without operand are reported.
+pub(crate) fn indent(line: &str) -> usize {
+    line.len() - line.trim_start().len() + 1
+}
+
+// Assembles a single file, which cannot include others.
+#[wasm_bindgen]
+pub fn assemble(file_content: Vec<String>) -> Result<Assembly, String> {
+    let main = SourceFile {
+        name: String::new(),
+        lines: file_content,
+    };
+    assemble_files(vec![main], &MemoryFiles::new()).and_then(Assembly::absolute)
+}
+
+// Assembles the main file of the web editor, files holding it and the files it includes.
+#[wasm_bindgen]
+pub fn assemble_project(main: &str, files: &MemoryFiles) -> Result<Assembly, String> {
+    assemble_with(main, files)
+}
+
+// Assembles the file at path main, reading it and the files it includes through provider.
+pub fn assemble_with(main: &str, provider: &dyn SourceProvider) -> Result<Assembly, String> {
+    assemble_module(main, provider).and_then(Assembly::absolute)
+}
+
+// Same as assemble_with, the file being allowed to omit .ORIG and to use external symbols.
+pub fn assemble_module(main: &str, provider: &dyn SourceProvider) -> Result<Assembly, String> {
+    let main = provider.resolve("", main);
+    let content = provider.read(&main)?;
+    assemble_files(vec![SourceFile::new(&main, &content)], provider)
+}
+
+fn assemble_files(
+    mut files: Vec<SourceFile>,
+    provider: &dyn SourceProvider,
+) -> Result<Assembly, String> {
+    let mut output: Vec<u16> = Vec::new();
+    let parsed_file = parse_files(&mut files, provider)
+        .map_err(|diagnostics| render_all(&diagnostics, &files))?;
+    let base = parsed_file.orig.unwrap_or(0);
+    output.push(base);
+    let lines = || {
+        parsed_file
+            .instructions
+            .iter()
+            .zip(&parsed_file.positions)
+            .flat_map(|(lines, &position)| lines.iter().map(move |p| (position, p)))
+    };
+    let mut errors = Vec::new();
+    let error = |(file, ln): (usize, usize), (column, message): EncodeError| {
+        Diagnostic::error(
+            ln + 1,
+            column.unwrap_or_else(|| indent(&files[file].lines[ln])),
+            message,
+        )
+        .in_file(file)
+    };
+
+    // First pass: Labels and constants. Constants may only use the symbols defined above them.
+    let mut symbols: Vec<Symbol> = Vec::new();
+    let mut scope = Scope::default();
+    let mut equ = HashSet::new();
+    let mut constants = Vec::new();
+    let mut globals: Vec<(String, (usize, usize), usize)> = Vec::new();
+    let mut externals = Vec::new();
+    let mut pools = Vec::new();
+    let mut pending = Pool::default();
+    // Pool and index in the pool of the literal loaded by each LD, in order
+    let mut loads = Vec::new();
+    let mut last = (0, 0);
+    let mut location = base as u32;
+    for (ln, instruction) in lines() {
+        last = ln;
+        if let ParsedOpCode::GLOBAL | ParsedOpCode::EXTERNAL = instruction.opcode {
+            if instruction.operands.is_empty() {
+                let message = format!("{} expects symbol names.", name(&instruction.opcode));
+                errors.push(error(ln, (None, message)));
+            }
+            for operand in &instruction.operands {
+                let OperandTypes::Label(name) = &operand.operand_type else {
+                    errors.push(error(ln, (Some(operand.column), "Expected a name.".into())));
+                    continue;
+                };
+                if instruction.opcode == ParsedOpCode::GLOBAL {
+                    if !globals.iter().any(|(global, ..)| global == name) {
+                        globals.push((name.clone(), ln, operand.column));
                     }
-                    _ => emit_error(ln, fc, "Second argument of ADD must be a register.")?,
+                } else if scope.labels.contains_key(name) || scope.constants.contains_key(name) {
+                    let message = format!("{} is already defined.", name);
+                    errors.push(error(ln, (Some(operand.column), message)));
+                } else if scope.externals.insert(name.clone()) {
+                    externals.push(name.clone());
                 }
-                match instruction.operands.get(2).unwrap().operand_type {
-                    OperandTypes::Register(reg) => {
-                        assembled = assembled | reg as u16;
-                    }
-                    OperandTypes::Immediate { value, sign } => {
-                        check_imm_bounds(ln, fc, value, sign, 5)?;
-                        let value = shrink_imm!(value, 5);
-                        assembled = assembled | (value);
-                        assembled = assembled | (1 << 5);
+            }
+            continue;
+        }
+        if let ParsedOpCode::EQU | ParsedOpCode::SET = instruction.opcode {
+            let name = match &instruction.operands[0].operand_type {
+                OperandTypes::Label(name) => name,
+                _ => unreachable!("the parser checks constant names"),
+            };
+            let redefined = scope.labels.contains_key(name)
+                || scope.externals.contains(name)
+                || equ.contains(name)
+                || (instruction.opcode == ParsedOpCode::EQU && scope.constants.contains_key(name));
+            if redefined {
+                let column = Some(instruction.operands[0].column);
+                errors.push(error(ln, (column, format!("{} is already defined.", name))));
+            }
+            if instruction.opcode == ParsedOpCode::EQU {
+                equ.insert(name.clone());
+            }
+            if let Some(external) = scope.external_in(&instruction.operands[1]) {
+                let column = Some(instruction.operands[1].column);
+                let message = format!("External symbol {} cannot be used in a constant.", external);
+                errors.push(error(ln, (column, message)));
+            }
+            let value = scope
+                .value(&instruction.operands[1])
+                .map(wrap16)
+                .unwrap_or_else(|e| {
+                    errors.push(error(ln, e));
+                    0
+                });
+            scope.constants.insert(name.clone(), value);
+            constants.push(value);
+            continue;
+        }
+        if let Some(label) = instruction.label.as_ref() {
+            if scope.labels.contains_key(label)
+                || scope.constants.contains_key(label)
+                || scope.externals.contains(label)
+            {
+                errors.push(error(
+                    ln,
+                    (None, format!("Label {} is already defined.", label)),
+                ));
+            } else {
+                scope.labels.insert(label.clone(), location as u16);
+                symbols.push(Symbol {
+                    name: label.clone(),
+                    address: location as u16,
+                });
+            }
+        }
+        match (&instruction.opcode, instruction.operands.get(1)) {
+            (ParsedOpCode::LTORG, _) => {
+                pending.start = location as u16;
+                location += pending.literals.len() as u32;
+                pools.push(mem::take(&mut pending));
+            }
+            (
+                ParsedOpCode::LD,
+                Some(Operand {
+                    operand_type: OperandTypes::Literal(expr),
+                    column,
+                }),
+            ) => {
+                let literals = &mut pending.literals;
+                let index = match literals.iter().position(|l| l.expr == *expr) {
+                    Some(index) => index,
+                    None => {
+                        literals.push(Literal {
+                            expr: expr.clone(),
+                            position: ln,
+                            column: *column,
+                        });
+                        literals.len() - 1
                     }
-                    _ => emit_error(
-                        ln,
-                        fc,
-                        "Second argument of ADD must be a register or an immediate.",
-                    )?,
+                };
+                loads.push((pools.len(), index));
+            }
+            _ => (),
+        }
+        match size(instruction, &scope) {
+            Ok(size) => location += size as u32,
+            Err(e) => errors.push(error(ln, e)),
+        }
+        if location > 0x10000 {
+            errors.push(error(
+                ln,
+                (None, "The program does not fit in memory.".into()),
+            ));
+            break;
+        }
+    }
+    if !pending.literals.is_empty() {
+        pending.start = location as u16;
+        location += pending.literals.len() as u32;
+        pools.push(pending);
+        if location > 0x10000 {
+            let message = "The literal pool does not fit in memory.".into();
+            errors.push(error(last, (None, message)));
+        }
+    }
+    for (name, ln, column) in &globals {
+        if !scope.labels.contains_key(name) {
+            let message = format!("Global symbol {} is not a label of this program.", name);
+            errors.push(error(*ln, (Some(*column), message)));
+        }
+    }
+    if !errors.is_empty() {
+        return Err(render_all(&errors, &files));
+    }
+
+    // Second pass: Instructions. Constants take the value they had in the first pass, and
+    // like there cannot be used above their definition.
+    scope.defined = scope.constants.drain().map(|(name, _)| name).collect();
+    let mut constants = constants.into_iter();
+    let mut listing = Vec::new();
+    let mut expansions = Vec::new();
+    let mut relocations = Vec::new();
+    // Pools become .FILL lines listed at their .LTORG, or at the .END for the last pool, and
+    // reporting errors where their literals are first loaded.
+    let mut items = Vec::new();
+    let mut remaining = pools.iter();
+    for (ln, instruction) in lines() {
+        if instruction.opcode == ParsedOpCode::LTORG {
+            let fills = remaining.next().into_iter().flat_map(Pool::fills);
+            items.extend(fills.map(|(at, fill)| (Some(ln), at, Cow::Owned(fill))));
+        } else {
+            items.push((Some(ln), ln, Cow::Borrowed(instruction)));
+        }
+    }
+    let fills = remaining.flat_map(Pool::fills);
+    items.extend(fills.map(|(at, fill)| (parsed_file.end, at, Cow::Owned(fill))));
+    let mut loads = loads.into_iter();
+    for (ln, at, instruction) in &items {
+        let (ln, at) = (*ln, *at);
+        if let ParsedOpCode::EQU | ParsedOpCode::SET = instruction.opcode {
+            if let OperandTypes::Label(name) = &instruction.operands[0].operand_type {
+                scope
+                    .constants
+                    .insert(name.clone(), constants.next().unwrap_or(0));
+            }
+            continue;
+        }
+        let expansion = instruction.expansion.as_ref().map(|text| {
+            expansions.push(text.clone());
+            expansions.len() - 1
+        });
+        let kind = match instruction.opcode {
+            ParsedOpCode::FILL | ParsedOpCode::BLKW | ParsedOpCode::STRINGZ => WordKind::Data,
+            _ => WordKind::Code,
+        };
+        let pc = base.wrapping_add(output.len() as u16 - 1);
+        // LD of a literal loads the word of the pool
+        let loaded = match (&instruction.opcode, instruction.operands.get(1)) {
+            (
+                ParsedOpCode::LD,
+                Some(Operand {
+                    operand_type: OperandTypes::Literal(_),
+                    column,
+                }),
+            ) => {
+                let (pool, index) = loads.next().expect("the first pass records every load");
+                let address = pools[pool].start.wrapping_add(index as u16);
+                let offset = address.wrapping_sub(pc.wrapping_add(1)) as i16 as i32;
+                let mut load = instruction.as_ref().clone();
+                load.operands[1].operand_type = OperandTypes::Immediate {
+                    value: offset as u16,
+                    sign: true,
+                };
+                match signed(offset, 9) {
+                    Ok(_) => Ok(Cow::Owned(load)),
+                    Err(_) => Err((
+                        Some(*column),
+                        format!(
+                            "The literal pool at x{:04X} is too far: offset {} does not fit in 9 bits. Add a .LTORG closer.",
+                            address, offset
+                        ),
+                    )),
                 }
-                output.push(assembled);
             }
-            _ => {
-                let e = format!(
-                    "The instruction {:?} is still not implemented.",
-                    instruction.opcode
-                );
-                emit_error(ln, fc, &e)?;
+            _ => Ok(Cow::Borrowed(instruction.as_ref())),
+        };
+        let encoded = loaded.and_then(|instruction| {
+            let words = encode(&instruction, pc, &scope)?;
+            let relocation = relocation(&instruction, &scope, parsed_file.orig.is_none())?;
+            if let Some((kind, symbol)) = relocation {
+                relocations.push(Relocation {
+                    offset: output.len() as u16 - 1,
+                    kind,
+                    symbol,
+                });
             }
+            Ok(words)
+        });
+        let words = encoded.unwrap_or_else(|e| {
+            errors.push(error(at, e));
+            // Keeps the following addresses right
+            vec![0; size(instruction, &scope).unwrap_or(1) as usize]
+        });
+        for (i, word) in words.into_iter().enumerate() {
+            listing.push(ListingLine {
+                address: pc.wrapping_add(i as u16),
+                word,
+                file: ln.map_or(0, |(file, _)| file),
+                line: ln.map(|(_, line)| line),
+                kind,
+                expansion,
+            });
+            output.push(word);
         }
     }
-    Ok(output)
+    if !errors.is_empty() {
+        return Err(render_all(&errors, &files));
+    }
+    Ok(Assembly {
+        object: output,
+        symbols,
+        listing,
+        expansions,
+        files,
+        positions: parsed_file.positions,
+        has_orig: parsed_file.orig.is_some(),
+        globals: globals.into_iter().map(|(name, ..)| name).collect(),
+        externals,
+        relocations,
+    })
 }
 
 #[cfg(test)]
@@ -144,22 +1052,303 @@ mod tests {
     use super::*;
     #[test]
     fn test_orig() -> Result<(), String> {
-        let content = vec![".ORIG x3000"];
+        let content = [".ORIG x3000"];
         let result = assemble_file(content.iter().map(|s| s.to_string()).collect())?;
         assert_eq!(result, [0x3000]);
         Ok(())
     }
     #[test]
     fn test_add() -> Result<(), String> {
-        let content = vec![".ORIG x3000", "ADD R2, R2, #-5; testst", "ADD R2, R2, R2"];
+        let content = [".ORIG x3000", "ADD R2, R2, #-5; testst", "ADD R2, R2, R2"];
         let result = assemble_file(content.iter().map(|s| s.to_string()).collect())?;
-        for i in result.iter() {
-            println!("{:#b}", i);
-        }
         assert_eq!(
             result,
             [0x3000, 0b0001_010_010_1_11011, 0b0001_010_010_0_00_010]
         );
         Ok(())
     }
+
+    #[test]
+    fn test_lexical_syntax() -> Result<(), String> {
+        let content = [
+            "  ; comment before the origin",
+            "\t.orig\tX3000",
+            "\tadd r2, r2, #-5\t; tabs and lower case",
+            "ADD R2,R2,b11",
+        ];
+        let result = assemble_file(content.iter().map(|s| s.to_string()).collect())?;
+        assert_eq!(
+            result,
+            [0x3000, 0b0001_010_010_1_11011, 0b0001_010_010_1_00011]
+        );
+        let error = assemble_file(vec![".ORIG x3000".into(), "ADD R1, R1, #1x".into()]);
+        assert!(error.unwrap_err().starts_with("Error at line 2, column 13"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_symbols() -> Result<(), String> {
+        let content = [
+            ".ORIG x3000",
+            "LOOP ADD R1, R1, #1",
+            "; comment",
+            "",
+            "MORE ADD R2, R2, R2",
+        ];
+        let result = assemble(content.iter().map(|s| s.to_string()).collect())?;
+        assert_eq!(result.symbol("LOOP"), Some(0x3000));
+        assert_eq!(result.symbol("MORE"), Some(0x3001));
+        let sym = result.symbol_file();
+        assert!(sym.contains("//\tLOOP              3000\n"));
+        assert_eq!(parse_symbol_file(&sym), result.symbols());
+
+        let listing = result.listing_file();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[2],
+            "x3000  1261 0001001001100001     2  LOOP ADD R1, R1, #1"
+        );
+        assert!(lines[3].trim_start().starts_with("3  ; comment"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_macro_listing() -> Result<(), String> {
+        let content = [
+            ".MACRO INC2 reg",
+            "ADD reg, reg, #1",
+            "ADD reg, reg, #1",
+            ".ENDM",
+            ".ORIG x3000",
+            "INC2 R1",
+            "ADD R2, R2, R2",
+        ];
+        let result = assemble(content.iter().map(|s| s.to_string()).collect())?;
+        assert_eq!(result.object(), [0x3000, 0x1261, 0x1261, 0x1482]);
+        let listing = result.listing_file();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert!(lines[6].trim_start().starts_with("6  INC2 R1"));
+        assert_eq!(
+            lines[7],
+            "x3000  1261 0001001001100001     6  + ADD R1, R1, #1"
+        );
+        assert_eq!(
+            lines[9],
+            "x3002  1482 0001010010000010     7  ADD R2, R2, R2"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_macro_params() -> Result<(), String> {
+        // Parameters ignore case, and #n takes a number or a constant
+        let content = [
+            ".MACRO ADDN reg, n",
+            "ADD REG, Reg, #n",
+            ".ENDM",
+            ".ORIG x3000",
+            ".EQU TWO 2",
+            "ADDN R1, #3",
+            "ADDN R1, TWO",
+        ];
+        let result = assemble(content.iter().map(|s| s.to_string()).collect())?;
+        assert_eq!(result.object(), [0x3000, 0x1263, 0x1262]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_constants() -> Result<(), String> {
+        let content = [
+            ".ORIG x3000",
+            ".EQU N 3",
+            ".EQU BUFSIZE, 4",
+            "START LEA R0, MSG",
+            "      ADD R1, R1, #-(N*2)",
+            "      LD R2, BUF+1",
+            "      BRnzp END",
+            "      .FILL END-START",
+            "BUF   .BLKW BUFSIZE",
+            "MSG   .STRINGZ \"hi\"",
+            "END   HALT",
+            ".SET C 1",
+            ".FILL C",
+            ".SET C C*2",
+            ".FILL C",
+        ];
+        let result = assemble_file(content.iter().map(|s| s.to_string()).collect())?;
+        assert_eq!(
+            result,
+            [
+                0x3000, 0xE008, 0x127A, 0x2403, 0x0E08, 0x000C, 0, 0, 0, 0, 0x68, 0x69, 0, 0xF025,
+                1, 2
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_constant_errors() {
+        let content = [".ORIG x3000", ".EQU N 16", "N .FILL 1", ".EQU N 1"];
+        let errors = assemble(content.iter().map(|s| s.to_string()).collect()).unwrap_err();
+        assert!(errors.starts_with("Error at line 3, column 1:"));
+        assert!(errors.contains("--> Label N is already defined."));
+        assert!(errors.contains("line 4, column 6:"));
+
+        let content = [
+            ".ORIG x3000",
+            ".EQU N 16",
+            "ADD R1, R1, N",
+            "LD R1, MISSING+1",
+            "BR FAR",
+            ".BLKW 300",
+            "FAR .FILL x10000-1",
+        ];
+        let errors = assemble(content.iter().map(|s| s.to_string()).collect()).unwrap_err();
+        assert!(errors.contains("line 3, column 13:"));
+        assert!(errors.contains("--> Value 16 does not fit in 5 bits (from -16 to 15)."));
+        assert!(errors.contains("--> Undefined symbol MISSING."));
+        assert!(errors.contains("--> x312F is too far: offset 300 does not fit in 9 bits."));
+        assert!(!errors.contains("line 7"));
+    }
+
+    #[test]
+    fn test_wrapping() -> Result<(), String> {
+        // Words wrap to 16 bits, constants too but keeping small negative values
+        let content = [
+            ".ORIG x3000",
+            ".EQU BIG xFFFF+2",
+            ".EQU MINUS -1",
+            ".FILL xFFFF+1",
+            ".FILL x8000+x8000",
+            ".FILL x10000*x10000+3",
+            ".FILL BIG",
+            "ADD R1, R1, #MINUS",
+        ];
+        let result = assemble(content.iter().map(|s| s.to_string()).collect())?;
+        assert_eq!(result.object(), [0x3000, 0, 0, 3, 1, 0x127F]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_constant_before_definition() {
+        // Unlike labels, constants can only be used below their .EQU or .SET
+        let content = [".ORIG x3000", "ADD R1, R1, #N", ".EQU N 3", "HALT"];
+        let errors = assemble(content.iter().map(|s| s.to_string()).collect()).unwrap_err();
+        assert!(errors.contains("line 2, column 13:"));
+        assert!(errors.contains("--> Constant N is used before its definition."));
+    }
+
+    #[test]
+    fn test_duplicate_label() {
+        let content = [".ORIG x3000", "A ADD R1, R1, #1", "A ADD R1, R1, #1"];
+        assert!(assemble(content.iter().map(|s| s.to_string()).collect()).is_err());
+    }
+
+    #[test]
+    fn test_debug_info() -> Result<(), String> {
+        let content = [
+            ".ORIG x3000",
+            "LOOP ADD R1, R1, #1",
+            "",
+            "ADD R2, R2, R1",
+            "MORE ADD R2, R2, R1",
+        ];
+        let result = assemble(content.iter().map(|s| s.to_string()).collect())?;
+        let info = result.debug_info("main.asm");
+        assert_eq!(info.files(), ["main.asm"]);
+        let location = info.location(0x3001).unwrap();
+        assert_eq!((location.line, location.column), (4, 1));
+        assert_eq!(location.kind, WordKind::Code);
+        assert_eq!(info.address_of_line(0, 3), Some(0x3001));
+        assert_eq!(info.label_at(0x3001).unwrap().name, "LOOP");
+        assert_eq!(info.label_at(0x3002).unwrap().end, 0x3003);
+        Ok(())
+    }
+
+    #[test]
+    fn test_include() -> Result<(), String> {
+        let mut files = MemoryFiles::new();
+        files.add(
+            "src/main.asm",
+            ".ORIG x3000\nJSR PRINT\nHALT\n.INCLUDE \"lib/io.asm\"",
+        );
+        files.add("src/lib/io.asm", "; prints R0\nPRINT OUT\n  RET");
+        let result = assemble_project("src/main.asm", &files)?;
+        assert_eq!(result.object(), [0x3000, 0x4801, 0xF025, 0xF021, 0xC1C0]);
+        let listing = result.listing_file();
+        let lines: Vec<&str> = listing.lines().map(str::trim_end).collect();
+        assert_eq!(lines[5], format!("{:<28} {:>5}  ; prints R0", "", 1));
+        assert!(lines[6].starts_with("x3002  F021"));
+        assert!(lines[6].ends_with("    2  PRINT OUT"));
+        let info = result.debug_info("main.asm");
+        assert_eq!(info.files(), ["main.asm", "lib/io.asm"]);
+        let location = info.location(0x3003).unwrap();
+        assert_eq!((location.file, location.line, location.column), (1, 3, 3));
+
+        files.add("src/lib/io.asm", "PRINT OUT\nRET R7");
+        let error = assemble_project("src/main.asm", &files).unwrap_err();
+        assert!(error.starts_with("Error at line 2, column 1 of src/lib/io.asm: RET R7"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal_pool_end() -> Result<(), String> {
+        let content = [".ORIG x3000", "LD R0, =#4", "HALT", ".END", "; done"];
+        let result = assemble(content.iter().map(|s| s.to_string()).collect())?;
+        assert_eq!(result.object(), [0x3000, 0x2001, 0xF025, 4]);
+        let info = result.debug_info("main.asm");
+        assert_eq!(info.location(0x3001).unwrap().line, 3);
+        let location = info.location(0x3002).unwrap();
+        assert_eq!((location.line, location.kind), (4, WordKind::Data));
+        let listing = result.listing_file();
+        let lines: Vec<&str> = listing.lines().collect();
+        assert_eq!(lines[4], "x3002  0004 0000000000000100     4  .END");
+        assert!(lines[5].trim_start().starts_with("5  ; done"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_literal_pool() -> Result<(), String> {
+        let content = [
+            ".ORIG x3000",
+            "LD R0, =x8000",
+            "LD R1, =LABEL",
+            "LD R2, =#-32768",
+            ".LTORG",
+            "LABEL LD R3, =x8000",
+            "HALT",
+        ];
+        let result = assemble(content.iter().map(|s| s.to_string()).collect())?;
+        assert_eq!(
+            result.object(),
+            [0x3000, 0x2002, 0x2202, 0x2400, 0x8000, 0x3005, 0x2601, 0xF025, 0x8000]
+        );
+        let pool: Vec<Option<usize>> = result.listing()[3..5].iter().map(|l| l.line).collect();
+        assert_eq!(pool, [Some(4), Some(4)]);
+        // Without .END, the last pool has no source line
+        assert_eq!(result.listing()[7].line, None);
+        assert_eq!(result.debug_info("main.asm").location(0x3007), None);
+        let listing = result.listing_file();
+        assert_eq!(listing.lines().last(), Some("x3007  8000 1000000000000000"));
+
+        let content = [
+            ".ORIG x3000",
+            "LD R0, =x8000",
+            ".BLKW 300",
+            "ADD R0, R0, =1",
+            "LD R1, =UNDEFINED",
+        ];
+        let errors = assemble(content.iter().map(|s| s.to_string()).collect()).unwrap_err();
+        let errors: Vec<&str> = errors.lines().filter(|l| l.starts_with("Error")).collect();
+        assert_eq!(
+            errors,
+            [
+                "Error at line 2, column 8: LD R0, =x8000",
+                "Error at line 4, column 13: ADD R0, R0, =1",
+                "Error at line 5, column 8: LD R1, =UNDEFINED",
+            ]
+        );
+        Ok(())
+    }
 }
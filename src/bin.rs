@@ -1,18 +1,29 @@
 use std::env;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Read;
+use std::path::Path;
 use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use tdal3::Core;
-fn main() {
-    // Get the file path from the command-line arguments
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <file_path>", args[0]);
-        process::exit(1);
-    }
-    let file_path = &args[1];
+use tdal3::assemble::{assemble_module, parse_symbol_file};
+use tdal3::cfg::ControlFlowGraph;
+use tdal3::config::CoreConfig;
+use tdal3::console::{BufferConsole, TerminalConsole};
+use tdal3::dataflow::{analyze_registers, CallingConvention};
+use tdal3::debuginfo::DebugInfo;
+use tdal3::formatter::format_source;
+use tdal3::link::{link, Module};
+use tdal3::lint::{lint_with, LintConfig};
+use tdal3::memory::{parse_word, PowerOn, Protection, UninitializedCheck};
+use tdal3::replay::InputScript;
+use tdal3::source::DiskFiles;
+use tdal3::timing::TimingModel;
+use tdal3::video::{to_png, to_ppm, VIDEO_HEIGHT, VIDEO_WIDTH};
+use tdal3::{Core, StopReason};
+
+mod tui;
 
+fn read_obj(file_path: &str) -> Vec<u16> {
     // Open the file
     let mut file = match File::open(file_path) {
         Ok(file) => file,
@@ -29,6 +40,12 @@ fn main() {
         process::exit(1);
     }
 
+    // The first word is the origin, which every object has
+    if buffer.is_empty() {
+        eprintln!("Object file {} is empty.", file_path);
+        process::exit(1);
+    }
+
     // Ensure the byte length is a multiple of 2
     if buffer.len() % 2 != 0 {
         eprintln!("File size is not a multiple of 2 bytes, cannot convert to Vec<u16>.");
@@ -36,15 +53,529 @@ fn main() {
     }
 
     // Convert the bytes to a Vec<u16> in big-endian order
-    let u16_vec: Vec<u16> = buffer
+    buffer
         .chunks(2)
         .map(|chunk| u16::from_be_bytes([chunk[0], chunk[1]])) // Big-endian byte order
-        .collect();
+        .collect()
+}
+
+fn write_file(path: &Path, content: &[u8]) {
+    if let Err(e) = fs::write(path, content) {
+        eprintln!("Error writing {}: {}", path.display(), e);
+        process::exit(1);
+    }
+}
+
+// Writes the .obj, .sym, .lst and .dbg files next to the source file, or the .rel and .lst
+// files when it must be linked.
+fn assemble_to_files(file_path: &str) {
+    let assembly = match assemble_module(file_path, &DiskFiles) {
+        Ok(assembly) => assembly,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let path = Path::new(file_path);
+    write_file(
+        &path.with_extension("lst"),
+        assembly.listing_file().as_bytes(),
+    );
+    if assembly.is_module() {
+        let rel_path = path.with_extension("rel");
+        let module = assembly.module(&rel_path.to_string_lossy());
+        write_file(&rel_path, module.serialize().as_bytes());
+        return;
+    }
+    write_obj(&path.with_extension("obj"), &assembly.object());
+    write_file(
+        &path.with_extension("sym"),
+        assembly.symbol_file().as_bytes(),
+    );
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let debug_info = assembly.debug_info(&file_name).serialize();
+    write_file(&path.with_extension("dbg"), debug_info.as_bytes());
+}
+
+fn write_obj(path: &Path, object: &[u16]) {
+    let obj: Vec<u8> = object.iter().flat_map(|word| word.to_be_bytes()).collect();
+    write_file(path, &obj);
+}
+
+// Links .rel files into an .obj file, writing its .sym file next to it.
+fn link_to_file(output: &str, modules: &[String]) {
+    let mut parsed = Vec::new();
+    for path in modules {
+        let module = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading {}: {}", path, e))
+            .and_then(|content| Module::parse(path, &content));
+        match module {
+            Ok(module) => parsed.push(module),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+    let linked = match link(&parsed) {
+        Ok(linked) => linked,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    };
+    let path = Path::new(output);
+    write_obj(path, &linked.object);
+    write_file(&path.with_extension("sym"), linked.symbol_file().as_bytes());
+}
+
+// Formats the files in place, or with check only lists those that are not formatted and
+// exits with 1 if any.
+fn format_files(files: &[String], check: bool) {
+    let mut unformatted = false;
+    for path in files {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("Error reading {}: {}", path, e);
+                process::exit(1);
+            }
+        };
+        let formatted = format_source(&source);
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{}", path);
+            unformatted = true;
+        } else {
+            write_file(Path::new(path), formatted.as_bytes());
+        }
+    }
+    if unformatted {
+        process::exit(1);
+    }
+}
+
+// Prints the lint diagnostics of the file, exiting with 1 if a rule configured as an
+// error reported something.
+fn lint_file(file_path: &str, rules: &str) {
+    let lints =
+        LintConfig::parse(rules).and_then(|config| lint_with(file_path, &DiskFiles, &config));
+    match lints {
+        Ok(lints) => {
+            if !lints.diagnostics.is_empty() {
+                println!("{}", lints.render());
+            }
+            if lints.has_errors() {
+                process::exit(1);
+            }
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1);
+        }
+    }
+}
+
+// Prints the register diagnostics of an object, preserved being the registers
+// subroutines must give back unchanged.
+fn print_register_report(file_path: &str, preserved: &str) {
+    let convention = CallingConvention::parse(preserved).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(1);
+    });
+    let info = read_debug_info(file_path);
+    let graph = ControlFlowGraph::build(&read_obj(file_path), &info);
+    for diagnostic in analyze_registers(&graph, &convention) {
+        match info.files().get(diagnostic.file) {
+            Some(name) => println!("{}: {}", name, diagnostic),
+            None => println!("{}", diagnostic),
+        }
+    }
+}
+
+// Debug info of an object from the .dbg file next to it, empty without one.
+fn read_debug_info(file_path: &str) -> DebugInfo {
+    match fs::read_to_string(Path::new(file_path).with_extension("dbg")) {
+        Ok(content) => DebugInfo::parse(&content).unwrap_or_else(|e| {
+            eprintln!("{}", e);
+            process::exit(1);
+        }),
+        Err(_) => DebugInfo::default(),
+    }
+}
 
-    let mut c = Core::new();
-    c.load_obj(&u16_vec);
-    while c.pc != u16::MAX - 1 {
-        c.step();
+// Prints the flow graph of an object in the DOT or JSON format, words being told apart
+// as code or data by the .dbg file next to it.
+fn print_flow_graph(file_path: &str, json: bool) {
+    let graph = ControlFlowGraph::build(&read_obj(file_path), &read_debug_info(file_path));
+    if json {
+        println!("{}", graph.to_json());
+    } else {
+        print!("{}", graph.to_dot());
     }
+}
+
+// Where the run starts.
+#[derive(Default)]
+enum Entry {
+    // Origin of the object
+    #[default]
+    Origin,
+    Address(u16),
+    // Label of the .sym file next to the object
+    Label(String),
+}
+
+// Options of the commands running an object.
+#[derive(Default)]
+struct RunOptions {
+    tui: bool,
+    uninitialized: UninitializedCheck,
+    power_on: PowerOn,
+    entry: Entry,
+    user_mode: bool,
+    stop_on_illegal_opcode: bool,
+    regions: Vec<(u16, u16, Protection)>,
+    // Image the framebuffer is written to once the run is over
+    video: Option<String>,
+    timing: Option<TimingModel>,
+    profile: bool,
+    // Input script written once the run is over, and the one read instead of the terminal
+    record: Option<String>,
+    replay: Option<String>,
+}
+
+// Prints the error, if any, so that the usage follows it.
+fn reported<T>(result: Result<T, String>) -> Option<T> {
+    result.map_err(|message| eprintln!("{}", message)).ok()
+}
+
+// Parses <start>-<end>=ro|nx|none, the end being included.
+fn parse_region(spec: &str) -> Result<(u16, u16, Protection), String> {
+    let invalid = || {
+        format!(
+            "Invalid region {}, expected <start>-<end>=ro|nx|none.",
+            spec
+        )
+    };
+    let (range, protection) = spec.split_once('=').ok_or_else(invalid)?;
+    let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+    let protection = match protection {
+        "ro" => Protection::ReadOnly,
+        "nx" => Protection::NoExecute,
+        "none" => Protection::NoAccess,
+        _ => return Err(invalid()),
+    };
+    Ok((parse_word(start)?, parse_word(end)?, protection))
+}
+
+// A plain random power-on state takes its seed from the clock.
+fn parse_power_on(spec: &str) -> Result<PowerOn, String> {
+    if spec != "random" {
+        return PowerOn::parse(spec);
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    Ok(PowerOn::Random(now.as_nanos() as u64))
+}
+
+// Parses [tui] [--uninitialized warn|stop] [--power-on <state>] [--pc <address>]
+// [--entry <label>] [--user] [--protect <region>]... [--illegal-opcode exception|stop]
+// [--video <image>] [--timing <model>] [--profile] [--record <script>] [--replay <script>]
+// <file.obj>, giving the options and the file.
+fn parse_run(args: &[String]) -> Option<(RunOptions, &str)> {
+    let mut options = RunOptions::default();
+    let mut args = args;
+    if let [command, rest @ ..] = args {
+        if command == "tui" {
+            options.tui = true;
+            args = rest;
+        }
+    }
+    loop {
+        match args {
+            [file_path] => return Some((options, file_path)),
+            [flag, mode, rest @ ..] if flag == "--uninitialized" => {
+                options.uninitialized = match mode.as_str() {
+                    "warn" => UninitializedCheck::Warn,
+                    "stop" => UninitializedCheck::Stop,
+                    _ => return None,
+                };
+                args = rest;
+            }
+            [flag, address, rest @ ..] if flag == "--pc" => {
+                options.entry = Entry::Address(reported(parse_word(address))?);
+                args = rest;
+            }
+            [flag, label, rest @ ..] if flag == "--entry" => {
+                options.entry = Entry::Label(label.clone());
+                args = rest;
+            }
+            [flag, spec, rest @ ..] if flag == "--power-on" => {
+                options.power_on = reported(parse_power_on(spec))?;
+                args = rest;
+            }
+            [flag, mode, rest @ ..] if flag == "--illegal-opcode" => {
+                options.stop_on_illegal_opcode = match mode.as_str() {
+                    "exception" => false,
+                    "stop" => true,
+                    _ => return None,
+                };
+                args = rest;
+            }
+            [flag, rest @ ..] if flag == "--user" => {
+                options.user_mode = true;
+                args = rest;
+            }
+            [flag, spec, rest @ ..] if flag == "--timing" => {
+                options.timing = Some(reported(TimingModel::parse(spec))?);
+                args = rest;
+            }
+            [flag, rest @ ..] if flag == "--profile" => {
+                options.profile = true;
+                args = rest;
+            }
+            [flag, script, rest @ ..] if flag == "--record" => {
+                options.record = Some(script.clone());
+                args = rest;
+            }
+            [flag, script, rest @ ..] if flag == "--replay" => {
+                options.replay = Some(script.clone());
+                args = rest;
+            }
+            [flag, image, rest @ ..] if flag == "--video" => {
+                options.video = Some(image.clone());
+                args = rest;
+            }
+            [flag, spec, rest @ ..] if flag == "--protect" => {
+                options.regions.push(reported(parse_region(spec))?);
+                args = rest;
+            }
+            _ => return None,
+        }
+    }
+}
+
+fn main() {
+    // Get the file path from the command-line arguments
+    let args: Vec<String> = env::args().collect();
+    let run = match args.as_slice() {
+        [_, command, file_path] if command == "asm" => {
+            assemble_to_files(file_path);
+            return;
+        }
+        [_, command, output, modules @ ..] if command == "link" && !modules.is_empty() => {
+            link_to_file(output, modules);
+            return;
+        }
+        [_, command, file_path] if command == "cfg" => {
+            print_flow_graph(file_path, false);
+            return;
+        }
+        [_, command, flag, file_path] if command == "cfg" && flag == "--json" => {
+            print_flow_graph(file_path, true);
+            return;
+        }
+        [_, command, file_path] if command == "regs" => {
+            print_register_report(file_path, "");
+            return;
+        }
+        [_, command, flag, preserved, file_path] if command == "regs" && flag == "--preserve" => {
+            print_register_report(file_path, preserved);
+            return;
+        }
+        [_, command, file_path] if command == "lint" => {
+            lint_file(file_path, "");
+            return;
+        }
+        [_, command, flag, rules, file_path] if command == "lint" && flag == "--rules" => {
+            lint_file(file_path, rules);
+            return;
+        }
+        [_, command, flag, files @ ..]
+            if command == "fmt" && flag == "--check" && !files.is_empty() =>
+        {
+            format_files(files, true);
+            return;
+        }
+        [_, command, files @ ..] if command == "fmt" && !files.is_empty() => {
+            format_files(files, false);
+            return;
+        }
+        [_, rest @ ..] => parse_run(rest),
+        _ => None,
+    };
+    let Some((options, file_path)) = run else {
+        eprintln!(
+            "Usage: {} [tui] [--uninitialized warn|stop] [--power-on zero|fill=<value>|random[=<seed>]]",
+            args[0]
+        );
+        eprintln!("           [--pc <address> | --entry <label>] [--user]");
+        eprintln!("           [--protect <start>-<end>=ro|nx|none]...");
+        eprintln!("           [--illegal-opcode exception|stop] [--video <image.ppm|png>]");
+        eprintln!("           [--timing base=<n>,memory=<n>,device=<n>] [--profile]");
+        eprintln!("           [--record <script>] [--replay <script>] <file.obj>");
+        eprintln!("       {} asm <file.asm>", args[0]);
+        eprintln!("       {} link <output.obj> <module.rel>...", args[0]);
+        eprintln!("       {} fmt [--check] <file>...", args[0]);
+        eprintln!("       {} cfg [--json] <file.obj>", args[0]);
+        eprintln!(
+            "       {} regs [--preserve <R1-R6,...>] <file.obj>",
+            args[0]
+        );
+        eprintln!(
+            "       {} lint [--rules <rule=off|warning|error,...>] <file>",
+            args[0]
+        );
+        process::exit(1);
+    };
+
+    let obj = read_obj(file_path);
+    // Labels from the .sym file written along the object, if any
+    let symbols = fs::read_to_string(Path::new(file_path).with_extension("sym")).ok();
+    let pc = match &options.entry {
+        Entry::Origin => obj[0],
+        Entry::Address(address) => *address,
+        Entry::Label(label) => {
+            let symbols = parse_symbol_file(symbols.as_deref().unwrap_or(""));
+            match symbols.iter().find(|symbol| &symbol.name == label) {
+                Some(symbol) => symbol.address,
+                None => {
+                    eprintln!("Entry label {} is not in the symbol file.", label);
+                    process::exit(1);
+                }
+            }
+        }
+    };
+    let config = CoreConfig::new()
+        .pc(pc)
+        .user_mode(options.user_mode)
+        .power_on(options.power_on);
+    let mut c = Core::with_config(&config);
+    if options.video.is_some() {
+        c.enable_video();
+    }
+    if let Some(model) = &options.timing {
+        c.set_timing_model(model);
+    }
+    if options.profile {
+        c.enable_profiling();
+    }
+    if options.record.is_some() {
+        c.start_recording();
+    }
+    if let Some(script) = &options.replay {
+        let script =
+            fs::read_to_string(script).map_err(|e| format!("Cannot read {}: {}", script, e));
+        match script.and_then(|content| InputScript::parse(&content)) {
+            Ok(script) => c.replay_input(&script),
+            Err(e) => {
+                eprintln!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
+    for &(start, end, protection) in &options.regions {
+        c.protect(start, end, protection);
+    }
+    if let Some(seed) = c.power_on_seed() {
+        eprintln!("Power-on seed: {} (--power-on random={})", seed, seed);
+    }
+    c.set_uninitialized_check(options.uninitialized);
+    c.set_stop_on_illegal_opcode(options.stop_on_illegal_opcode);
+    if let Err(e) = c.load_obj(&obj) {
+        eprintln!("{}", e);
+        process::exit(1);
+    }
+    if let Some(symbols) = &symbols {
+        c.load_symbols(symbols);
+    }
+    if let Ok(debug_info) = fs::read_to_string(Path::new(file_path).with_extension("dbg")) {
+        match DebugInfo::parse(&debug_info) {
+            Ok(info) => c.load_debug_info(&info),
+            Err(e) => eprintln!("Ignoring debug info: {}", e),
+        }
+    }
+    if options.tui {
+        match tui::run(c) {
+            Ok(c) => write_outputs(&c, &options),
+            Err(e) => {
+                eprintln!("Terminal error: {}", e);
+                process::exit(1);
+            }
+        }
+        return;
+    }
+    match TerminalConsole::new() {
+        Ok(console) => c.set_console(Box::new(console)),
+        Err(e) => {
+            eprintln!("Terminal error: {}", e);
+            process::exit(1);
+        }
+    }
+    let stop = c.run(usize::MAX);
+    // Gives the terminal back before printing
+    c.set_console(Box::new(BufferConsole::new()));
+    match stop {
+        StopReason::Interrupted => eprintln!("\nInterrupted at x{:04X}", c.pc()),
+        StopReason::IllegalOpcode { address, word } => {
+            eprintln!(
+                "\nStopped: illegal opcode x{:04X} at x{:04X}",
+                word, address
+            )
+        }
+        StopReason::Uninitialized(access) if access.fetch => {
+            eprintln!(
+                "\nStopped: uninitialized word at x{:04X} reached",
+                access.address
+            )
+        }
+        StopReason::Uninitialized(access) => eprintln!(
+            "\nStopped: x{:04X} read x{:04X}, which was never written",
+            access.pc, access.address
+        ),
+        _ => (),
+    }
+    if options.uninitialized == UninitializedCheck::Warn {
+        for access in c.uninitialized_accesses() {
+            match access.fetch {
+                true => eprintln!("Warning: executed uninitialized word at x{:04X}", access.pc),
+                false => eprintln!(
+                    "Warning: x{:04X} read x{:04X}, which was never written",
+                    access.pc, access.address
+                ),
+            }
+        }
+    }
+    write_outputs(&c, &options);
     c.dump_registers();
 }
+
+// Writes what the options asked for once the run is over: the framebuffer image, the
+// recorded input and the profile.
+fn write_outputs(c: &Core, options: &RunOptions) {
+    if let Some(image) = &options.video {
+        write_video(c, Path::new(image));
+    }
+    if let (Some(script), Some(input)) = (&options.record, c.recorded_input()) {
+        write_file(Path::new(script), input.serialize().as_bytes());
+    }
+    if let Some(report) = c.profile_report() {
+        eprint!("{}", report);
+    }
+}
+
+// Writes the framebuffer as a PNG image, or as a PPM one unless the extension is .png.
+fn write_video(c: &Core, path: &Path) {
+    let rgba = c.video_rgba();
+    let image = match path.extension() {
+        Some(extension) if extension.eq_ignore_ascii_case("png") => {
+            to_png(&rgba, VIDEO_WIDTH, VIDEO_HEIGHT)
+        }
+        _ => to_ppm(&rgba, VIDEO_WIDTH, VIDEO_HEIGHT),
+    };
+    write_file(path, &image);
+}
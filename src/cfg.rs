@@ -0,0 +1,679 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use wasm_bindgen::prelude::*;
+
+use crate::debuginfo::{DebugInfo, SourceLocation, WordKind};
+use crate::disasm::{disassemble, pc_relative};
+use crate::opcode::OpCode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EdgeKind {
+    // To the next block, including the return site after a call
+    FallThrough,
+    // Taken BR
+    Branch,
+    // JSR to the entry of a subroutine
+    Call,
+    // RET of a subroutine to the return sites of its callers
+    Return,
+    // TRAP to the next block, the trap routine not being part of the program
+    Trap,
+}
+
+impl EdgeKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EdgeKind::FallThrough => "fallthrough",
+            EdgeKind::Branch => "branch",
+            EdgeKind::Call => "call",
+            EdgeKind::Return => "return",
+            EdgeKind::Trap => "trap",
+        }
+    }
+
+    // Whether the edge stays within a subroutine.
+    pub fn is_local(&self) -> bool {
+        matches!(
+            self,
+            EdgeKind::FallThrough | EdgeKind::Branch | EdgeKind::Trap
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Edge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: EdgeKind,
+}
+
+/// Instructions from start (included) to end (excluded), only entered at start.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasicBlock {
+    pub start: u16,
+    pub end: u16,
+    pub label: Option<String>,
+}
+
+/// Code reached from the entry of a subroutine without following calls and returns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Subroutine {
+    pub name: String,
+    pub entry: usize,
+    pub blocks: Vec<usize>,
+}
+
+/// Natural loop: the blocks from which the header can be reached again without leaving
+/// the loop. The header dominates all of them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Loop {
+    pub header: usize,
+    pub blocks: Vec<usize>,
+}
+
+/// Control-flow graph of an assembled program, blocks being numbered in address order.
+/// Words are told apart as code or data by the debug info. JMP through another register
+/// than R7 goes to an unknown place and has no edge, as do HALT and RTI.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ControlFlowGraph {
+    words: HashMap<u16, u16>,
+    locations: HashMap<u16, SourceLocation>,
+    blocks: Vec<BasicBlock>,
+    edges: Vec<Edge>,
+    // Block at the .ORIG address, if it is code
+    entry: Option<usize>,
+    subroutines: Vec<Subroutine>,
+    // Immediate dominator of each block within its subroutine or the main program
+    dominators: Vec<Option<usize>>,
+    loops: Vec<Loop>,
+}
+
+// Address a BR or JSR goes to, if it goes to a fixed address.
+fn target(inst: u16, address: u16) -> Option<u16> {
+    match OpCode::from(inst) {
+        OpCode::BR if get_bits!(inst, 9, 3) != 0 => Some(pc_relative(address, inst, 9)),
+        OpCode::JSR if get_bits!(inst, 11, 1) == 1 => Some(pc_relative(address, inst, 11)),
+        _ => None,
+    }
+}
+
+// Whether a new block starts after the instruction.
+fn ends_block(inst: u16) -> bool {
+    match OpCode::from(inst) {
+        OpCode::BR => get_bits!(inst, 9, 3) != 0,
+        OpCode::JMP | OpCode::JSR | OpCode::TRAP | OpCode::RTI => true,
+        _ => false,
+    }
+}
+
+impl ControlFlowGraph {
+    // Builds the graph of object, an .ORIG address followed by the words of the program.
+    pub fn build(object: &[u16], info: &DebugInfo) -> ControlFlowGraph {
+        let orig = object.first().copied().unwrap_or(0);
+        let words: HashMap<u16, u16> = object
+            .iter()
+            .skip(1)
+            .enumerate()
+            .map(|(i, &word)| (orig.wrapping_add(i as u16), word))
+            .collect();
+        // Without locations, every word is taken for code
+        let kinds: HashMap<u16, WordKind> = info
+            .locations()
+            .iter()
+            .map(|l| (l.address, l.kind))
+            .collect();
+        let code: Vec<u16> = (0..object.len().saturating_sub(1))
+            .map(|i| orig.wrapping_add(i as u16))
+            .filter(|a| {
+                kinds
+                    .get(a)
+                    .map_or(kinds.is_empty(), |k| *k == WordKind::Code)
+            })
+            .collect();
+        let is_code: HashSet<u16> = code.iter().copied().collect();
+
+        let mut leaders = BTreeSet::new();
+        for &address in &code {
+            let inst = words[&address];
+            if !is_code.contains(&address.wrapping_sub(1)) || address == orig {
+                leaders.insert(address);
+            }
+            if ends_block(inst) {
+                leaders.insert(address.wrapping_add(1));
+            }
+            leaders.extend(target(inst, address));
+        }
+        for label in info.labels() {
+            leaders.insert(label.start);
+        }
+        let names: HashMap<u16, &str> = info
+            .labels()
+            .iter()
+            .map(|l| (l.start, l.name.as_str()))
+            .collect();
+
+        let mut blocks: Vec<BasicBlock> = Vec::new();
+        for &address in &code {
+            match blocks.last_mut() {
+                Some(block) if block.end == address && !leaders.contains(&address) => {
+                    block.end = address.wrapping_add(1)
+                }
+                _ => blocks.push(BasicBlock {
+                    start: address,
+                    end: address.wrapping_add(1),
+                    label: names.get(&address).map(|s| s.to_string()),
+                }),
+            }
+        }
+        let block_at: HashMap<u16, usize> = blocks
+            .iter()
+            .enumerate()
+            .map(|(i, b)| (b.start, i))
+            .collect();
+
+        let mut edges = Vec::new();
+        for (i, block) in blocks.iter().enumerate() {
+            let last = block.end.wrapping_sub(1);
+            let inst = words[&last];
+            let next = block_at.get(&block.end).copied();
+            let jump = target(inst, last).and_then(|t| block_at.get(&t).copied());
+            let mut add = |to: Option<usize>, kind| {
+                if let Some(to) = to {
+                    edges.push(Edge { from: i, to, kind });
+                }
+            };
+            match OpCode::from(inst) {
+                OpCode::BR => {
+                    add(jump, EdgeKind::Branch);
+                    if get_bits!(inst, 9, 3) != 0b111 {
+                        add(next, EdgeKind::FallThrough);
+                    }
+                }
+                OpCode::JSR => {
+                    add(jump, EdgeKind::Call);
+                    add(next, EdgeKind::FallThrough);
+                }
+                OpCode::TRAP if get_bits!(inst, 0, 8) == 0x25 => (),
+                OpCode::TRAP => add(next, EdgeKind::Trap),
+                OpCode::JMP | OpCode::RTI => (),
+                _ => add(next, EdgeKind::FallThrough),
+            }
+        }
+
+        let mut graph = ControlFlowGraph {
+            words,
+            locations: info.locations().iter().map(|l| (l.address, *l)).collect(),
+            blocks,
+            entry: block_at.get(&orig).copied(),
+            edges,
+            subroutines: Vec::new(),
+            dominators: Vec::new(),
+            loops: Vec::new(),
+        };
+        graph.find_subroutines();
+        graph.find_dominators();
+        graph.find_loops();
+        graph
+    }
+
+    pub fn blocks(&self) -> &[BasicBlock] {
+        &self.blocks
+    }
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges
+    }
+    pub fn entry(&self) -> Option<usize> {
+        self.entry
+    }
+    pub fn subroutines(&self) -> &[Subroutine] {
+        &self.subroutines
+    }
+    pub fn loops(&self) -> &[Loop] {
+        &self.loops
+    }
+    pub fn word(&self, address: u16) -> Option<u16> {
+        self.words.get(&address).copied()
+    }
+    pub fn location(&self, address: u16) -> Option<&SourceLocation> {
+        self.locations.get(&address)
+    }
+    pub fn block_at(&self, address: u16) -> Option<usize> {
+        self.blocks
+            .iter()
+            .position(|b| b.start <= address && address < b.end)
+    }
+    pub fn immediate_dominator(&self, block: usize) -> Option<usize> {
+        self.dominators.get(block).copied().flatten()
+    }
+    // Whether every path from the entry of the subroutine of b to b goes through a.
+    pub fn dominates(&self, a: usize, b: usize) -> bool {
+        let mut block = Some(b);
+        while let Some(current) = block {
+            if current == a {
+                return true;
+            }
+            block = self.immediate_dominator(current);
+        }
+        false
+    }
+
+    fn successors(&self, block: usize) -> impl Iterator<Item = usize> + '_ {
+        self.edges
+            .iter()
+            .filter(move |e| e.from == block && e.kind.is_local())
+            .map(|e| e.to)
+    }
+
+    // Blocks reached from block without following calls and returns, in reverse postorder.
+    fn reverse_postorder(&self, block: usize) -> Vec<usize> {
+        let mut order = Vec::new();
+        let mut visited = HashSet::from([block]);
+        let mut stack = vec![(block, self.successors(block).collect::<Vec<_>>())];
+        while let Some((current, next)) = stack.last_mut() {
+            match next.pop() {
+                Some(n) if visited.insert(n) => {
+                    let successors = self.successors(n).collect();
+                    stack.push((n, successors));
+                }
+                Some(_) => (),
+                None => {
+                    order.push(*current);
+                    stack.pop();
+                }
+            }
+        }
+        order.reverse();
+        order
+    }
+
+    // Entries are the targets of JSR. RETs get edges to the return site of every call.
+    fn find_subroutines(&mut self) {
+        let calls: Vec<Edge> = self
+            .edges
+            .iter()
+            .filter(|e| e.kind == EdgeKind::Call)
+            .copied()
+            .collect();
+        let entries: BTreeSet<usize> = calls.iter().map(|e| e.to).collect();
+        for entry in entries {
+            let mut blocks = self.reverse_postorder(entry);
+            blocks.sort();
+            let name = self.blocks[entry]
+                .label
+                .clone()
+                .unwrap_or_else(|| format!("x{:04X}", self.blocks[entry].start));
+            let sites: Vec<usize> = calls
+                .iter()
+                .filter(|call| call.to == entry)
+                .filter_map(|call| {
+                    self.edges
+                        .iter()
+                        .find(|e| e.from == call.from && e.kind == EdgeKind::FallThrough)
+                        .map(|e| e.to)
+                })
+                .collect();
+            for &block in &blocks {
+                let ret = self.words[&self.blocks[block].end.wrapping_sub(1)];
+                if OpCode::from(ret) == OpCode::JMP && get_bits!(ret, 6, 3) == 7 {
+                    for &to in &sites {
+                        self.edges.push(Edge {
+                            from: block,
+                            to,
+                            kind: EdgeKind::Return,
+                        });
+                    }
+                }
+            }
+            self.subroutines.push(Subroutine {
+                name,
+                entry,
+                blocks,
+            });
+        }
+    }
+
+    // Cooper, Harvey and Kennedy's iterative algorithm, run from the entry of the program
+    // and of each subroutine. Blocks shared by several keep their first dominator.
+    fn find_dominators(&mut self) {
+        let mut dominators = vec![None; self.blocks.len()];
+        let mut done = vec![false; self.blocks.len()];
+        let roots: Vec<usize> = self
+            .entry
+            .into_iter()
+            .chain(self.subroutines.iter().map(|s| s.entry))
+            .collect();
+        for root in roots {
+            let order: Vec<usize> = self
+                .reverse_postorder(root)
+                .into_iter()
+                .filter(|&b| !done[b] || b == root)
+                .collect();
+            let rank: HashMap<usize, usize> =
+                order.iter().enumerate().map(|(i, &b)| (b, i)).collect();
+            let mut idom: HashMap<usize, usize> = HashMap::from([(root, root)]);
+            let intersect = |idom: &HashMap<usize, usize>, mut a: usize, mut b: usize| {
+                while a != b {
+                    while rank[&a] > rank[&b] {
+                        a = idom[&a];
+                    }
+                    while rank[&b] > rank[&a] {
+                        b = idom[&b];
+                    }
+                }
+                a
+            };
+            let mut changed = true;
+            while changed {
+                changed = false;
+                for &block in order.iter().skip(1) {
+                    let predecessors = self.edges.iter().filter(|e| {
+                        e.to == block
+                            && e.kind.is_local()
+                            && rank.contains_key(&e.from)
+                            && idom.contains_key(&e.from)
+                    });
+                    let new = predecessors
+                        .map(|e| e.from)
+                        .reduce(|a, b| intersect(&idom, a, b));
+                    if let Some(new) = new {
+                        if idom.get(&block) != Some(&new) {
+                            idom.insert(block, new);
+                            changed = true;
+                        }
+                    }
+                }
+            }
+            for &block in &order {
+                if !done[block] {
+                    done[block] = true;
+                    dominators[block] = idom.get(&block).filter(|&&d| d != block).copied();
+                }
+            }
+        }
+        self.dominators = dominators;
+    }
+
+    // One loop per header, gathering the bodies of its back edges.
+    fn find_loops(&mut self) {
+        let mut bodies: HashMap<usize, BTreeSet<usize>> = HashMap::new();
+        for edge in self.edges.iter().filter(|e| e.kind.is_local()) {
+            if !self.dominates(edge.to, edge.from) {
+                continue;
+            }
+            let body = bodies.entry(edge.to).or_default();
+            body.insert(edge.to);
+            let mut pending = vec![edge.from];
+            while let Some(block) = pending.pop() {
+                if body.insert(block) {
+                    pending.extend(
+                        self.edges
+                            .iter()
+                            .filter(|e| e.to == block && e.kind.is_local())
+                            .map(|e| e.from),
+                    );
+                }
+            }
+        }
+        let mut loops: Vec<Loop> = bodies
+            .into_iter()
+            .map(|(header, blocks)| Loop {
+                header,
+                blocks: blocks.into_iter().collect(),
+            })
+            .collect();
+        loops.sort_by_key(|l| l.header);
+        self.loops = loops;
+    }
+
+    // Disassembly of the instructions of a block, one per line.
+    fn instructions(&self, block: &BasicBlock) -> Vec<(u16, String)> {
+        (block.start..block.end)
+            .map(|address| (address, disassemble(self.words[&address], address)))
+            .collect()
+    }
+
+    // Graphviz description, subroutines being drawn as clusters.
+    pub fn to_dot(&self) -> String {
+        let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+        let mut out =
+            String::from("digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n");
+        for (i, subroutine) in self.subroutines.iter().enumerate() {
+            out.push_str(&format!(
+                "    subgraph cluster_{} {{\n        label=\"{}\";\n",
+                i,
+                escape(&subroutine.name)
+            ));
+            for block in &subroutine.blocks {
+                out.push_str(&format!("        b{};\n", block));
+            }
+            out.push_str("    }\n");
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            let mut label = block
+                .label
+                .as_ref()
+                .map_or(String::new(), |l| format!("{}:\\l", escape(l)));
+            for (address, text) in self.instructions(block) {
+                label.push_str(&format!("x{:04X}  {}\\l", address, escape(&text)));
+            }
+            out.push_str(&format!("    b{} [label=\"{}\"];\n", i, label));
+        }
+        for edge in &self.edges {
+            let style = match edge.kind {
+                EdgeKind::FallThrough => "",
+                EdgeKind::Branch => " [label=\"branch\"]",
+                EdgeKind::Call => " [style=dashed, label=\"call\"]",
+                EdgeKind::Return => " [style=dotted, label=\"return\"]",
+                EdgeKind::Trap => " [label=\"trap\"]",
+            };
+            out.push_str(&format!("    b{} -> b{}{};\n", edge.from, edge.to, style));
+        }
+        out.push_str("}\n");
+        out
+    }
+
+    // JSON description for the web editor. Blocks are referred to by their index.
+    pub fn to_json(&self) -> String {
+        let string = |s: &str| {
+            let mut out = String::from('"');
+            for c in s.chars() {
+                match c {
+                    '"' => out.push_str("\\\""),
+                    '\\' => out.push_str("\\\\"),
+                    c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+                    c => out.push(c),
+                }
+            }
+            out.push('"');
+            out
+        };
+        let option = |o: Option<usize>| o.map_or("null".to_string(), |v| v.to_string());
+        let list = |items: &[usize]| {
+            let items: Vec<String> = items.iter().map(usize::to_string).collect();
+            format!("[{}]", items.join(","))
+        };
+
+        let blocks: Vec<String> = self
+            .blocks
+            .iter()
+            .enumerate()
+            .map(|(i, block)| {
+                let instructions: Vec<String> = self
+                    .instructions(block)
+                    .into_iter()
+                    .map(|(address, text)| {
+                        let (file, line) = self
+                            .location(address)
+                            .map_or((0, 0), |l| (l.file, l.line));
+                        format!(
+                            "{{\"address\":{},\"file\":{},\"line\":{},\"text\":{}}}",
+                            address,
+                            file,
+                            line,
+                            string(&text)
+                        )
+                    })
+                    .collect();
+                format!(
+                    "{{\"id\":{},\"start\":{},\"end\":{},\"label\":{},\"dominator\":{},\"instructions\":[{}]}}",
+                    i,
+                    block.start,
+                    block.end,
+                    block.label.as_deref().map_or("null".to_string(), string),
+                    option(self.immediate_dominator(i)),
+                    instructions.join(",")
+                )
+            })
+            .collect();
+        let edges: Vec<String> = self
+            .edges
+            .iter()
+            .map(|e| {
+                format!(
+                    "{{\"from\":{},\"to\":{},\"kind\":\"{}\"}}",
+                    e.from,
+                    e.to,
+                    e.kind.name()
+                )
+            })
+            .collect();
+        let subroutines: Vec<String> = self
+            .subroutines
+            .iter()
+            .map(|s| {
+                format!(
+                    "{{\"name\":{},\"entry\":{},\"blocks\":{}}}",
+                    string(&s.name),
+                    s.entry,
+                    list(&s.blocks)
+                )
+            })
+            .collect();
+        let loops: Vec<String> = self
+            .loops
+            .iter()
+            .map(|l| format!("{{\"header\":{},\"blocks\":{}}}", l.header, list(&l.blocks)))
+            .collect();
+        format!(
+            "{{\"entry\":{},\"blocks\":[{}],\"edges\":[{}],\"subroutines\":[{}],\"loops\":[{}]}}",
+            option(self.entry),
+            blocks.join(","),
+            edges.join(","),
+            subroutines.join(","),
+            loops.join(",")
+        )
+    }
+}
+
+// Flow graph of an assembled program as JSON, for the web editor to draw.
+#[wasm_bindgen]
+pub fn flow_graph_json(object: Vec<u16>, info: &DebugInfo) -> String {
+    ControlFlowGraph::build(&object, info).to_json()
+}
+
+// Flow graph of an assembled program in the Graphviz format.
+#[wasm_bindgen]
+pub fn flow_graph_dot(object: Vec<u16>, info: &DebugInfo) -> String {
+    ControlFlowGraph::build(&object, info).to_dot()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble::assemble;
+
+    fn graph(lines: &[&str]) -> ControlFlowGraph {
+        let assembly = assemble(lines.iter().map(|l| l.to_string()).collect()).unwrap();
+        ControlFlowGraph::build(&assembly.object(), &assembly.debug_info("main.asm"))
+    }
+
+    #[test]
+    fn test_blocks() {
+        let cfg = graph(&[
+            ".ORIG x3000",
+            "MAIN    AND R1, R1, #0",
+            "LOOP    ADD R1, R1, #1",
+            "        JSR SUB",
+            "        BRp LOOP",
+            "        HALT",
+            "SUB     ADD R0, R0, #-1",
+            "        BRz DONE",
+            "        OUT",
+            "DONE    RET",
+            "DATA    .FILL 3",
+            ".END",
+        ]);
+        let starts: Vec<u16> = cfg.blocks().iter().map(|b| b.start).collect();
+        assert_eq!(
+            starts,
+            [0x3000, 0x3001, 0x3003, 0x3004, 0x3005, 0x3007, 0x3008]
+        );
+        assert_eq!(cfg.blocks()[1].label.as_deref(), Some("LOOP"));
+        assert_eq!(cfg.entry(), Some(0));
+        let edges: Vec<(usize, usize, EdgeKind)> =
+            cfg.edges().iter().map(|e| (e.from, e.to, e.kind)).collect();
+        assert_eq!(
+            edges,
+            [
+                (0, 1, EdgeKind::FallThrough),
+                (1, 4, EdgeKind::Call),
+                (1, 2, EdgeKind::FallThrough),
+                (2, 1, EdgeKind::Branch),
+                (2, 3, EdgeKind::FallThrough),
+                (4, 6, EdgeKind::Branch),
+                (4, 5, EdgeKind::FallThrough),
+                (5, 6, EdgeKind::Trap),
+                (6, 2, EdgeKind::Return),
+            ]
+        );
+        assert_eq!(
+            cfg.subroutines(),
+            [Subroutine {
+                name: "SUB".into(),
+                entry: 4,
+                blocks: vec![4, 5, 6],
+            }]
+        );
+        assert_eq!(cfg.block_at(0x3002), Some(1));
+        assert_eq!(cfg.block_at(0x3009), None);
+    }
+
+    #[test]
+    fn test_dominators_and_loops() {
+        let cfg = graph(&[
+            ".ORIG x3000",
+            "        AND R1, R1, #0",
+            "LOOP    ADD R1, R1, #1",
+            "        BRz SKIP",
+            "        ADD R2, R2, #1",
+            "SKIP    BRp LOOP",
+            "        HALT",
+        ]);
+        let dominators: Vec<Option<usize>> = (0..cfg.blocks().len())
+            .map(|b| cfg.immediate_dominator(b))
+            .collect();
+        assert_eq!(dominators, [None, Some(0), Some(1), Some(1), Some(3)]);
+        assert!(cfg.dominates(1, 4));
+        assert!(!cfg.dominates(2, 3));
+        assert_eq!(
+            cfg.loops(),
+            [Loop {
+                header: 1,
+                blocks: vec![1, 2, 3],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        let cfg = graph(&[".ORIG x3000", "LOOP BRnzp LOOP"]);
+        assert_eq!(
+            cfg.to_dot(),
+            "digraph cfg {\n    node [shape=box, fontname=\"monospace\"];\n    b0 [label=\"LOOP:\\lx3000  BRnzp x3000\\l\"];\n    b0 -> b0 [label=\"branch\"];\n}\n"
+        );
+        assert_eq!(
+            cfg.to_json(),
+            "{\"entry\":0,\"blocks\":[{\"id\":0,\"start\":12288,\"end\":12289,\"label\":\"LOOP\",\"dominator\":null,\"instructions\":[{\"address\":12288,\"file\":0,\"line\":2,\"text\":\"BRnzp x3000\"}]}],\"edges\":[{\"from\":0,\"to\":0,\"kind\":\"branch\"}],\"subroutines\":[],\"loops\":[{\"header\":0,\"blocks\":[0]}]}"
+        );
+    }
+}
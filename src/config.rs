@@ -0,0 +1,108 @@
+use wasm_bindgen::prelude::*;
+
+use crate::memory::PowerOn;
+
+/// State of the machine at reset. The defaults start the OS at x0200 in supervisor mode at
+/// priority 0, with the supervisor stack at x3000 and the user stack at xFE00.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CoreConfig {
+    pub(crate) pc: u16,
+    pub(crate) ssp: u16,
+    pub(crate) usp: u16,
+    pub(crate) user_mode: bool,
+    pub(crate) priority: u8,
+    pub(crate) power_on: PowerOn,
+}
+
+impl Default for CoreConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl CoreConfig {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> CoreConfig {
+        CoreConfig {
+            pc: 0x0200,
+            ssp: 0x3000,
+            usp: 0xFE00,
+            user_mode: false,
+            priority: 0,
+            power_on: PowerOn::Zero,
+        }
+    }
+    pub fn pc(mut self, pc: u16) -> CoreConfig {
+        self.pc = pc;
+        self
+    }
+    // Supervisor stack pointer, in R6 unless starting in user mode
+    pub fn ssp(mut self, ssp: u16) -> CoreConfig {
+        self.ssp = ssp;
+        self
+    }
+    // User stack pointer, in R6 when starting in user mode
+    pub fn usp(mut self, usp: u16) -> CoreConfig {
+        self.usp = usp;
+        self
+    }
+    pub fn user_mode(mut self, user_mode: bool) -> CoreConfig {
+        self.user_mode = user_mode;
+        self
+    }
+    // Levels above 7 are taken as 7.
+    pub fn priority(mut self, priority: u8) -> CoreConfig {
+        self.priority = priority.min(7);
+        self
+    }
+    pub fn fill(self, value: u16) -> CoreConfig {
+        self.power_on(PowerOn::Fill(value))
+    }
+    pub fn random(self, seed: u64) -> CoreConfig {
+        self.power_on(PowerOn::Random(seed))
+    }
+
+    // Processor status register at reset, condition codes cleared.
+    pub(crate) fn psr(&self) -> u16 {
+        ((self.user_mode as u16) << 15) | ((self.priority as u16) << 8)
+    }
+}
+
+impl CoreConfig {
+    pub fn power_on(mut self, power_on: PowerOn) -> CoreConfig {
+        self.power_on = power_on;
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Core;
+
+    #[test]
+    fn test_config() {
+        let c = Core::with_config(&CoreConfig::new());
+        assert_eq!((c.pc(), c.register(6), c.psr()), (0x0200, 0x3000, 0));
+
+        let config = CoreConfig::new()
+            .pc(0x3000)
+            .ssp(0x2FFF)
+            .usp(0xF000)
+            .user_mode(true)
+            .priority(9)
+            .fill(0x1234);
+        let c = Core::with_config(&config);
+        assert_eq!(c.pc(), 0x3000);
+        assert_eq!(c.register(6), 0xF000);
+        assert_eq!(c.psr(), 0x8700);
+        assert_eq!(c.register(0), 0x1234);
+        // The supervisor stack is back in R6 once a TRAP switches stacks
+        let mut c = c;
+        c.load_obj(&[0x3000, 0xF021]).unwrap();
+        c.step();
+        assert_eq!(c.register(6), 0x2FFD);
+    }
+}
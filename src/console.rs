@@ -0,0 +1,240 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::Rc;
+
+use wasm_bindgen::prelude::*;
+
+use crate::Core;
+
+/// Backend of the keyboard (KBSR/KBDR) and display (DSR/DDR) devices.
+pub trait Console {
+    /// Returns the next byte typed by the user, if any. Must not wait for a keystroke.
+    fn read(&mut self) -> Option<u8>;
+    /// Prints a byte written to DDR.
+    fn write(&mut self, byte: u8);
+    /// Whether the user asked to stop the program (e.g. Ctrl+C on a raw terminal).
+    fn interrupted(&mut self) -> bool {
+        false
+    }
+}
+
+impl fmt::Debug for dyn Console {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Console")
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buffers {
+    input: VecDeque<u8>,
+    output: Vec<u8>,
+}
+
+/// In memory console: input is a queue of bytes and output is captured.
+/// Clones share the same buffers, so a handle can be kept after giving one to a Core.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Default)]
+pub struct BufferConsole {
+    buffers: Rc<RefCell<Buffers>>,
+}
+
+#[wasm_bindgen]
+impl BufferConsole {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> BufferConsole {
+        BufferConsole::default()
+    }
+    pub fn push_input(&self, input: &[u8]) {
+        self.buffers.borrow_mut().input.extend(input);
+    }
+    pub fn output(&self) -> Vec<u8> {
+        self.buffers.borrow().output.clone()
+    }
+    // Returns the output produced since the last call.
+    pub fn take_output(&self) -> Vec<u8> {
+        std::mem::take(&mut self.buffers.borrow_mut().output)
+    }
+}
+
+impl BufferConsole {
+    pub fn with_input(input: &[u8]) -> BufferConsole {
+        let console = BufferConsole::new();
+        console.push_input(input);
+        console
+    }
+}
+
+impl Console for BufferConsole {
+    fn read(&mut self) -> Option<u8> {
+        self.buffers.borrow_mut().input.pop_front()
+    }
+    fn write(&mut self, byte: u8) {
+        self.buffers.borrow_mut().output.push(byte);
+    }
+}
+
+#[wasm_bindgen]
+impl Core {
+    // The console stays shared with the given handle.
+    pub fn attach_console(&mut self, console: &BufferConsole) {
+        self.console = Box::new(console.clone());
+    }
+}
+
+impl Core {
+    pub fn set_console(&mut self, console: Box<dyn Console>) {
+        self.console = console;
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use terminal::TerminalConsole;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod terminal {
+    use std::collections::VecDeque;
+    use std::io::{self, IsTerminal, Read, Write};
+    use std::panic;
+    use std::sync::mpsc::{self, Receiver};
+    use std::sync::Once;
+    use std::thread;
+    use std::time::Duration;
+
+    use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+    use crossterm::terminal;
+
+    use super::Console;
+
+    /// Console reading keystrokes from a terminal in raw mode (no echo, no line
+    /// buffering) and printing to stdout. The terminal is restored when the
+    /// console is dropped or the program panics.
+    /// When stdin is not a terminal (e.g. piped input) bytes are read from it as is, by a
+    /// thread so that reads never block.
+    pub struct TerminalConsole {
+        input: VecDeque<u8>,
+        interrupted: bool,
+        raw: bool,
+        piped: Option<Receiver<u8>>,
+    }
+
+    static PANIC_HOOK: Once = Once::new();
+
+    impl TerminalConsole {
+        pub fn new() -> io::Result<TerminalConsole> {
+            PANIC_HOOK.call_once(|| {
+                let hook = panic::take_hook();
+                panic::set_hook(Box::new(move |info| {
+                    let _ = terminal::disable_raw_mode();
+                    hook(info);
+                }));
+            });
+            let raw = io::stdin().is_terminal();
+            if raw {
+                terminal::enable_raw_mode()?;
+            }
+            Ok(TerminalConsole {
+                input: VecDeque::new(),
+                interrupted: false,
+                raw,
+                piped: (!raw).then(read_stdin),
+            })
+        }
+
+        // Moves pending key presses to the input queue.
+        fn poll(&mut self) {
+            while let Ok(true) = event::poll(Duration::ZERO) {
+                let Ok(Event::Key(key)) = event::read() else {
+                    continue;
+                };
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+                match key.code {
+                    KeyCode::Char('c') if ctrl => self.interrupted = true,
+                    // Ctrl+A..Ctrl+Z map to 1..26 like on a real terminal
+                    KeyCode::Char(c) if ctrl && c.is_ascii_lowercase() => {
+                        self.input.push_back(c as u8 - b'a' + 1)
+                    }
+                    KeyCode::Char(c) if c.is_ascii() => self.input.push_back(c as u8),
+                    KeyCode::Enter => self.input.push_back(b'\n'),
+                    KeyCode::Tab => self.input.push_back(b'\t'),
+                    KeyCode::Backspace => self.input.push_back(0x08),
+                    KeyCode::Esc => self.input.push_back(0x1B),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    // Sends the bytes of stdin as they come, until its end.
+    fn read_stdin() -> Receiver<u8> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut buffer = [0; 4096];
+            let mut stdin = io::stdin();
+            while let Ok(n @ 1..) = stdin.read(&mut buffer) {
+                if buffer[..n].iter().any(|&byte| sender.send(byte).is_err()) {
+                    break;
+                }
+            }
+        });
+        receiver
+    }
+
+    impl Console for TerminalConsole {
+        fn read(&mut self) -> Option<u8> {
+            if let Some(piped) = &self.piped {
+                return piped.try_recv().ok();
+            }
+            if self.input.is_empty() {
+                self.poll();
+            }
+            self.input.pop_front()
+        }
+        fn write(&mut self, byte: u8) {
+            let mut stdout = io::stdout();
+            // Raw mode does not move back to the first column on line feeds
+            let _ = if self.raw && byte == b'\n' {
+                stdout.write_all(b"\r\n")
+            } else {
+                stdout.write_all(&[byte])
+            };
+            let _ = stdout.flush();
+        }
+        fn interrupted(&mut self) -> bool {
+            if self.raw {
+                self.poll();
+            }
+            self.interrupted
+        }
+    }
+
+    impl Drop for TerminalConsole {
+        fn drop(&mut self) {
+            if self.raw {
+                let _ = terminal::disable_raw_mode();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buffer_console() {
+        let console = BufferConsole::with_input(b"ab");
+        let mut handle: Box<dyn Console> = Box::new(console.clone());
+        assert_eq!(handle.read(), Some(b'a'));
+        handle.write(b'x');
+        console.push_input(b"c");
+        assert_eq!(handle.read(), Some(b'b'));
+        assert_eq!(handle.read(), Some(b'c'));
+        assert_eq!(handle.read(), None);
+        assert_eq!(console.take_output(), b"x");
+        assert!(console.output().is_empty());
+    }
+}
@@ -0,0 +1,199 @@
+use std::collections::HashSet;
+use std::fmt;
+
+use crate::diagnostic::Diagnostic;
+use crate::lexer::{tokenize, TokenKind};
+use crate::parser::parse_opcode;
+
+/// A token with its text as written and the whitespace before it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CstToken {
+    pub kind: TokenKind,
+    pub column: usize,
+    pub leading: String,
+    pub text: String,
+}
+
+/// One line of source: the tokens, their leading whitespace and the trailing whitespace
+/// give the line back exactly.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CstLine {
+    pub tokens: Vec<CstToken>,
+    pub trailing: String,
+    // Why the line could not be split in tokens, trailing then holding the whole line
+    pub error: Option<Diagnostic>,
+    // Indices in tokens of the label, of the instruction, directive or macro invoked, and of
+    // the comment
+    pub label: Option<usize>,
+    pub operation: Option<usize>,
+    pub comment: Option<usize>,
+}
+
+impl CstLine {
+    // Tokens between the operation and the comment.
+    pub fn operands(&self) -> &[CstToken] {
+        let end = self.comment.unwrap_or(self.tokens.len());
+        let start = self.operation.map_or(end, |i| i + 1);
+        &self.tokens[start.min(end)..end]
+    }
+
+    // The colon written after the label, if any.
+    pub fn colon(&self) -> Option<&CstToken> {
+        let token = self.tokens.get(self.label? + 1)?;
+        (token.kind == TokenKind::Colon).then_some(token)
+    }
+
+    fn parse(text: &str, number: usize, macros: &mut HashSet<String>) -> CstLine {
+        let tokens = match tokenize(text, number) {
+            Ok(tokens) => tokens,
+            Err(e) => {
+                return CstLine {
+                    trailing: text.to_string(),
+                    error: Some(e),
+                    ..CstLine::default()
+                }
+            }
+        };
+        // Byte offset of each column
+        let offsets: Vec<usize> = text
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain([text.len()])
+            .collect();
+        let mut line = CstLine::default();
+        let mut end = 0;
+        for (i, token) in tokens.iter().enumerate() {
+            let start = offsets[token.column - 1];
+            let next = tokens
+                .get(i + 1)
+                .map_or(text.len(), |next| offsets[next.column - 1]);
+            let written = text[start..next].trim_end();
+            line.tokens.push(CstToken {
+                kind: token.kind.clone(),
+                column: token.column,
+                leading: text[end..start].to_string(),
+                text: written.to_string(),
+            });
+            end = start + written.len();
+        }
+        line.trailing = text[end..].to_string();
+
+        let mut next = 0;
+        if let Some(TokenKind::Word(word)) = tokens.first().map(|t| &t.kind) {
+            if parse_opcode(word).is_none() && !macros.contains(&word.to_ascii_uppercase()) {
+                line.label = Some(0);
+                next = match tokens.get(1).map(|t| &t.kind) {
+                    Some(TokenKind::Colon) => 2,
+                    _ => 1,
+                };
+            }
+        }
+        if let Some(TokenKind::Word(_) | TokenKind::Directive(_)) =
+            tokens.get(next).map(|t| &t.kind)
+        {
+            line.operation = Some(next);
+        }
+        if let Some(TokenKind::Comment(_)) = tokens.last().map(|t| &t.kind) {
+            line.comment = Some(tokens.len() - 1);
+        }
+        // Later lines may invoke the macro
+        if let (Some(TokenKind::Directive(directive)), Some(TokenKind::Word(name))) = (
+            tokens.get(next).map(|t| &t.kind),
+            tokens.get(next + 1).map(|t| &t.kind),
+        ) {
+            if directive == "MACRO" {
+                macros.insert(name.to_ascii_uppercase());
+            }
+        }
+        line
+    }
+}
+
+impl fmt::Display for CstLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for token in &self.tokens {
+            write!(f, "{}{}", token.leading, token.text)?;
+        }
+        f.write_str(&self.trailing)
+    }
+}
+
+/// Lossless concrete syntax tree of a file: printing it gives the source back, comments,
+/// blank lines and spelling included.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cst {
+    pub lines: Vec<CstLine>,
+    // Whether the last line ends with a newline
+    pub final_newline: bool,
+}
+
+impl Cst {
+    pub fn parse(source: &str) -> Cst {
+        let (content, final_newline) = match source.strip_suffix('\n') {
+            Some(content) => (content, true),
+            None => (source, false),
+        };
+        let mut macros = HashSet::new();
+        let lines = content
+            .split('\n')
+            .enumerate()
+            .map(|(i, line)| CstLine::parse(line, i + 1, &mut macros))
+            .collect();
+        Cst {
+            lines,
+            final_newline,
+        }
+    }
+}
+
+impl fmt::Display for Cst {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, line) in self.lines.iter().enumerate() {
+            if i > 0 {
+                f.write_str("\n")?;
+            }
+            write!(f, "{}", line)?;
+        }
+        if self.final_newline {
+            f.write_str("\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lossless() {
+        let sources = [
+            "",
+            "\n",
+            "; header\n\t.orig\tX3000 \r\nloop:  add r1,R1 ,#1\t; incr  \n  BRnz loop\n",
+            ".MACRO push reg\nSTR reg, R6, #0\n.ENDM\nPUSH R1\nmsg .STRINGZ \"a ;b\" ; c",
+            "ok\n@@@ bad line\nLD R0, =x-10+(2*N)",
+        ];
+        for source in sources {
+            assert_eq!(Cst::parse(source).to_string(), source);
+        }
+    }
+
+    #[test]
+    fn test_structure() {
+        let cst = Cst::parse(".MACRO push reg\n.ENDM\nloop: PUSH R1 ; save\nPUSH\nHALT\nEND\n@");
+        let line = &cst.lines[2];
+        assert_eq!(line.tokens[line.label.unwrap()].text, "loop");
+        assert_eq!(line.colon().unwrap().text, ":");
+        assert_eq!(line.tokens[line.operation.unwrap()].text, "PUSH");
+        assert_eq!(line.operands().len(), 1);
+        assert_eq!(line.tokens[line.comment.unwrap()].text, "; save");
+        assert_eq!(cst.lines[3].label, None);
+        assert_eq!(cst.lines[4].operation, Some(0));
+        assert_eq!(
+            (cst.lines[5].label, cst.lines[5].operation),
+            (Some(0), None)
+        );
+        assert!(cst.lines[6].error.is_some());
+    }
+}
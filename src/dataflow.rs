@@ -0,0 +1,585 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use wasm_bindgen::prelude::*;
+
+use crate::cfg::ControlFlowGraph;
+use crate::debuginfo::DebugInfo;
+use crate::diagnostic::Diagnostic;
+use crate::disasm::pc_relative;
+use crate::opcode::OpCode;
+
+// Sets of registers are bit masks, R0 being bit 0. The condition codes count as a ninth
+// register, set by the instructions writing a register and read by conditional branches.
+const REGISTERS: u16 = 0xFF;
+const CC: u16 = 1 << 8;
+const R7: u16 = 1 << 7;
+
+/// Registers subroutines must give back unchanged to their caller. None by default.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CallingConvention {
+    preserved: u16,
+}
+
+impl CallingConvention {
+    pub fn preserving(registers: &[u8]) -> Self {
+        CallingConvention {
+            preserved: registers.iter().fold(0, |set, r| set | 1 << r),
+        }
+    }
+
+    pub fn preserves(&self, register: u8) -> bool {
+        self.preserved & 1 << register != 0
+    }
+
+    // Registers and ranges of registers, as in "R1-R5, R6".
+    pub fn parse(spec: &str) -> Result<CallingConvention, String> {
+        let register = |s: &str| match s.trim().as_bytes() {
+            [b'r' | b'R', digit @ b'0'..=b'6'] => Ok(digit - b'0'),
+            [b'r' | b'R', b'7'] => {
+                Err("R7 holds the return address and cannot be preserved.".to_string())
+            }
+            _ => Err(format!("Expected a register, found {}.", s.trim())),
+        };
+        let mut registers = Vec::new();
+        for item in spec.split(',').filter(|s| !s.trim().is_empty()) {
+            match item.split_once('-') {
+                Some((first, last)) => registers.extend(register(first)?..=register(last)?),
+                None => registers.push(register(item)?),
+            }
+        }
+        Ok(CallingConvention::preserving(&registers))
+    }
+}
+
+fn bit(register: u16) -> u16 {
+    1 << register
+}
+
+// Registers written and read by an instruction, calls aside.
+fn effect(inst: u16) -> (u16, u16) {
+    let dr = bit(get_bits!(inst, 9, 3));
+    let sr1 = bit(get_bits!(inst, 6, 3));
+    match OpCode::from(inst) {
+        // AND R1, R1, #0 clears R1 whatever it held
+        OpCode::AND if get_bits!(inst, 0, 6) == 0b100000 => (dr | CC, 0),
+        OpCode::ADD | OpCode::AND if get_bits!(inst, 5, 1) == 1 => (dr | CC, sr1),
+        OpCode::ADD | OpCode::AND => (dr | CC, sr1 | bit(get_bits!(inst, 0, 3))),
+        OpCode::NOT => (dr | CC, sr1),
+        OpCode::LD | OpCode::LDI | OpCode::LEA => (dr | CC, 0),
+        OpCode::LDR => (dr | CC, sr1),
+        OpCode::ST | OpCode::STI => (0, dr),
+        OpCode::STR => (0, dr | sr1),
+        OpCode::BR => match get_bits!(inst, 9, 3) {
+            0 | 0b111 => (0, 0),
+            _ => (0, CC),
+        },
+        OpCode::JMP => (0, sr1),
+        OpCode::JSR if get_bits!(inst, 11, 1) == 1 => (R7 | CC, 0),
+        OpCode::JSR => (R7 | CC, sr1),
+        // GETC and IN read a character in R0, OUT, PUTS and PUTSP print from R0
+        OpCode::TRAP => match get_bits!(inst, 0, 8) {
+            0x20 | 0x23 => (R7 | CC | bit(0), 0),
+            0x21 | 0x22 | 0x24 => (R7 | CC, bit(0)),
+            _ => (R7 | CC, 0),
+        },
+        OpCode::RTI | OpCode::UNKNOWN => (0, 0),
+    }
+}
+
+// Register written by the instruction in its destination field, if any.
+fn destination(inst: u16) -> Option<u16> {
+    match OpCode::from(inst) {
+        OpCode::ADD
+        | OpCode::AND
+        | OpCode::NOT
+        | OpCode::LD
+        | OpCode::LDI
+        | OpCode::LDR
+        | OpCode::LEA => Some(get_bits!(inst, 9, 3)),
+        _ => None,
+    }
+}
+
+fn is_ret(inst: u16) -> bool {
+    OpCode::from(inst) == OpCode::JMP && get_bits!(inst, 6, 3) == 7
+}
+
+fn registers(set: u16) -> impl Iterator<Item = u16> {
+    (0..8).filter(move |r| set & bit(*r) != 0)
+}
+
+// Registers a subroutine may read before writing them, and may write, calls included.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Summary {
+    uses: u16,
+    defs: u16,
+}
+
+// Where a register was saved, to recognize the load restoring it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Slot {
+    // ST and LD
+    Direct(u16),
+    // STI and LDI
+    Indirect(u16),
+    // STR and LDR from the base register
+    Based(u16),
+}
+
+// Value of a preserved register along a path through a subroutine, and the address of
+// the instruction that last changed it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Value {
+    Same,
+    // Incremented or decremented, as the stack pointer is
+    Offset(i32, u16),
+    Changed(u16),
+}
+
+// Offsets beyond which a register is taken as changed, which bounds the search.
+const MAX_OFFSET: i32 = 64;
+
+struct Analysis<'a> {
+    cfg: &'a ControlFlowGraph,
+    // Index of the subroutine at each entry block
+    subroutines: HashMap<usize, usize>,
+    summaries: Vec<Summary>,
+    found: BTreeSet<(u16, String, &'static str)>,
+}
+
+impl<'a> Analysis<'a> {
+    fn new(cfg: &'a ControlFlowGraph) -> Self {
+        let subroutines = cfg
+            .subroutines()
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (s.entry, i))
+            .collect();
+        let mut analysis = Analysis {
+            cfg,
+            subroutines,
+            summaries: vec![Summary::default(); cfg.subroutines().len()],
+            found: BTreeSet::new(),
+        };
+        analysis.summarize();
+        analysis
+    }
+
+    fn word(&self, address: u16) -> u16 {
+        self.cfg.word(address).unwrap_or_default()
+    }
+
+    // Subroutine called by the JSR at address, if it is one of the program.
+    fn callee(&self, address: u16) -> Option<usize> {
+        let inst = self.word(address);
+        if OpCode::from(inst) != OpCode::JSR || get_bits!(inst, 11, 1) == 0 {
+            return None;
+        }
+        let block = self.cfg.block_at(pc_relative(address, inst, 11))?;
+        self.subroutines.get(&block).copied()
+    }
+
+    fn local_successors(&self, block: usize) -> impl Iterator<Item = usize> + '_ {
+        self.cfg
+            .edges()
+            .iter()
+            .filter(move |e| e.from == block && e.kind.is_local())
+            .map(|e| e.to)
+    }
+
+    // Registers live before the instruction, given those live after it.
+    fn live_before(&self, address: u16, after: u16) -> u16 {
+        let inst = self.word(address);
+        let (defs, uses) = effect(inst);
+        match (OpCode::from(inst), self.callee(address)) {
+            (OpCode::JSR, Some(callee)) => (after & !defs) | self.summaries[callee].uses,
+            // Anything may be read by a subroutine out of the program
+            (OpCode::JSR, None) => REGISTERS,
+            _ => (after & !defs) | uses,
+        }
+    }
+
+    // Registers live at the end of each of the given blocks. Those leaving the code, by
+    // RET, JMP, HALT or into data, keep exit live.
+    fn liveness(&self, blocks: &[usize], exit: u16) -> HashMap<usize, u16> {
+        let inside: HashSet<usize> = blocks.iter().copied().collect();
+        let mut live_out: HashMap<usize, u16> = blocks.iter().map(|&b| (b, 0)).collect();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for &block in blocks.iter().rev() {
+                let successors: Vec<usize> = self
+                    .local_successors(block)
+                    .filter(|s| inside.contains(s))
+                    .collect();
+                let mut out = if successors.is_empty() { exit } else { 0 };
+                for successor in successors {
+                    let range = &self.cfg.blocks()[successor];
+                    out |= (range.start..range.end)
+                        .rev()
+                        .fold(live_out[&successor], |live, a| self.live_before(a, live));
+                }
+                if live_out[&block] != out {
+                    live_out.insert(block, out);
+                    changed = true;
+                }
+            }
+        }
+        live_out
+    }
+
+    // Uses and definitions of every subroutine, iterated until stable as subroutines may
+    // call each other.
+    fn summarize(&mut self) {
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (i, subroutine) in self.cfg.subroutines().iter().enumerate() {
+                let live_out = self.liveness(&subroutine.blocks, 0);
+                let entry = &self.cfg.blocks()[subroutine.entry];
+                // R7 read by RET is the one written by the JSR
+                let uses = (entry.start..entry.end)
+                    .rev()
+                    .fold(live_out[&subroutine.entry], |live, a| {
+                        self.live_before(a, live)
+                    })
+                    & REGISTERS
+                    & !R7;
+                let mut defs = R7;
+                for &block in &subroutine.blocks {
+                    let range = &self.cfg.blocks()[block];
+                    for address in range.start..range.end {
+                        defs |= effect(self.word(address)).0 & REGISTERS;
+                        if let Some(callee) = self.callee(address) {
+                            defs |= self.summaries[callee].defs;
+                        }
+                    }
+                }
+                let summary = Summary { uses, defs };
+                if self.summaries[i] != summary {
+                    self.summaries[i] = summary;
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    fn report(&mut self, address: u16, message: String, rule: &'static str) {
+        self.found.insert((address, message, rule));
+    }
+
+    // Registers that may not have been written yet, from the start of the program.
+    fn uninitialized(&mut self) {
+        let Some(entry) = self.cfg.entry() else {
+            return;
+        };
+        let mut unset: HashMap<usize, u16> = HashMap::from([(entry, REGISTERS)]);
+        let mut pending = vec![entry];
+        let mut reads = Vec::new();
+        while let Some(block) = pending.pop() {
+            let range = &self.cfg.blocks()[block];
+            let mut state = unset[&block];
+            for address in range.start..range.end {
+                let inst = self.word(address);
+                let (defs, uses) = effect(inst);
+                let (defs, uses) = match self.callee(address) {
+                    Some(callee) => {
+                        let summary = self.summaries[callee];
+                        (summary.defs, summary.uses)
+                    }
+                    None => (defs, uses),
+                };
+                reads.push((address, uses & state & REGISTERS));
+                state &= !defs;
+            }
+            for successor in self.local_successors(block).collect::<Vec<_>>() {
+                let before = unset.get(&successor).copied();
+                let after = before.unwrap_or(0) | state;
+                if before != Some(after) {
+                    unset.insert(successor, after);
+                    pending.push(successor);
+                }
+            }
+        }
+        // The last state of each instruction holds every path
+        let mut unread = HashMap::new();
+        for (address, registers) in reads {
+            *unread.entry(address).or_insert(0) |= registers;
+        }
+        for (address, set) in unread {
+            let call = self
+                .callee(address)
+                .map(|callee| &self.cfg.subroutines()[callee].name);
+            for r in registers(set) {
+                let message = match call {
+                    Some(name) => format!("{} reads R{}, which may not have been set.", name, r),
+                    None => format!("R{} is read but may not have been set.", r),
+                };
+                self.report(address, message, "uninitialized-register");
+            }
+        }
+    }
+
+    // Writes overwritten before being read. Registers are taken as read after leaving the
+    // code, where the caller or the user may look at them.
+    fn dead_stores(&mut self) {
+        let blocks: Vec<usize> = (0..self.cfg.blocks().len()).collect();
+        let live_out = self.liveness(&blocks, REGISTERS | CC);
+        for block in blocks {
+            let range = &self.cfg.blocks()[block];
+            let mut live = live_out[&block];
+            for address in (range.start..range.end).rev() {
+                let inst = self.word(address);
+                if let Some(dr) = destination(inst) {
+                    if live & (bit(dr) | CC) == 0 {
+                        let message = format!("The value written to R{} is never read.", dr);
+                        self.report(address, message, "dead-store");
+                    }
+                }
+                live = self.live_before(address, live);
+            }
+        }
+    }
+
+    // Follows each subroutine from its entry to its RETs, tracking whether each preserved
+    // register still holds its value. Called subroutines are assumed to follow the
+    // convention and traps to only change R0 as GETC and IN do.
+    fn clobbered(&mut self, convention: &CallingConvention) {
+        for subroutine in self.cfg.subroutines() {
+            for r in registers(convention.preserved) {
+                let start = self.cfg.blocks()[subroutine.entry].start;
+                let initial = (start, Value::Same, None);
+                let mut visited = HashSet::from([initial]);
+                let mut pending = vec![initial];
+                let mut changes = BTreeSet::new();
+                while let Some((address, value, saved)) = pending.pop() {
+                    let inst = self.word(address);
+                    if is_ret(inst) {
+                        match value {
+                            Value::Same => (),
+                            Value::Offset(_, at) | Value::Changed(at) => {
+                                changes.insert(at);
+                            }
+                        }
+                        continue;
+                    }
+                    let (value, saved) = self.preserved_after(address, r, value, saved);
+                    let block = self.cfg.block_at(address).unwrap_or_default();
+                    let range = &self.cfg.blocks()[block];
+                    let next: Vec<u16> = match address.wrapping_add(1) {
+                        next if next < range.end => vec![next],
+                        _ => self
+                            .local_successors(block)
+                            .filter(|s| subroutine.blocks.contains(s))
+                            .map(|s| self.cfg.blocks()[s].start)
+                            .collect(),
+                    };
+                    for next in next {
+                        if visited.insert((next, value, saved)) {
+                            pending.push((next, value, saved));
+                        }
+                    }
+                }
+                for at in changes {
+                    let message = format!(
+                        "{} changes R{} without restoring it before returning, but the calling convention preserves it.",
+                        subroutine.name, r
+                    );
+                    self.report(at, message, "clobbered-register");
+                }
+            }
+        }
+    }
+
+    fn preserved_after(
+        &self,
+        address: u16,
+        r: u16,
+        value: Value,
+        saved: Option<Slot>,
+    ) -> (Value, Option<Slot>) {
+        let inst = self.word(address);
+        let sr = get_bits!(inst, 9, 3);
+        let base = get_bits!(inst, 6, 3);
+        let target = pc_relative(address, inst, 9);
+        let slot = match OpCode::from(inst) {
+            OpCode::ST | OpCode::LD => Some(Slot::Direct(target)),
+            OpCode::STI | OpCode::LDI => Some(Slot::Indirect(target)),
+            OpCode::STR | OpCode::LDR => Some(Slot::Based(base)),
+            _ => None,
+        };
+        match OpCode::from(inst) {
+            OpCode::ST | OpCode::STI | OpCode::STR if sr == r && value == Value::Same => {
+                return (value, slot);
+            }
+            OpCode::LD | OpCode::LDI | OpCode::LDR
+                if sr == r && slot == saved && saved.is_some() =>
+            {
+                return (Value::Same, saved);
+            }
+            // ADD r, r, #imm
+            OpCode::ADD if sr == r && base == r && get_bits!(inst, 5, 1) == 1 => {
+                let imm = extend_to_u16!(get_bits!(inst, 0, 5), 5) as i16 as i32;
+                let offset = match value {
+                    Value::Same => imm,
+                    Value::Offset(offset, _) => offset + imm,
+                    Value::Changed(_) => return (value, saved),
+                };
+                return match offset {
+                    0 => (Value::Same, saved),
+                    offset if offset.abs() > MAX_OFFSET => (Value::Changed(address), saved),
+                    offset => (Value::Offset(offset, address), saved),
+                };
+            }
+            _ => (),
+        }
+        let writes = match self.callee(address) {
+            Some(_) => false,
+            None => effect(inst).0 & bit(r) != 0,
+        };
+        match (writes, value) {
+            (true, _) => (Value::Changed(address), saved),
+            (false, value) => (value, saved),
+        }
+    }
+}
+
+// Uninitialized registers, dead stores and, for the registers the convention preserves,
+// subroutines changing them. Diagnostics are located by the debug info of the graph.
+pub fn analyze_registers(
+    cfg: &ControlFlowGraph,
+    convention: &CallingConvention,
+) -> Vec<Diagnostic> {
+    let mut analysis = Analysis::new(cfg);
+    analysis.uninitialized();
+    analysis.dead_stores();
+    analysis.clobbered(convention);
+    analysis
+        .found
+        .into_iter()
+        .map(|(address, message, rule)| {
+            let message = format!("{} (x{:04X})", message, address);
+            let diagnostic = match cfg.location(address) {
+                Some(l) => Diagnostic::warning(l.line, l.column, message).in_file(l.file),
+                None => Diagnostic::warning(0, 0, message),
+            };
+            diagnostic.with_rule(rule)
+        })
+        .collect()
+}
+
+// Register diagnostics of an assembled program for the web editor, one per line, the
+// preserved registers being given as by CallingConvention::parse.
+#[wasm_bindgen]
+pub fn register_report(
+    object: Vec<u16>,
+    info: &DebugInfo,
+    preserved: &str,
+) -> Result<String, String> {
+    let convention = CallingConvention::parse(preserved)?;
+    let cfg = ControlFlowGraph::build(&object, info);
+    let diagnostics = analyze_registers(&cfg, &convention);
+    Ok(diagnostics
+        .iter()
+        .map(|d| d.to_string())
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble::assemble;
+
+    // Rule and line of each diagnostic on the program.
+    fn analyze(lines: &[&str], preserved: &str) -> Vec<(&'static str, usize)> {
+        let assembly = assemble(lines.iter().map(|l| l.to_string()).collect()).unwrap();
+        let cfg = ControlFlowGraph::build(&assembly.object(), &assembly.debug_info("main.asm"));
+        let convention = CallingConvention::parse(preserved).unwrap();
+        analyze_registers(&cfg, &convention)
+            .iter()
+            .map(|d| (d.rule.unwrap(), d.line))
+            .collect()
+    }
+
+    #[test]
+    fn test_uninitialized() {
+        let program = [
+            ".ORIG x3000",
+            "        AND R1, R1, #0",
+            "        LD R2, N",
+            "        BRz SKIP",
+            "        ADD R3, R2, #0",
+            "SKIP    ADD R1, R1, R3",
+            "        JSR SUB",
+            "        ST R1, N",
+            "        HALT",
+            "SUB     ADD R4, R4, #1",
+            "        ADD R0, R4, #0",
+            "        RET",
+            "N       .FILL 3",
+        ];
+        assert_eq!(
+            analyze(&program, ""),
+            [("uninitialized-register", 6), ("uninitialized-register", 7),]
+        );
+    }
+
+    #[test]
+    fn test_dead_stores() {
+        let program = [
+            ".ORIG x3000",
+            "        LD R1, N",
+            "        LD R1, N",
+            "        ADD R2, R1, #1",
+            "        AND R2, R2, #0",
+            "        ADD R1, R1, #0",
+            "        BRp DONE",
+            "        ADD R2, R2, R2",
+            "DONE    HALT",
+            "N       .FILL 3",
+        ];
+        assert_eq!(
+            analyze(&program, ""),
+            [("dead-store", 2), ("dead-store", 4)]
+        );
+    }
+
+    #[test]
+    fn test_clobbered() {
+        let program = [
+            ".ORIG x3000",
+            "        AND R0, R0, #0",
+            "        AND R1, R1, #0",
+            "        AND R2, R2, #0",
+            "        ADD R6, R0, #0",
+            "        JSR SAVES",
+            "        JSR BREAKS",
+            "        HALT",
+            "SAVES   ADD R6, R6, #-1",
+            "        STR R1, R6, #0",
+            "        ST R2, SAVE",
+            "        ADD R1, R0, #1",
+            "        ADD R2, R1, R1",
+            "        ADD R0, R2, #0",
+            "        LD R2, SAVE",
+            "        LDR R1, R6, #0",
+            "        ADD R6, R6, #1",
+            "        RET",
+            "BREAKS  ADD R1, R0, #0",
+            "        BRz DONE",
+            "        ADD R6, R6, #-1",
+            "DONE    RET",
+            "SAVE    .BLKW 1",
+        ];
+        assert_eq!(
+            analyze(&program, "R1-R2,R6"),
+            [("clobbered-register", 19), ("clobbered-register", 21)]
+        );
+        assert_eq!(
+            CallingConvention::parse("R1, R7"),
+            Err("R7 holds the return address and cannot be preserved.".into())
+        );
+        assert!(CallingConvention::parse("R1-R3").unwrap().preserves(2));
+    }
+}
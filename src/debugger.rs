@@ -0,0 +1,215 @@
+use wasm_bindgen::prelude::*;
+
+use crate::assemble::parse_symbol_file;
+use crate::memory::UninitializedAccess;
+use crate::opcode::OpCode;
+use crate::Core;
+
+// Guards against programs that JSR without ever returning.
+const MAX_CALL_DEPTH: usize = 1024;
+// How often the console is asked whether the user wants to stop.
+const INTERRUPT_CHECK_STEPS: usize = 4096;
+
+/// Why `Core::run` gave control back to the caller.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Breakpoint(u16),
+    Halted,
+    // The user asked the console to stop the program
+    Interrupted,
+    StepLimit,
+    // A word never written was read or fetched, with the check set to stop
+    Uninitialized(UninitializedAccess),
+    // The reserved opcode 1101 was reached, with the stop set instead of the exception
+    IllegalOpcode { address: u16, word: u16 },
+}
+
+/// One entry of the call stack, pushed by JSR/JSRR/TRAP and popped by RET.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame {
+    pub call_site: u16,
+    pub entry: u16,
+}
+
+#[wasm_bindgen]
+impl Core {
+    // Returns true if a breakpoint is now set at address.
+    pub fn toggle_breakpoint(&mut self, address: u16) -> bool {
+        if self.breakpoints.remove(&address) {
+            return false;
+        }
+        self.breakpoints.insert(address);
+        true
+    }
+    pub fn has_breakpoint(&self, address: u16) -> bool {
+        self.breakpoints.contains(&address)
+    }
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+    pub fn breakpoints(&self) -> Vec<u16> {
+        self.breakpoints.iter().copied().collect()
+    }
+    // Loads the labels of a .sym file so they can be shown next to addresses.
+    pub fn load_symbols(&mut self, sym_file: &str) {
+        for symbol in parse_symbol_file(sym_file) {
+            self.symbols.entry(symbol.address).or_insert(symbol.name);
+        }
+    }
+    pub fn symbol_at(&self, address: u16) -> Option<String> {
+        self.symbols.get(&address).cloned()
+    }
+    // Stops the run on the reserved opcode 1101 rather than raising the illegal opcode
+    // exception.
+    pub fn set_stop_on_illegal_opcode(&mut self, stop: bool) {
+        self.stop_on_illegal_opcode = stop;
+    }
+    // Runs at most max_steps instructions. Returns false if the run stopped early.
+    pub fn run_for(&mut self, max_steps: usize) -> bool {
+        self.run(max_steps) == StopReason::StepLimit
+    }
+}
+
+impl Core {
+    // Executes instructions until the machine halts, a breakpoint is reached or max_steps
+    // have been executed. A breakpoint on the current PC does not stop the run, so that
+    // continuing from a breakpoint makes progress.
+    pub fn run(&mut self, max_steps: usize) -> StopReason {
+        for i in 0..max_steps {
+            if self.halted() {
+                return StopReason::Halted;
+            }
+            if i > 0 && self.breakpoints.contains(&self.pc) {
+                return StopReason::Breakpoint(self.pc);
+            }
+            if i % INTERRUPT_CHECK_STEPS == INTERRUPT_CHECK_STEPS - 1 && self.console.interrupted()
+            {
+                return StopReason::Interrupted;
+            }
+            self.step();
+            if let Some(reason) = self.stop.take() {
+                return reason;
+            }
+        }
+        StopReason::StepLimit
+    }
+
+    // Innermost call last.
+    pub fn call_stack(&self) -> &[Frame] {
+        &self.call_stack
+    }
+
+    pub(crate) fn track_call(&mut self, address: u16, instruction: u16) {
+        match OpCode::from(instruction) {
+            OpCode::JSR | OpCode::TRAP => {
+                if self.call_stack.len() == MAX_CALL_DEPTH {
+                    self.call_stack.remove(0);
+                }
+                self.call_stack.push(Frame {
+                    call_site: address,
+                    entry: self.pc,
+                });
+            }
+            // RET is JMP R7
+            OpCode::JMP if get_bits!(instruction, 6, 3) == 7 => {
+                self.call_stack.pop();
+            }
+            // RTI leaves interrupts and exceptions, which push no frame, and the trap
+            // routines entered from user mode, whose frame their RET popped
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::console::BufferConsole;
+
+    #[test]
+    fn test_breakpoint() {
+        let program: [u16; 4] = [
+            0x0200,
+            // ADD  R1  R1     1
+            0b0001_001_001_1_00001,
+            0b0001_001_001_1_00001,
+            0b0001_001_001_1_00001,
+        ];
+        let mut c = Core::new();
+        c.load_obj(&program).unwrap();
+        assert!(c.toggle_breakpoint(0x0202));
+        assert_eq!(c.run(10), StopReason::Breakpoint(0x0202));
+        assert_eq!(c.register(1), 2);
+        // Continuing steps over the breakpoint we are sitting on
+        assert_eq!(c.run(1), StopReason::StepLimit);
+        assert_eq!(c.register(1), 3);
+        assert!(!c.toggle_breakpoint(0x0202));
+        assert!(c.breakpoints().is_empty());
+    }
+
+    #[test]
+    fn test_call_stack() {
+        let program: [u16; 5] = [
+            0x0200,
+            // JSR  +1
+            0b0100_1_00000000001,
+            // BR   -2 (never taken, NOP)
+            0b0000_0_0_0_111111110,
+            // ADD  R1  R1     1
+            0b0001_001_001_1_00001,
+            // RET
+            0b1100_000_111_000000,
+        ];
+        let mut c = Core::new();
+        c.load_obj(&program).unwrap();
+        c.step();
+        assert_eq!(
+            c.call_stack(),
+            &[Frame {
+                call_site: 0x0200,
+                entry: 0x0202
+            }]
+        );
+        c.step();
+        c.step();
+        assert!(c.call_stack().is_empty());
+        assert_eq!(c.pc(), 0x0201);
+    }
+
+    #[test]
+    fn test_symbols() {
+        let mut c = Core::new();
+        c.load_symbols("// Symbol table\n//\tLOOP              3000\n//\tDONE              3004\n");
+        assert_eq!(c.symbol_at(0x3000), Some("LOOP".into()));
+        assert_eq!(c.symbol_at(0x3004), Some("DONE".into()));
+        assert_eq!(c.symbol_at(0x3001), None);
+    }
+
+    #[test]
+    fn test_illegal_opcode() {
+        let program: [u16; 3] = [
+            0x0200, //
+            // ADD  R1  R1     1
+            0b0001_001_001_1_00001,
+            // Reserved opcode
+            0b1101_0000_0010_0011,
+        ];
+        let console = BufferConsole::new();
+        let mut c = Core::new();
+        c.set_console(Box::new(console.clone()));
+        c.load_obj(&program).unwrap();
+        c.set_stop_on_illegal_opcode(true);
+        let stop = StopReason::IllegalOpcode {
+            address: 0x0201,
+            word: 0xD023,
+        };
+        assert_eq!(c.run(10), stop);
+        assert_eq!(c.pc(), 0x0201);
+        assert_eq!(c.run(10), stop);
+        // By default the exception handler of the OS reports it
+        c.set_stop_on_illegal_opcode(false);
+        assert_eq!(c.run(10_000), StopReason::Halted);
+        assert!(console.output().starts_with(b"\n--- Illegal opcode ---\n"));
+        assert_eq!(c.memory_at(0x2FFE), 0x0202);
+    }
+}
@@ -0,0 +1,291 @@
+use wasm_bindgen::prelude::*;
+
+use crate::Core;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WordKind {
+    Code,
+    Data,
+}
+
+/// Where a word of the object comes from. Lines and columns start at 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub address: u16,
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+    pub kind: WordKind,
+}
+
+/// Addresses from start (included) to end (excluded) belong to the label.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LabelRange {
+    pub name: String,
+    pub start: u16,
+    pub end: u16,
+}
+
+/// Links the words of an assembled object back to the source, written as a .dbg file
+/// next to the object.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DebugInfo {
+    files: Vec<String>,
+    locations: Vec<SourceLocation>,
+    labels: Vec<LabelRange>,
+}
+
+impl DebugInfo {
+    pub fn new(
+        files: Vec<String>,
+        locations: Vec<SourceLocation>,
+        labels: Vec<LabelRange>,
+    ) -> Self {
+        DebugInfo {
+            files,
+            locations,
+            labels,
+        }
+    }
+    pub fn files(&self) -> &[String] {
+        &self.files
+    }
+    pub fn locations(&self) -> &[SourceLocation] {
+        &self.locations
+    }
+    pub fn labels(&self) -> &[LabelRange] {
+        &self.labels
+    }
+    pub fn location(&self, address: u16) -> Option<&SourceLocation> {
+        self.locations.iter().find(|l| l.address == address)
+    }
+    // First word emitted by the given line of the given file, or by the closest line after it.
+    pub fn address_of_line(&self, file: usize, line: usize) -> Option<u16> {
+        self.locations
+            .iter()
+            .filter(|l| l.file == file && l.line >= line && l.kind == WordKind::Code)
+            .min_by_key(|l| (l.line, l.address))
+            .map(|l| l.address)
+    }
+    pub fn label_at(&self, address: u16) -> Option<&LabelRange> {
+        self.labels
+            .iter()
+            .find(|l| l.start <= address && address < l.end)
+    }
+
+    pub fn parse(content: &str) -> Result<DebugInfo, String> {
+        let mut info = DebugInfo::default();
+        for (i, line) in content.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let error = || format!("Invalid debug info at line {}: {}", i + 1, line);
+            let address = |s: &str| {
+                s.strip_prefix('x')
+                    .and_then(|s| u16::from_str_radix(s, 16).ok())
+                    .ok_or_else(error)
+            };
+            let number = |s: &str| s.parse::<usize>().map_err(|_| error());
+            match fields.as_slice() {
+                [] => (),
+                [comment, ..] if comment.starts_with('#') => (),
+                ["file", _, ..] => {
+                    // File names may contain spaces
+                    let name = line.trim_start()["file".len()..].trim();
+                    info.files.push(name.to_string());
+                }
+                ["word", a, file, line, column, kind] => {
+                    info.locations.push(SourceLocation {
+                        address: address(a)?,
+                        file: number(file)?,
+                        line: number(line)?,
+                        column: number(column)?,
+                        kind: match *kind {
+                            "code" => WordKind::Code,
+                            "data" => WordKind::Data,
+                            _ => return Err(error()),
+                        },
+                    });
+                }
+                ["label", name, start, end] => info.labels.push(LabelRange {
+                    name: name.to_string(),
+                    start: address(start)?,
+                    end: address(end)?,
+                }),
+                _ => return Err(error()),
+            }
+        }
+        Ok(info)
+    }
+}
+
+#[wasm_bindgen]
+impl DebugInfo {
+    #[wasm_bindgen(js_name = parse)]
+    pub fn parse_js(content: &str) -> Result<DebugInfo, String> {
+        DebugInfo::parse(content)
+    }
+
+    // Content of the .dbg file
+    pub fn serialize(&self) -> String {
+        let mut out = String::from("# tdal3 debug info\n");
+        for file in &self.files {
+            out.push_str(&format!("file {}\n", file));
+        }
+        for l in &self.locations {
+            let kind = match l.kind {
+                WordKind::Code => "code",
+                WordKind::Data => "data",
+            };
+            out.push_str(&format!(
+                "word x{:04X} {} {} {} {}\n",
+                l.address, l.file, l.line, l.column, kind
+            ));
+        }
+        for l in &self.labels {
+            out.push_str(&format!(
+                "label {} x{:04X} x{:04X}\n",
+                l.name, l.start, l.end
+            ));
+        }
+        out
+    }
+
+    // Source line of the word at address, 0 if unknown.
+    pub fn line_of(&self, address: u16) -> usize {
+        self.location(address).map_or(0, |l| l.line)
+    }
+}
+
+#[wasm_bindgen]
+impl Core {
+    pub fn load_debug_info(&mut self, info: &DebugInfo) {
+        for label in &info.labels {
+            self.symbols
+                .entry(label.start)
+                .or_insert_with(|| label.name.clone());
+        }
+        self.debug_info = Some(info.clone());
+    }
+    // Line of the main source file being executed, 0 if unknown or in an included file.
+    pub fn current_line(&self) -> usize {
+        self.debug_info
+            .as_ref()
+            .and_then(|info| info.location(self.pc))
+            .filter(|l| l.file == 0)
+            .map_or(0, |l| l.line)
+    }
+    // Whether one of the words of the line has a breakpoint.
+    pub fn has_line_breakpoint(&self, line: usize) -> bool {
+        self.debug_info.as_ref().is_some_and(|info| {
+            info.locations
+                .iter()
+                .any(|l| l.file == 0 && l.line == line && self.has_breakpoint(l.address))
+        })
+    }
+    // Toggles a breakpoint on the first instruction of the line (or of the next line with
+    // an instruction). Returns the address of the breakpoint, if one is now set.
+    pub fn toggle_line_breakpoint(&mut self, line: usize) -> Option<u16> {
+        let address = self.debug_info.as_ref()?.address_of_line(0, line)?;
+        if self.toggle_breakpoint(address) {
+            Some(address)
+        } else {
+            None
+        }
+    }
+}
+
+impl Core {
+    pub fn debug_info(&self) -> Option<&DebugInfo> {
+        self.debug_info.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble::assemble_with;
+    use crate::source::MemoryFiles;
+
+    fn info() -> DebugInfo {
+        DebugInfo::new(
+            vec!["main.asm".into()],
+            vec![
+                SourceLocation {
+                    address: 0x3000,
+                    file: 0,
+                    line: 2,
+                    column: 1,
+                    kind: WordKind::Code,
+                },
+                SourceLocation {
+                    address: 0x3001,
+                    file: 0,
+                    line: 4,
+                    column: 5,
+                    kind: WordKind::Code,
+                },
+                SourceLocation {
+                    address: 0x3002,
+                    file: 0,
+                    line: 5,
+                    column: 1,
+                    kind: WordKind::Data,
+                },
+            ],
+            vec![LabelRange {
+                name: "LOOP".into(),
+                start: 0x3000,
+                end: 0x3002,
+            }],
+        )
+    }
+
+    #[test]
+    fn test_serialize() {
+        let info = info();
+        assert_eq!(DebugInfo::parse(&info.serialize()), Ok(info));
+        assert!(DebugInfo::parse("word 3000").is_err());
+    }
+
+    #[test]
+    fn test_lookup() {
+        let info = info();
+        assert_eq!(info.line_of(0x3001), 4);
+        assert_eq!(info.address_of_line(0, 3), Some(0x3001));
+        assert_eq!(info.address_of_line(0, 5), None);
+        assert_eq!(info.label_at(0x3001).unwrap().name, "LOOP");
+        assert_eq!(info.label_at(0x3002), None);
+    }
+
+    #[test]
+    fn test_line_breakpoint() {
+        let mut c = Core::new();
+        c.load_debug_info(&info());
+        assert_eq!(c.toggle_line_breakpoint(1), Some(0x3000));
+        assert!(c.has_breakpoint(0x3000));
+        assert!(c.has_line_breakpoint(2));
+        assert!(!c.has_line_breakpoint(1));
+        assert_eq!(c.toggle_line_breakpoint(1), None);
+        assert_eq!(c.symbol_at(0x3000), Some("LOOP".into()));
+        c.pc = 0x3001;
+        assert_eq!(c.current_line(), 4);
+    }
+
+    #[test]
+    fn test_included_line() {
+        let mut files = MemoryFiles::new();
+        files.add(
+            "main.asm",
+            ".ORIG x3000\nJSR PRINT\nHALT\n.INCLUDE \"io.asm\"",
+        );
+        files.add("io.asm", "PRINT PUTS\nRET");
+        let assembly = assemble_with("main.asm", &files).unwrap();
+        let mut c = Core::new();
+        c.load_debug_info(&assembly.debug_info("main.asm"));
+        c.pc = 0x3001;
+        assert_eq!(c.current_line(), 3);
+        // PUTS is on line 1 of io.asm, not of main.asm
+        c.pc = 0x3002;
+        assert_eq!(c.current_line(), 0);
+    }
+}
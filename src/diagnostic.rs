@@ -0,0 +1,129 @@
+use std::fmt;
+
+use crate::source::SourceFile;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A problem found in a source file. Lines and columns start at 1, file is the index of the
+/// file among the files of the program (0 being the main file).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub file: usize,
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+    // Id of the lint rule that reported it
+    pub rule: Option<&'static str>,
+}
+
+impl Diagnostic {
+    pub fn error(line: usize, column: usize, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Error,
+            file: 0,
+            line,
+            column,
+            message: message.into(),
+            rule: None,
+        }
+    }
+    pub fn warning(line: usize, column: usize, message: impl Into<String>) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            file: 0,
+            line,
+            column,
+            message: message.into(),
+            rule: None,
+        }
+    }
+
+    pub fn in_file(mut self, file: usize) -> Self {
+        self.file = file;
+        self
+    }
+
+    pub fn with_rule(mut self, rule: &'static str) -> Self {
+        self.rule = Some(rule);
+        self
+    }
+
+    // Message followed by the rule, if any.
+    fn text(&self) -> String {
+        match self.rule {
+            Some(rule) => format!("{} [{}]", self.message, rule),
+            None => self.message.clone(),
+        }
+    }
+
+    // Shows the diagnostic along with the offending source line.
+    pub fn render(&self, source: &[String]) -> String {
+        self.render_named("", source)
+    }
+
+    // Same as render, with the file given among the files of the program. The main file
+    // is not named.
+    pub fn render_in(&self, files: &[SourceFile]) -> String {
+        match files.get(self.file) {
+            Some(file) if self.file > 0 => self.render_named(&file.name, &file.lines),
+            Some(file) => self.render(&file.lines),
+            None => self.render(&[]),
+        }
+    }
+
+    fn render_named(&self, name: &str, source: &[String]) -> String {
+        let code = source
+            .get(self.line.wrapping_sub(1))
+            .map(String::as_str)
+            .unwrap_or("");
+        let file = match name {
+            "" => String::new(),
+            name => format!(" of {}", name),
+        };
+        format!(
+            "{} at line {}, column {}{}: {}\n --> {}",
+            self.severity,
+            self.line,
+            self.column,
+            file,
+            code,
+            self.text()
+        )
+    }
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Error => f.write_str("Error"),
+            Severity::Warning => f.write_str("Warning"),
+        }
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} at line {}, column {}: {}",
+            self.severity,
+            self.line,
+            self.column,
+            self.text()
+        )
+    }
+}
+
+// Renders all the diagnostics, one after the other.
+pub fn render_all(diagnostics: &[Diagnostic], files: &[SourceFile]) -> String {
+    diagnostics
+        .iter()
+        .map(|d| d.render_in(files))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
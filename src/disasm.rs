@@ -0,0 +1,116 @@
+use wasm_bindgen::prelude::*;
+
+use crate::opcode::OpCode;
+
+fn trap_alias(trapvect: u16) -> Option<&'static str> {
+    match trapvect {
+        0x20 => Some("GETC"),
+        0x21 => Some("OUT"),
+        0x22 => Some("PUTS"),
+        0x23 => Some("IN"),
+        0x24 => Some("PUTSP"),
+        0x25 => Some("HALT"),
+        _ => None,
+    }
+}
+
+// Target of a PC-relative instruction located at address.
+pub(crate) fn pc_relative(address: u16, inst: u16, size: u16) -> u16 {
+    let offset = extend_to_u16!(get_bits!(inst, 0, size), size);
+    address.wrapping_add(1).wrapping_add(offset)
+}
+
+fn imm(inst: u16, size: u16) -> i16 {
+    extend_to_u16!(get_bits!(inst, 0, size), size) as i16
+}
+
+/// Turns the instruction word stored at address back into assembly.
+/// PC-relative operands are shown as absolute addresses.
+#[wasm_bindgen]
+pub fn disassemble(inst: u16, address: u16) -> String {
+    let dr = get_bits!(inst, 9, 3);
+    let sr1 = get_bits!(inst, 6, 3);
+    match OpCode::from(inst) {
+        OpCode::ADD | OpCode::AND => {
+            let name = if OpCode::from(inst) == OpCode::ADD {
+                "ADD"
+            } else {
+                "AND"
+            };
+            if get_bits!(inst, 5, 1) == 1 {
+                format!("{} R{}, R{}, #{}", name, dr, sr1, imm(inst, 5))
+            } else {
+                format!("{} R{}, R{}, R{}", name, dr, sr1, get_bits!(inst, 0, 3))
+            }
+        }
+        OpCode::BR => {
+            let mut flags = String::new();
+            for (bit, flag) in [(11, 'n'), (10, 'z'), (9, 'p')] {
+                if get_bits!(inst, bit, 1) == 1 {
+                    flags.push(flag);
+                }
+            }
+            if flags.is_empty() {
+                // A branch on no condition never branches
+                return "NOP".into();
+            }
+            format!("BR{} x{:04X}", flags, pc_relative(address, inst, 9))
+        }
+        OpCode::JMP if sr1 == 7 => "RET".into(),
+        OpCode::JMP => format!("JMP R{}", sr1),
+        OpCode::JSR if get_bits!(inst, 11, 1) == 1 => {
+            format!("JSR x{:04X}", pc_relative(address, inst, 11))
+        }
+        OpCode::JSR => format!("JSRR R{}", sr1),
+        OpCode::LD => format!("LD R{}, x{:04X}", dr, pc_relative(address, inst, 9)),
+        OpCode::LDI => format!("LDI R{}, x{:04X}", dr, pc_relative(address, inst, 9)),
+        OpCode::LEA => format!("LEA R{}, x{:04X}", dr, pc_relative(address, inst, 9)),
+        OpCode::ST => format!("ST R{}, x{:04X}", dr, pc_relative(address, inst, 9)),
+        OpCode::STI => format!("STI R{}, x{:04X}", dr, pc_relative(address, inst, 9)),
+        OpCode::LDR => format!("LDR R{}, R{}, #{}", dr, sr1, imm(inst, 6)),
+        OpCode::STR => format!("STR R{}, R{}, #{}", dr, sr1, imm(inst, 6)),
+        OpCode::NOT => format!("NOT R{}, R{}", dr, sr1),
+        OpCode::RTI => "RTI".into(),
+        OpCode::TRAP => {
+            let trapvect = get_bits!(inst, 0, 8);
+            match trap_alias(trapvect) {
+                Some(alias) => alias.into(),
+                None => format!("TRAP x{:02X}", trapvect),
+            }
+        }
+        OpCode::UNKNOWN => format!(".FILL x{:04X}", inst),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disassemble() {
+        assert_eq!(
+            disassemble(0b0001_010_111_1_00111, 0x3000),
+            "ADD R2, R7, #7"
+        );
+        assert_eq!(
+            disassemble(0b0001_010_011_1_11011, 0x3000),
+            "ADD R2, R3, #-5"
+        );
+        assert_eq!(
+            disassemble(0b0101_000_111_0_00_001, 0x3000),
+            "AND R0, R7, R1"
+        );
+        assert_eq!(disassemble(0b0000_1_0_1_111111110, 0x3000), "BRnp x2FFF");
+        assert_eq!(disassemble(0b0000_0_0_0_000000001, 0x3000), "NOP");
+        assert_eq!(disassemble(0b1100_000_111_000000, 0x3000), "RET");
+        assert_eq!(disassemble(0b0100_1_00000000100, 0x3000), "JSR x3005");
+        assert_eq!(disassemble(0b0100_0_00_011_000000, 0x3000), "JSRR R3");
+        assert_eq!(
+            disassemble(0b0110_001_110_111111, 0x3000),
+            "LDR R1, R6, #-1"
+        );
+        assert_eq!(disassemble(0xF025, 0x3000), "HALT");
+        assert_eq!(disassemble(0xF030, 0x3000), "TRAP x30");
+        assert_eq!(disassemble(0xD123, 0x3000), ".FILL xD123");
+    }
+}
@@ -0,0 +1,122 @@
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+// Value wrapped to 16 bits, keeping the small negative numbers rather than their unsigned
+// form so that they still fit in the narrow fields: xFFFF+2 is 1 but -1 stays -1.
+pub fn wrap16(value: i32) -> i32 {
+    if (i16::MIN as i32..=u16::MAX as i32).contains(&value) {
+        value
+    } else {
+        value as u16 as i32
+    }
+}
+
+/// Constant expression, as in `LABEL+2`, `#-(N*2)` or `END-START`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(i32),
+    Symbol(String),
+    Neg(Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    // Computes the value on plain integers, lookup giving the value of symbols. Arithmetic
+    // wraps, which keeps the low 16 bits right whatever the intermediate values. Fitting the
+    // result in the field it is used in (and wrapping 16 bit values) is up to the caller.
+    pub fn eval(&self, lookup: &dyn Fn(&str) -> Option<i32>) -> Result<i32, String> {
+        match self {
+            Expr::Number(value) => Ok(*value),
+            Expr::Symbol(name) => lookup(name).ok_or_else(|| format!("Undefined symbol {}.", name)),
+            Expr::Neg(e) => Ok(e.eval(lookup)?.wrapping_neg()),
+            Expr::Binary(op, left, right) => {
+                let (left, right) = (left.eval(lookup)?, right.eval(lookup)?);
+                Ok(match op {
+                    BinaryOp::Add => left.wrapping_add(right),
+                    BinaryOp::Sub => left.wrapping_sub(right),
+                    BinaryOp::Mul => left.wrapping_mul(right),
+                    BinaryOp::Div if right == 0 => return Err("Division by zero.".into()),
+                    BinaryOp::Div => left.wrapping_div(right),
+                })
+            }
+        }
+    }
+
+    // Symbols used by the expression.
+    pub fn symbols(&self) -> Vec<&str> {
+        match self {
+            Expr::Number(_) => Vec::new(),
+            Expr::Symbol(name) => vec![name],
+            Expr::Neg(e) => e.symbols(),
+            Expr::Binary(_, left, right) => {
+                let mut symbols = left.symbols();
+                symbols.extend(right.symbols());
+                symbols
+            }
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expr::Number(value) => write!(f, "{}", value),
+            Expr::Symbol(name) => f.write_str(name),
+            Expr::Neg(e) => write!(f, "-({})", e),
+            Expr::Binary(op, left, right) => {
+                let op = match op {
+                    BinaryOp::Add => '+',
+                    BinaryOp::Sub => '-',
+                    BinaryOp::Mul => '*',
+                    BinaryOp::Div => '/',
+                };
+                write!(f, "({} {} {})", left, op, right)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_eval() {
+        // -(N*2) + END - START
+        let e = Expr::Binary(
+            BinaryOp::Sub,
+            Box::new(Expr::Binary(
+                BinaryOp::Add,
+                Box::new(Expr::Neg(Box::new(Expr::Binary(
+                    BinaryOp::Mul,
+                    Box::new(Expr::Symbol("N".into())),
+                    Box::new(Expr::Number(2)),
+                )))),
+                Box::new(Expr::Symbol("END".into())),
+            )),
+            Box::new(Expr::Symbol("START".into())),
+        );
+        let lookup = |name: &str| match name {
+            "N" => Some(3),
+            "START" => Some(0x3000),
+            "END" => Some(0x3010),
+            _ => None,
+        };
+        assert_eq!(e.eval(&lookup), Ok(10));
+        assert_eq!(e.symbols(), ["N", "END", "START"]);
+        assert_eq!(e.eval(&|_| None), Err("Undefined symbol N.".to_string()));
+        let e = Expr::Binary(
+            BinaryOp::Div,
+            Box::new(Expr::Number(1)),
+            Box::new(Expr::Number(0)),
+        );
+        assert!(e.eval(&lookup).is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use wasm_bindgen::prelude::*;
+
+use crate::cst::{Cst, CstLine, CstToken};
+use crate::lexer::{render, Token, TokenKind};
+use crate::parser::{parse_opcode, parse_prefixed, parse_register};
+
+// Columns start at multiples of this.
+const TAB: usize = 4;
+// Longer operands do not push the comments of the other lines further right.
+const MAX_OPERANDS: usize = 24;
+
+fn round_up(width: usize) -> usize {
+    width.div_ceil(TAB) * TAB
+}
+
+// Opcodes, directives and macro names are upper cased, except for the flags of BR which
+// are lower cased and sorted.
+fn operation(token: &CstToken) -> String {
+    match &token.kind {
+        TokenKind::Word(word) => {
+            let upper = word.to_ascii_uppercase();
+            match upper.strip_prefix("BR") {
+                Some(flags) if parse_opcode(word).is_some() => {
+                    let flags: String = "nzp"
+                        .chars()
+                        .filter(|c| flags.contains(c.to_ascii_uppercase()))
+                        .collect();
+                    format!("BR{}", flags)
+                }
+                _ => upper,
+            }
+        }
+        kind => kind.to_string(),
+    }
+}
+
+// Registers are upper cased, and x/b literals get a lower case prefix and upper case digits.
+fn word(word: &str) -> String {
+    if parse_register(word).is_some() {
+        return word.to_ascii_uppercase();
+    }
+    match parse_prefixed(word) {
+        Some(Ok(_)) => {
+            let (prefix, digits) = word.split_at(1);
+            prefix.to_ascii_lowercase() + &digits.to_ascii_uppercase()
+        }
+        _ => word.to_string(),
+    }
+}
+
+// Operands with normalized spelling and spacing, decimals being written #12.
+fn operands(tokens: &[CstToken]) -> String {
+    let tokens: Vec<Token> = tokens
+        .iter()
+        .map(|token| Token {
+            kind: match &token.kind {
+                TokenKind::Word(w) => TokenKind::Word(word(w)),
+                kind => kind.clone(),
+            },
+            column: token.column,
+        })
+        .collect();
+    render(&tokens)
+}
+
+// The columns of a line of code. Empty fields are omitted.
+struct Fields {
+    label: String,
+    operation: String,
+    operands: String,
+    comment: String,
+}
+
+impl Fields {
+    fn of(line: &CstLine) -> Fields {
+        let text =
+            |index: Option<usize>| index.map_or(String::new(), |i| line.tokens[i].text.clone());
+        let mut label = text(line.label);
+        if let Some(colon) = line.colon() {
+            label.push_str(&colon.text);
+        }
+        Fields {
+            label,
+            operation: line
+                .operation
+                .map_or(String::new(), |i| operation(&line.tokens[i])),
+            operands: operands(line.operands()),
+            comment: text(line.comment),
+        }
+    }
+
+    fn is_code(&self) -> bool {
+        !self.label.is_empty() || !self.operation.is_empty()
+    }
+}
+
+// Appends field at column, or one space after what is already there if it is too wide.
+fn push_at(out: &mut String, column: usize, field: &str) {
+    let width = out.chars().count();
+    let gap = match column.checked_sub(width) {
+        Some(gap) if gap > 0 || width == 0 => gap,
+        _ => 1,
+    };
+    out.push_str(&" ".repeat(gap));
+    out.push_str(field);
+}
+
+/// Aligns labels, operations, operands and comments in columns, and normalizes the case of
+/// opcodes, directives and registers and the spelling of literals. Comment lines starting
+/// the line stay there, the others are indented like instructions. Lines that cannot be
+/// split in tokens are only stripped of their trailing whitespace.
+#[wasm_bindgen]
+pub fn format_source(source: &str) -> String {
+    let cst = Cst::parse(source);
+    let fields: Vec<Option<Fields>> = cst
+        .lines
+        .iter()
+        .map(|line| line.error.is_none().then(|| Fields::of(line)))
+        .collect();
+    let code = || fields.iter().flatten().filter(|f| f.is_code());
+    let widest = |width: fn(&Fields) -> usize| code().map(width).max().unwrap_or(0);
+    let operation_column = round_up(widest(|f| f.label.chars().count()) + 1).max(TAB);
+    let operands_column = operation_column + round_up(widest(|f| f.operation.len()) + 1);
+    let comment_column =
+        operands_column + round_up(widest(|f| f.operands.chars().count()).min(MAX_OPERANDS) + 1);
+
+    let mut out = String::new();
+    for (line, fields) in cst.lines.iter().zip(&fields) {
+        let formatted = match fields {
+            None => line.to_string().trim_end().to_string(),
+            Some(f) if !f.is_code() => match line.tokens.first() {
+                Some(first) if first.column > 1 => " ".repeat(operation_column) + &f.comment,
+                _ => f.comment.clone(),
+            },
+            Some(f) => {
+                let mut text = f.label.clone();
+                for (column, field) in [
+                    (operation_column, &f.operation),
+                    (operands_column, &f.operands),
+                    (comment_column, &f.comment),
+                ] {
+                    if !field.is_empty() {
+                        push_at(&mut text, column, field);
+                    }
+                }
+                text
+            }
+        };
+        out.push_str(&formatted);
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format() {
+        let source = [
+            "; Counts down",
+            "\t.orig\tX3000",
+            "loop:  add r1,R1 ,#-1\t; decrement  ",
+            "  brZN loop",
+            "   ; indented",
+            "",
+            "    ld r0,=xcafe ; load",
+            "LONG_LABEL .fill 12",
+            "  .stringz \"Hi\"",
+            "@@ kept  ",
+            ".end",
+        ]
+        .join("\n");
+        let expected = [
+            "; Counts down",
+            "            .ORIG       x3000",
+            "loop:       ADD         R1, R1, #-1 ; decrement",
+            "            BRnz        loop",
+            "            ; indented",
+            "",
+            "            LD          R0, =xCAFE  ; load",
+            "LONG_LABEL  .FILL       #12",
+            "            .STRINGZ    \"Hi\"",
+            "@@ kept",
+            "            .END",
+            "",
+        ]
+        .join("\n");
+        assert_eq!(format_source(&source), expected);
+        assert_eq!(format_source(&expected), expected);
+    }
+}
@@ -0,0 +1,152 @@
+use wasm_bindgen::prelude::*;
+
+use crate::memory::{INTERRUPT_ENABLE, KBSR, READY};
+use crate::{Core, VECTOR_TABLE};
+
+// Keyboard interrupts, raised while a key is waiting and KBSR enables them
+pub const KEYBOARD_VECTOR: u8 = 0x80;
+pub const KEYBOARD_PRIORITY: u8 = 4;
+// Priority bits of the PSR
+const PRIORITY_SHIFT: u16 = 8;
+const PRIORITY_MASK: u16 = 0b111 << PRIORITY_SHIFT;
+
+/// An interrupt raised by a device, served once its priority exceeds the one of the PSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterruptRequest {
+    pub priority: u8,
+    pub vector: u8,
+}
+
+impl Core {
+    // Requests of the devices whose status calls for one. They last as long as the status,
+    // unlike the requests raised by the host which are dropped once served.
+    fn device_requests(&mut self) -> Vec<InterruptRequest> {
+        let mut requests = Vec::new();
+        if self.memory[KBSR as usize] & INTERRUPT_ENABLE != 0 {
+            self.poll_keyboard();
+            if self.memory[KBSR as usize] & READY != 0 {
+                requests.push(InterruptRequest {
+                    priority: KEYBOARD_PRIORITY,
+                    vector: KEYBOARD_VECTOR,
+                });
+            }
+        }
+        requests.extend(self.timer_request());
+        requests
+    }
+
+    // Enters the handler of the most urgent request whose priority exceeds the current one,
+    // pushing the PSR and the PC on the supervisor stack. Returns whether one was served.
+    pub(crate) fn serve_interrupt(&mut self) -> bool {
+        let priority = self.priority();
+        let request = self
+            .device_requests()
+            .into_iter()
+            .chain(self.pending_interrupts.iter().copied())
+            .filter(|request| request.priority > priority)
+            // The first one raised wins among equals
+            .rev()
+            .max_by_key(|request| request.priority);
+        let Some(request) = request else {
+            return false;
+        };
+        self.pending_interrupts
+            .retain(|pending| *pending != request);
+        self.enter_supervisor(self.pc);
+        self.psr = (self.psr & !PRIORITY_MASK) | (request.priority as u16) << PRIORITY_SHIFT;
+        let vector = VECTOR_TABLE + request.vector as u16;
+        self.charge(vector);
+        self.pc = self.memory[vector as usize];
+        true
+    }
+
+    pub fn pending_interrupts(&self) -> &[InterruptRequest] {
+        &self.pending_interrupts
+    }
+}
+
+#[wasm_bindgen]
+impl Core {
+    // Priority level of the running program, from 0 to 7
+    pub fn priority(&self) -> u8 {
+        ((self.psr & PRIORITY_MASK) >> PRIORITY_SHIFT) as u8
+    }
+
+    // Raises an interrupt, served between two instructions once its priority exceeds the
+    // current one. Levels above 7 are taken as 7. Raising a pending vector again does
+    // nothing.
+    pub fn request_interrupt(&mut self, priority: u8, vector: u8) {
+        if self.pending_interrupts.iter().all(|r| r.vector != vector) {
+            self.pending_interrupts.push(InterruptRequest {
+                priority: priority.min(7),
+                vector,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CoreConfig;
+    use crate::console::BufferConsole;
+    use crate::StopReason;
+
+    #[test]
+    fn test_arbitration() {
+        let mut c = Core::with_config(&CoreConfig::new().pc(0x3000).priority(2));
+        c.load_obj(&[0x0180, 0x4000, 0x4100, 0x4200]).unwrap();
+        c.request_interrupt(1, 0x80);
+        c.request_interrupt(3, 0x81);
+        c.request_interrupt(3, 0x82);
+        c.request_interrupt(5, 0x81);
+        // Below the current priority
+        c.request_interrupt(2, 0x83);
+        c.step();
+        assert_eq!((c.pc(), c.priority()), (0x4100, 3));
+        // The PSR of the interrupted program and its PC are on the supervisor stack
+        assert_eq!(c.register(6), 0x2FFE);
+        assert_eq!(c.memory_at(0x2FFE), 0x3000);
+        assert_eq!(c.memory_at(0x2FFF), 0x0200);
+        // Equal priorities wait
+        c.step();
+        assert_eq!(c.pc(), 0x4101);
+        assert_eq!(c.pending_interrupts().len(), 3);
+    }
+
+    #[test]
+    fn test_keyboard_interrupt() {
+        // The handler echoes the key, the program enables interrupts then spins in user mode
+        let handler = [
+            0x1000, //
+            0x3204, // ST R1, SAVE
+            0xA204, // LDI R1, KBDR
+            0xB204, // STI R1, DDR
+            0x2201, // LD R1, SAVE
+            0x8000, // RTI
+            0x0000, // SAVE
+            0xFE02, // KBDR
+            0xFE06, // DDR
+        ];
+        let program = [
+            0x3000, //
+            0x2202, // LD R1, IE
+            0xB202, // STI R1, KBSR
+            0x0FFF, // BRnzp -1
+            0x4000, // IE
+            0xFE00, // KBSR
+        ];
+        let console = BufferConsole::new();
+        let mut c = Core::with_config(&CoreConfig::new().pc(0x3000));
+        c.set_console(Box::new(console.clone()));
+        c.load_obj(&[0x0180, 0x1000]).unwrap();
+        // The handler replaces the OS routines, unused here
+        c.load_obj(&handler).unwrap();
+        c.load_obj(&program).unwrap();
+        assert_eq!(c.run(100), StopReason::StepLimit);
+        console.push_input(b"ok");
+        assert_eq!(c.run(100), StopReason::StepLimit);
+        assert_eq!(console.output(), b"ok");
+        assert_eq!((c.pc(), c.priority(), c.register(6)), (0x3002, 0, 0x3000));
+    }
+}
@@ -0,0 +1,409 @@
+use std::fmt;
+
+use nom::{
+    branch::alt,
+    bytes::complete::{take_while, take_while1},
+    character::complete::{char, digit1, one_of},
+    combinator::{map, opt, peek, recognize, verify},
+    sequence::{pair, preceded, terminated, tuple},
+    IResult,
+};
+
+use crate::diagnostic::Diagnostic;
+use crate::parser::parse_prefixed;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    // Opcodes, registers, labels and x/b prefixed literals. The parser tells them apart.
+    Word(String),
+    // #12, #-5 or a bare 12
+    Decimal(i32),
+    // # in front of a parenthesized or negated expression or of a symbol, as in #-(N*2) or #N
+    Hash,
+    // = in front of a constant placed in a literal pool, as in LD R0, =x8000
+    Equals,
+    // Operators of constant expressions
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    LParen,
+    RParen,
+    Char(u8),
+    Str(String),
+    // Name of the directive, upper cased and without the dot
+    Directive(String),
+    Comma,
+    Colon,
+    // Text following the ';'
+    Comment(String),
+}
+
+/// A token and the column (starting at 1) of its first character.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub column: usize,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+fn word(input: &str) -> IResult<&str, &str> {
+    alt((
+        // Negative hex and binary literals, x-10 or b-101. x-START is x minus START.
+        verify(
+            recognize(tuple((
+                one_of("xXbB"),
+                char('-'),
+                take_while1(is_word_char),
+            ))),
+            |w: &str| w[2..].starts_with(|c: char| c.is_ascii_digit()),
+        ),
+        recognize(pair(
+            take_while1(|c: char| c.is_ascii_alphabetic() || c == '_'),
+            take_while(is_word_char),
+        )),
+    ))(input)
+}
+
+fn decimal(input: &str) -> IResult<&str, Result<i32, String>> {
+    map(
+        alt((
+            preceded(char('#'), recognize(pair(opt(one_of("+-")), digit1))),
+            digit1,
+        )),
+        |digits: &str| {
+            digits
+                .parse::<i32>()
+                .map_err(|_| format!("Number {} is too large.", digits))
+        },
+    )(input)
+}
+
+fn directive(input: &str) -> IResult<&str, &str> {
+    preceded(char('.'), take_while1(|c: char| c.is_ascii_alphabetic()))(input)
+}
+
+fn comment(input: &str) -> IResult<&str, &str> {
+    preceded(char(';'), take_while(|_| true))(input)
+}
+
+// Escape sequences allowed in character and string literals.
+fn escape(c: char) -> Option<char> {
+    match c {
+        'n' => Some('\n'),
+        't' => Some('\t'),
+        'r' => Some('\r'),
+        'e' => Some('\x1B'),
+        '0' => Some('\0'),
+        '\\' | '\'' | '"' => Some(c),
+        _ => None,
+    }
+}
+
+// Reads a literal delimited by quote. Returns the remaining input and the unescaped content.
+fn quoted(input: &str, quote: char) -> IResult<&str, Result<String, String>> {
+    let (mut rest, _) = char(quote)(input)?;
+    let mut content = String::new();
+    loop {
+        let mut chars = rest.chars();
+        match chars.next() {
+            None => {
+                return Ok((rest, Err(format!("Missing closing {}.", quote))));
+            }
+            Some(c) if c == quote => return Ok((chars.as_str(), Ok(content))),
+            Some('\\') => match chars.next().and_then(escape) {
+                Some(c) => content.push(c),
+                None => return Ok((rest, Err("Invalid escape sequence.".into()))),
+            },
+            Some(c) if !c.is_ascii() => {
+                return Ok((rest, Err(format!("'{}' is not an ASCII character.", c))));
+            }
+            Some(c) => content.push(c),
+        }
+        rest = chars.as_str();
+    }
+}
+
+// Inverse of escape, to print literals back.
+fn escaped(c: char) -> String {
+    match c {
+        '\n' => "\\n".into(),
+        '\t' => "\\t".into(),
+        '\r' => "\\r".into(),
+        '\x1B' => "\\e".into(),
+        '\0' => "\\0".into(),
+        '\\' | '\'' | '"' => format!("\\{}", c),
+        _ => c.to_string(),
+    }
+}
+
+fn character(input: &str) -> IResult<&str, Result<u8, String>> {
+    map(
+        |i| quoted(i, '\''),
+        |content| {
+            content.and_then(|c| match c.as_bytes() {
+                [byte] => Ok(*byte),
+                _ => Err("A character literal holds exactly one character.".into()),
+            })
+        },
+    )(input)
+}
+
+fn token(input: &str) -> IResult<&str, Result<TokenKind, String>> {
+    alt((
+        map(char(','), |_| Ok(TokenKind::Comma)),
+        map(char(':'), |_| Ok(TokenKind::Colon)),
+        map(comment, |c| Ok(TokenKind::Comment(c.to_string()))),
+        map(directive, |d| {
+            Ok(TokenKind::Directive(d.to_ascii_uppercase()))
+        }),
+        map(character, |c| c.map(TokenKind::Char)),
+        map(|i| quoted(i, '"'), |s| s.map(TokenKind::Str)),
+        map(decimal, |d| d.map(TokenKind::Decimal)),
+        map(
+            terminated(
+                char('#'),
+                // #x10 stays an invalid decimal rather than the symbol x10
+                peek(alt((
+                    recognize(one_of("(+-")),
+                    verify(word, |w: &str| parse_prefixed(w).is_none()),
+                ))),
+            ),
+            |_| Ok(TokenKind::Hash),
+        ),
+        map(char('='), |_| Ok(TokenKind::Equals)),
+        map(one_of("+-*/()"), |c| {
+            Ok(match c {
+                '+' => TokenKind::Plus,
+                '-' => TokenKind::Minus,
+                '*' => TokenKind::Star,
+                '/' => TokenKind::Slash,
+                '(' => TokenKind::LParen,
+                _ => TokenKind::RParen,
+            })
+        }),
+        map(word, |w| Ok(TokenKind::Word(w.to_string()))),
+    ))(input)
+}
+
+fn column(line: &str, rest: &str) -> usize {
+    line[..line.len() - rest.len()].chars().count() + 1
+}
+
+fn is_separator(c: char) -> bool {
+    c.is_whitespace() || matches!(c, ',' | ':' | ';')
+}
+
+// Everything up to the next separator.
+fn until_separator(input: &str) -> &str {
+    &input[..input.find(is_separator).unwrap_or(input.len())]
+}
+
+/// Splits one line of source into tokens. Spaces and tabs separate tokens and
+/// are otherwise ignored.
+pub fn tokenize(line: &str, line_number: usize) -> Result<Vec<Token>, Diagnostic> {
+    let mut tokens = Vec::new();
+    let mut rest = line.trim_start();
+    while !rest.is_empty() {
+        let col = column(line, rest);
+        let error = |message: String| Diagnostic::error(line_number, col, message);
+        let invalid = || {
+            error(format!(
+                "{} is neither a valid number nor a valid label.",
+                until_separator(rest)
+            ))
+        };
+        let (remaining, kind) = match token(rest) {
+            Ok((remaining, Ok(kind))) => (remaining, kind),
+            Ok((_, Err(message))) => return Err(error(message)),
+            Err(_) if rest.starts_with('#') => return Err(invalid()),
+            Err(_) => {
+                let c = rest.chars().next().unwrap_or_default();
+                return Err(error(format!("Unexpected character '{}'.", c)));
+            }
+        };
+        // Literals and words must be followed by a separator: "12ab" is not "12" then "ab".
+        // Operators separate too, except after # literals: "#1-2" is not "#1" then "-2".
+        let separated = remaining.chars().next().is_none_or(|c| {
+            is_separator(c) || c == ')' || ("+-*/(".contains(c) && !rest.starts_with('#'))
+        });
+        let needs_separator = matches!(
+            kind,
+            TokenKind::Word(_)
+                | TokenKind::Decimal(_)
+                | TokenKind::Char(_)
+                | TokenKind::Str(_)
+                | TokenKind::Directive(_)
+        );
+        if needs_separator && !separated {
+            return Err(invalid());
+        }
+        tokens.push(Token { kind, column: col });
+        rest = remaining.trim_start();
+    }
+    Ok(tokens)
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TokenKind::Word(word) => f.write_str(word),
+            TokenKind::Decimal(value) => write!(f, "#{}", value),
+            TokenKind::Char(c) => write!(f, "'{}'", escaped(*c as char)),
+            TokenKind::Str(s) => write!(f, "\"{}\"", s.chars().map(escaped).collect::<String>()),
+            TokenKind::Directive(name) => write!(f, ".{}", name),
+            TokenKind::Hash => f.write_str("#"),
+            TokenKind::Equals => f.write_str("="),
+            TokenKind::Plus => f.write_str("+"),
+            TokenKind::Minus => f.write_str("-"),
+            TokenKind::Star => f.write_str("*"),
+            TokenKind::Slash => f.write_str("/"),
+            TokenKind::LParen => f.write_str("("),
+            TokenKind::RParen => f.write_str(")"),
+            TokenKind::Comma => f.write_str(","),
+            TokenKind::Colon => f.write_str(":"),
+            TokenKind::Comment(comment) => write!(f, ";{}", comment),
+        }
+    }
+}
+
+fn is_operator(kind: &TokenKind) -> bool {
+    matches!(
+        kind,
+        TokenKind::Plus | TokenKind::Minus | TokenKind::Star | TokenKind::Slash
+    )
+}
+
+// Prints tokens back as a line of source, with a single space between tokens except
+// around parentheses and after # and unary signs.
+pub fn render(tokens: &[Token]) -> String {
+    let mut out = String::new();
+    for (i, token) in tokens.iter().enumerate() {
+        let previous = i.checked_sub(1).map(|i| &tokens[i].kind);
+        let operand_before = |i: usize| {
+            i.checked_sub(1).is_some_and(|i| {
+                matches!(
+                    tokens[i].kind,
+                    TokenKind::Word(_)
+                        | TokenKind::Decimal(_)
+                        | TokenKind::Char(_)
+                        | TokenKind::RParen
+                )
+            })
+        };
+        let attached = match (previous, &token.kind) {
+            (None, _) => true,
+            (_, TokenKind::Comma | TokenKind::Colon | TokenKind::RParen) => true,
+            (Some(TokenKind::Hash | TokenKind::Equals | TokenKind::LParen), _) => true,
+            // Unary sign
+            (Some(TokenKind::Plus | TokenKind::Minus), _) => !operand_before(i - 1),
+            _ => false,
+        };
+        if !attached {
+            out.push(' ');
+        }
+        match (previous, &token.kind) {
+            // No # inside expressions
+            (Some(p), TokenKind::Decimal(value)) if is_operator(p) || *p == TokenKind::LParen => {
+                out.push_str(&value.to_string())
+            }
+            (_, kind) => out.push_str(&kind.to_string()),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(line: &str) -> Vec<TokenKind> {
+        tokenize(line, 1)
+            .unwrap()
+            .into_iter()
+            .map(|t| t.kind)
+            .collect()
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            kinds("loop:\tadd r1, R1,#-1 ; decrement"),
+            [
+                TokenKind::Word("loop".into()),
+                TokenKind::Colon,
+                TokenKind::Word("add".into()),
+                TokenKind::Word("r1".into()),
+                TokenKind::Comma,
+                TokenKind::Word("R1".into()),
+                TokenKind::Comma,
+                TokenKind::Decimal(-1),
+                TokenKind::Comment(" decrement".into()),
+            ]
+        );
+        assert_eq!(
+            kinds(".orig X3000"),
+            [
+                TokenKind::Directive("ORIG".into()),
+                TokenKind::Word("X3000".into())
+            ]
+        );
+        assert_eq!(
+            kinds(".FILL '\\n' .STRINGZ \"a;\\\"b\""),
+            [
+                TokenKind::Directive("FILL".into()),
+                TokenKind::Char(b'\n'),
+                TokenKind::Directive("STRINGZ".into()),
+                TokenKind::Str("a;\"b".into()),
+            ]
+        );
+        assert_eq!(
+            kinds(".FILL #-(N*2)+x-1"),
+            [
+                TokenKind::Directive("FILL".into()),
+                TokenKind::Hash,
+                TokenKind::Minus,
+                TokenKind::LParen,
+                TokenKind::Word("N".into()),
+                TokenKind::Star,
+                TokenKind::Decimal(2),
+                TokenKind::RParen,
+                TokenKind::Plus,
+                TokenKind::Word("x-1".into()),
+            ]
+        );
+        assert_eq!(
+            kinds("END-START"),
+            [
+                TokenKind::Word("END".into()),
+                TokenKind::Minus,
+                TokenKind::Word("START".into()),
+            ]
+        );
+        let tokens = tokenize(".FILL #-(N*2)+x1, -1", 1).unwrap();
+        assert_eq!(render(&tokens), ".FILL #-(N * 2) + x1, -1");
+        assert_eq!(kinds("   ; only a comment").len(), 1);
+        let tokens = tokenize("LOOP:\tstr r1,R6, #0 .STRINGZ \"a\\n\"", 1).unwrap();
+        assert_eq!(render(&tokens), "LOOP: str r1, R6, #0 .STRINGZ \"a\\n\"");
+        assert!(kinds("\t ").is_empty());
+    }
+
+    #[test]
+    fn test_tokenize_errors() {
+        let error = tokenize("ADD R1, R1, #12ab", 3).unwrap_err();
+        assert_eq!((error.line, error.column), (3, 13));
+        let error = tokenize("\t.STRINGZ \"abc", 1).unwrap_err();
+        assert_eq!(error.column, 11);
+        assert!(tokenize(".FILL 'ab'", 1).is_err());
+        assert!(tokenize("ADD R1, R1, @", 1).is_err());
+        let error = tokenize("ADD R1, R1, #1-2", 1).unwrap_err();
+        assert_eq!(error.column, 13);
+        assert!(error.message.starts_with("#1-2 is neither"));
+        assert!(tokenize("ADD R1, R1, #x10", 1).is_err());
+        assert!(tokenize("ADD R1, R1, #1+2", 1).is_err());
+        assert!(tokenize(".FILL #99999999999", 1).is_err());
+    }
+}
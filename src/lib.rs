@@ -1,13 +1,43 @@
+use config::CoreConfig;
+use console::{BufferConsole, Console};
+use debuginfo::DebugInfo;
+use interrupt::InterruptRequest;
+use memory::{Access, PowerOn, Region, Shadow, UninitializedAccess, UninitializedCheck};
 use opcode::OpCode;
+use replay::{InputEvent, InputScript};
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use timing::{Profile, TimingModel};
 use wasm_bindgen::prelude::*;
 pub mod assemble;
+pub mod config;
+pub mod console;
+pub mod cst;
+pub mod diagnostic;
+mod expr;
+pub mod formatter;
+mod lexer;
+pub mod link;
+pub mod lint;
+mod macros;
 mod opcode;
+mod os;
 mod parser;
+pub mod source;
 #[cfg(target_arch = "wasm32")]
 use js_sys;
 
+pub use debugger::{Frame, StopReason};
+
 const MEMORY_SIZE: usize = 65536; // 2^16 memory locations
 const REGISTERS_COUNT: usize = 8;
+// PSR bit set in user mode
+const USER_MODE: u16 = 1 << 15;
+// Handler addresses of the exceptions, then of the interrupts
+const VECTOR_TABLE: u16 = 0x0100;
+// Exception vector of RTI in user mode
+const PRIVILEGE_VECTOR: u8 = 0x00;
+// Exception vector of the reserved opcode 1101
+const ILLEGAL_OPCODE_VECTOR: u8 = 0x01;
 
 macro_rules! get_bits {
     ($value:expr, $start:expr, $length:expr) => {
@@ -25,6 +55,19 @@ macro_rules! extend_to_u16 {
         }
     }};
 }
+
+pub mod cfg;
+pub mod dataflow;
+mod debugger;
+pub mod debuginfo;
+pub mod disasm;
+pub mod interrupt;
+pub mod memory;
+pub mod replay;
+pub mod timer;
+pub mod timing;
+pub mod video;
+
 #[derive(Debug)]
 #[wasm_bindgen]
 pub struct Core {
@@ -37,30 +80,126 @@ pub struct Core {
     registers: [u16; REGISTERS_COUNT],
     result: u16,
     swap_sp: u16,
+    breakpoints: BTreeSet<u16>,
+    call_stack: Vec<Frame>,
+    symbols: BTreeMap<u16, String>,
+    debug_info: Option<DebugInfo>,
+    console: Box<dyn Console>,
+    // Words written by the loader or by stores
+    initialized: Shadow,
+    uninitialized_check: UninitializedCheck,
+    uninitialized_accesses: Vec<UninitializedAccess>,
+    // Why the current step stops the run
+    stop: Option<StopReason>,
+    stop_on_illegal_opcode: bool,
+    power_on: PowerOn,
+    regions: Vec<Region>,
+    // Vector of the exception raised by the current instruction
+    exception: Option<u8>,
+    // Interrupts raised by the host and not served yet
+    pending_interrupts: Vec<InterruptRequest>,
+    // Instructions left before the next tick of the timer
+    timer_count: u16,
+    // Whether xC000-xFDFF is a framebuffer, and whether it changed since last drawn
+    video: bool,
+    video_dirty: bool,
+    timing: Option<TimingModel>,
+    // Cycles of the memory accesses of the current step
+    step_cycles: u64,
+    cycles: u64,
+    instructions: u64,
+    profile: Option<Profile>,
+    recording: Option<InputScript>,
+    // Input left to replay, instead of the console
+    replay: Option<VecDeque<InputEvent>>,
+}
+
+impl Default for Core {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 #[wasm_bindgen]
 impl Core {
     #[wasm_bindgen(constructor)]
     pub fn new() -> Core {
+        Core::with_power_on(PowerOn::Zero)
+    }
+    // Memory and registers filled with value instead of zeros.
+    pub fn filled(value: u16) -> Core {
+        Core::with_power_on(PowerOn::Fill(value))
+    }
+    // Memory and registers filled with pseudo-random words from seed.
+    pub fn randomized(seed: u64) -> Core {
+        Core::with_power_on(PowerOn::Random(seed))
+    }
+    pub fn with_config(config: &CoreConfig) -> Core {
         let mut c = Core {
             result: 0,
             memory: [0; MEMORY_SIZE],
-            pc: 0x0200,
+            pc: config.pc,
             registers: [0; REGISTERS_COUNT],
             N: false,
             Z: false,
             P: false,
-            psr: 0,
-            swap_sp: 0xFE00, //Initial value of User Stack Pointer
+            psr: config.psr(),
+            swap_sp: config.usp, // Stack pointer of the other mode
+            breakpoints: BTreeSet::new(),
+            call_stack: Vec::new(),
+            symbols: BTreeMap::new(),
+            debug_info: None,
+            console: Box::new(BufferConsole::new()),
+            initialized: Shadow::new(),
+            uninitialized_check: UninitializedCheck::Off,
+            uninitialized_accesses: Vec::new(),
+            stop: None,
+            stop_on_illegal_opcode: false,
+            power_on: PowerOn::Zero,
+            regions: Vec::new(),
+            exception: None,
+            pending_interrupts: Vec::new(),
+            timer_count: 0,
+            video: false,
+            video_dirty: false,
+            timing: None,
+            step_cycles: 0,
+            cycles: 0,
+            instructions: 0,
+            profile: None,
+            recording: None,
+            replay: None,
         };
-        c.registers[6] = 0x3000; // Supervisor Stack Pointer
+        c.power_on(config.power_on);
+        c.registers[6] = config.ssp;
+        if config.user_mode {
+            c.swap_stacks();
+        }
+        c.memory[memory::MCR as usize] = 0x8000; // Clock enabled
+        c.copy_obj(&os::TRAP_TABLE).expect("the OS fits in memory");
+        c.copy_obj(&os::OS_IMAGE).expect("the OS fits in memory");
+        c.copy_obj(&os::EXCEPTION_TABLE)
+            .expect("the OS fits in memory");
+        c.copy_obj(&os::EXCEPTION_IMAGE)
+            .expect("the OS fits in memory");
+        c.copy_obj(&os::INTERRUPT_TABLE)
+            .expect("the OS fits in memory");
+        c.copy_obj(&os::KEYBOARD_IMAGE)
+            .expect("the OS fits in memory");
         c
     }
+}
+
+impl Core {
+    pub fn with_power_on(power_on: PowerOn) -> Core {
+        Core::with_config(&CoreConfig::new().power_on(power_on))
+    }
+}
+
+#[wasm_bindgen]
+impl Core {
     fn swap_stacks(&mut self) {
-        let b = self.registers[6];
-        self.registers[6] = self.swap_sp;
-        self.swap_sp = b;
+        std::mem::swap(&mut self.registers[6], &mut self.swap_sp);
     }
     fn setcc(&mut self) {
         if self.result == 0 {
@@ -86,7 +225,7 @@ impl Core {
         // Put here for brievty
         let dr = get_bits!(inst, 9, 3);
 
-        let mut next_pc = self.pc + 1;
+        let mut next_pc = self.pc.wrapping_add(1);
         let mut address_read = 0;
         match op {
             // ADD
@@ -135,7 +274,7 @@ impl Core {
                 let p = get_bits!(inst, 9, 1) == 1;
                 let pc_offset = extend_to_u16!(get_bits!(inst, 0, 9), 9);
                 if (n & self.N) | (z & self.Z) | (p & self.P) {
-                    next_pc = self.pc.wrapping_add(pc_offset + 1);
+                    next_pc = self.pc.wrapping_add(1).wrapping_add(pc_offset);
                 }
             }
             OpCode::JMP => {
@@ -144,26 +283,26 @@ impl Core {
                 next_pc = self.registers[base_r as usize];
             }
             OpCode::JSR => {
-                self.registers[7] = self.pc + 1;
                 let is_offset = get_bits!(inst, 11, 1) == 1;
                 if is_offset {
                     let pc_offset = extend_to_u16!(get_bits!(inst, 0, 11), 11);
-                    next_pc = self.pc.wrapping_add(pc_offset + 1);
+                    next_pc = self.pc.wrapping_add(1).wrapping_add(pc_offset);
                 } else {
-                    self.pc = self.registers[get_bits!(inst, 6, 3) as usize]
+                    next_pc = self.registers[get_bits!(inst, 6, 3) as usize]
                 }
+                self.registers[7] = self.pc.wrapping_add(1);
             }
             OpCode::LD => {
                 let offset = extend_to_u16!(get_bits!(inst, 0, 9), 9);
-                address_read = self.pc.wrapping_add(offset + 1);
-                self.registers[dr as usize] = self.memory[address_read as usize];
+                address_read = self.pc.wrapping_add(1).wrapping_add(offset);
+                self.registers[dr as usize] = self.load(address_read);
                 self.result = self.registers[dr as usize];
                 self.setcc();
             }
             OpCode::LDI => {
                 let offset = extend_to_u16!(get_bits!(inst, 0, 9), 9);
-                address_read = self.memory[self.pc.wrapping_add(offset + 1) as usize];
-                self.registers[dr as usize] = self.memory[address_read as usize];
+                address_read = self.load(self.pc.wrapping_add(1).wrapping_add(offset));
+                self.registers[dr as usize] = self.load(address_read);
                 self.result = self.registers[dr as usize];
                 self.setcc();
             }
@@ -171,86 +310,172 @@ impl Core {
                 let offset = extend_to_u16!(get_bits!(inst, 0, 6), 6);
                 let base_r = get_bits!(inst, 6, 3);
                 address_read = self.registers[base_r as usize].wrapping_add(offset);
-                self.registers[dr as usize] = self.memory[address_read as usize];
+                self.registers[dr as usize] = self.load(address_read);
                 self.result = self.registers[dr as usize];
                 self.setcc();
             }
             OpCode::LEA => {
                 let offset = extend_to_u16!(get_bits!(inst, 0, 9), 9);
-                self.registers[dr as usize] = self.pc.wrapping_add(offset + 1);
+                self.registers[dr as usize] = self.pc.wrapping_add(1).wrapping_add(offset);
                 self.result = self.registers[dr as usize];
                 self.setcc();
             }
             OpCode::ST => {
                 let sr = get_bits!(inst, 9, 3);
                 let offset = extend_to_u16!(get_bits!(inst, 0, 9), 9);
-                self.memory[self.pc.wrapping_add(offset + 1) as usize] =
-                    self.registers[sr as usize];
+                self.store(
+                    self.pc.wrapping_add(1).wrapping_add(offset),
+                    self.registers[sr as usize],
+                );
             }
             OpCode::STI => {
                 let sr = get_bits!(inst, 9, 3);
                 let offset = extend_to_u16!(get_bits!(inst, 0, 9), 9);
-                self.memory[self.memory[self.pc.wrapping_add(offset + 1) as usize] as usize] =
-                    self.registers[sr as usize];
+                let address = self.load(self.pc.wrapping_add(1).wrapping_add(offset));
+                self.store(address, self.registers[sr as usize]);
             }
             OpCode::STR => {
                 let sr = get_bits!(inst, 9, 3);
                 let base_r = get_bits!(inst, 6, 3);
                 let offset = extend_to_u16!(get_bits!(inst, 0, 6), 6);
-                self.memory[self.registers[base_r as usize].wrapping_add(offset) as usize] =
-                    self.registers[sr as usize];
+                self.store(
+                    self.registers[base_r as usize].wrapping_add(offset),
+                    self.registers[sr as usize],
+                );
             }
             OpCode::TRAP => {
                 let trapvect = get_bits!(inst, 0, 8);
-                self.registers[7] = self.pc + 1;
-                self.pc = self.memory[trapvect as usize];
+                // The routine returns with RET, through RTI when it has to leave supervisor mode
+                self.registers[7] = if self.user_mode() {
+                    self.enter_supervisor(next_pc);
+                    os::TRAP_RETURN
+                } else {
+                    self.pc.wrapping_add(1)
+                };
+                self.charge(trapvect);
+                next_pc = self.memory[trapvect as usize];
+            }
+            OpCode::RTI if self.user_mode() => {
+                self.exception = Some(PRIVILEGE_VECTOR);
             }
             OpCode::RTI => {
-                // should check if in priviledge mode. Don't care for now.
-                // R6 is the Stack Pointer
-                // Those two operations are like a "pop"
-                self.pc = self.memory[self.registers[6] as usize];
-                self.registers[6] = self.registers[6] + 1;
-                let tmp = self.registers[6];
-                self.registers[6] = self.registers[6] + 1;
-                self.psr = tmp;
+                next_pc = self.pop();
+                let psr = self.pop();
+                self.N = psr & 0b100 != 0;
+                self.Z = psr & 0b010 != 0;
+                self.P = psr & 0b001 != 0;
+                self.psr = psr & !0b111;
+                if self.user_mode() {
+                    self.swap_stacks();
+                }
+            }
+            OpCode::UNKNOWN => {
+                self.exception = Some(ILLEGAL_OPCODE_VECTOR);
             }
-            _ => (),
         };
         self.pc = next_pc;
         address_read
     }
 
-    pub fn load_obj(&mut self, obj: &[u16]) {
-        let location = obj[0] as usize;
-
-        let obj_data = &obj[1..];
+    // Copies an object (origin followed by data) in memory. Returns the number of words copied.
+    // Copies the words of obj at its origin, its first word. Fails if they run past xFFFF.
+    fn copy_obj(&mut self, obj: &[u16]) -> Result<usize, String> {
+        let (&origin, obj_data) = obj.split_first().ok_or("The object is empty.")?;
+        let location = origin as usize;
         let end = location + obj_data.len();
+        if end > MEMORY_SIZE {
+            return Err(format!(
+                "The object of {} words at x{:04X} does not fit in memory.",
+                obj_data.len(),
+                origin
+            ));
+        }
         self.memory[location..end].copy_from_slice(obj_data);
-        println!(
-            "Loaded {} bytes at address {:#x}",
-            obj_data.len() * 2,
-            location
-        );
+        for address in location..end {
+            self.initialized.mark(address as u16);
+        }
+        Ok(obj_data.len())
+    }
+    pub fn load_obj(&mut self, obj: &[u16]) -> Result<(), String> {
+        let size = self.copy_obj(obj)?;
+        println!("Loaded {} bytes at address {:#x}", size * 2, obj[0]);
+        Ok(())
     }
     // Returns the address that has been read from.
     pub fn step(&mut self) -> u16 {
-        let instruction = self.memory[self.pc as usize];
+        if self.halted() {
+            return 0;
+        }
+        let address = self.pc;
+        if self.serve_interrupt() {
+            self.account(address, false);
+            return 0;
+        }
+        if !self.check_access(address, Access::Execute) {
+            self.raise_exception();
+            self.account(address, false);
+            return 0;
+        }
+        if self.check_initialized(address, true) {
+            return 0;
+        }
+        let instruction = self.memory[address as usize];
+        if self.stop_on_illegal_opcode && OpCode::from(instruction) == OpCode::UNKNOWN {
+            self.stop = Some(StopReason::IllegalOpcode {
+                address,
+                word: instruction,
+            });
+            return 0;
+        }
+        self.charge(address);
+        // An instruction raising an exception is undone
+        let (registers, n, z, p) = (self.registers, self.N, self.Z, self.P);
+        let mode = (self.psr, self.swap_sp);
         let read_address = self.exec_instruction(instruction);
+        if self.exception.is_some() {
+            (self.registers, self.N, self.Z, self.P) = (registers, n, z, p);
+            (self.psr, self.swap_sp) = mode;
+            self.raise_exception();
+            self.account(address, true);
+            return 0;
+        }
+        self.track_call(address, instruction);
+        self.account(address, true);
         read_address
     }
-    pub fn interrupt(&mut self, interrupt: u16) {
-        // Load R6 with Supervisor Stack Pointer
-        self.swap_stacks();
-
-        self.registers[6] -= 2; // Reserving two spaces on the stack to store the PC AND PSR
-        self.memory[self.registers[6] as usize] = self.pc;
-        self.memory[self.registers[6] as usize + 1] = self.psr;
-        // PSR and PC pushed onto SSP
-
-        self.pc = self.memory[interrupt as usize]
-        //TODO: Set privilege to Supervisor Mode
-        //TODO: Set priority to level 4
+    // Pushes the PSR and the PC on the supervisor stack, switching to it from user mode.
+    pub(crate) fn enter_supervisor(&mut self, pc: u16) {
+        let psr = self.psr | (self.N as u16) << 2 | (self.Z as u16) << 1 | self.P as u16;
+        if self.user_mode() {
+            self.psr &= !USER_MODE;
+            self.swap_stacks();
+        }
+        self.push(psr);
+        self.push(pc);
+    }
+    // Enters the handler of the exception raised by the last instruction. RTI goes back to
+    // the instruction following it, or to the word that could not be fetched.
+    fn raise_exception(&mut self) {
+        if let Some(vector) = self.exception.take() {
+            self.enter_supervisor(self.pc);
+            // A violation while pushing would raise the exception again: the handler runs anyway
+            self.exception = None;
+            self.charge(VECTOR_TABLE + vector as u16);
+            self.pc = self.memory[(VECTOR_TABLE + vector as u16) as usize];
+        }
+    }
+    // Stack accesses go through the devices and the access checks like LDR and STR.
+    fn push(&mut self, value: u16) {
+        self.registers[6] = self.registers[6].wrapping_sub(1);
+        self.store(self.registers[6], value);
+    }
+    fn pop(&mut self) -> u16 {
+        let value = self.load(self.registers[6]);
+        self.registers[6] = self.registers[6].wrapping_add(1);
+        value
+    }
+    pub(crate) fn user_mode(&self) -> bool {
+        self.psr & USER_MODE != 0
     }
     pub fn registers_clone(&self) -> Vec<u16> {
         self.registers.into()
@@ -280,6 +505,15 @@ impl Core {
     pub fn pc(&self) -> u16 {
         self.pc
     }
+    pub fn psr(&self) -> u16 {
+        self.psr
+    }
+    pub fn register(&self, index: usize) -> u16 {
+        self.registers[index]
+    }
+    pub fn memory_at(&self, address: u16) -> u16 {
+        self.memory[address as usize]
+    }
 
     pub fn dump_registers(&mut self) {
         for (i, r) in self.registers.iter().enumerate() {
@@ -299,6 +533,26 @@ mod tests {
         assert!(c.registers.len() == REGISTERS_COUNT);
     }
 
+    #[test]
+    pub fn test_jsrr_trap() {
+        //          JSRR     R2
+        let jsrr = 0b0100_0_00_010_000000;
+        let mut c = Core::new();
+        c.pc = 0x3000;
+        c.registers[2] = 0x4000;
+        let _ = c.exec_instruction(jsrr);
+        assert_eq!(c.pc, 0x4000);
+        assert_eq!(c.registers[7], 0x3001);
+
+        //          TRAP x25 at the last address
+        let trap = 0b1111_0000_00100101;
+        c.pc = 0xFFFF;
+        c.memory[0x25] = 0x1000;
+        let _ = c.exec_instruction(trap);
+        assert_eq!(c.pc, 0x1000);
+        assert_eq!(c.registers[7], 0x0000);
+    }
+
     #[test]
     pub fn test_add() {
         //          ADD  R2  R7  IM 7
@@ -361,7 +615,7 @@ mod tests {
         //              R0 = R7 & -5
         let and_imm = 0b0101_000_111_1_11011;
         let _ = c.exec_instruction(and_imm);
-        assert_eq!(c.registers[0] as i16, 3 as i16 & (-5));
+        assert_eq!(c.registers[0] as i16, 3_i16 & (-5));
         assert_eq!(c.N, false);
         assert_eq!(c.Z, false);
         assert_eq!(c.P, true);
@@ -384,7 +638,7 @@ mod tests {
         //                             ORIG      ADD   R2  R7    7      ADD    R2  R2       R2
         let basic_program: [u16; 3] = [0x0200, 0b0001_010_111_1_00111, 0b0001_010_010_0_00_010];
         let mut c = Core::new();
-        c.load_obj(&basic_program);
+        c.load_obj(&basic_program).unwrap();
         c.step();
         c.step();
         c.step();
@@ -393,6 +647,17 @@ mod tests {
         assert_eq!(c.registers[2], 14);
     }
     #[test]
+    pub fn test_load_past_end() {
+        let mut c = Core::new();
+        assert_eq!(
+            c.load_obj(&[0xFFFF, 1, 2]),
+            Err("The object of 2 words at xFFFF does not fit in memory.".to_string())
+        );
+        assert_eq!(c.memory[0xFFFF], 0);
+        assert!(c.load_obj(&[]).is_err());
+        assert_eq!(c.load_obj(&[0xFFFF, 1]), Ok(()));
+    }
+    #[test]
     pub fn test_br() {
         let basic_program: [u16; 4] = [
             0x0200,
@@ -404,7 +669,7 @@ mod tests {
             0b0001_010_010_0_00_010,
         ];
         let mut c = Core::new();
-        c.load_obj(&basic_program);
+        c.load_obj(&basic_program).unwrap();
         c.step();
         c.step();
         c.step();
@@ -423,7 +688,7 @@ mod tests {
             0b0001_010_010_0_00_010,
         ];
         let mut c = Core::new();
-        c.load_obj(&basic_program);
+        c.load_obj(&basic_program).unwrap();
         c.step();
         c.step();
         c.step();
@@ -442,7 +707,7 @@ mod tests {
             0b0001_010_010_0_00_010,
         ];
         let mut c = Core::new();
-        c.load_obj(&basic_program);
+        c.load_obj(&basic_program).unwrap();
         c.step();
         c.step();
         c.step();
@@ -462,7 +727,7 @@ mod tests {
             0b0001_010_010_0_00_010,
         ];
         let mut c = Core::new();
-        c.load_obj(&basic_program);
+        c.load_obj(&basic_program).unwrap();
         c.step();
         c.step();
         c.step();
@@ -484,7 +749,7 @@ mod tests {
             0b0001_010_010_1_01010,
         ];
         let mut c = Core::new();
-        c.load_obj(&basic_program);
+        c.load_obj(&basic_program).unwrap();
         c.step();
         c.step();
         c.step();
@@ -509,7 +774,7 @@ mod tests {
             0b0001_010_010_1_01010,
         ];
         let mut c = Core::new();
-        c.load_obj(&basic_program);
+        c.load_obj(&basic_program).unwrap();
         c.step();
         c.step();
         c.step();
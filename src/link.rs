@@ -0,0 +1,417 @@
+use std::collections::HashMap;
+
+use crate::assemble::{symbol_file, Symbol};
+
+// Where the linker places the first module without .ORIG.
+const DEFAULT_ORIG: u16 = 0x3000;
+
+/// Field of a word patched by the linker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RelocationKind {
+    // Offsets of BR, LD, LDI, LEA, ST, STI and JSR
+    PCOffset9,
+    PCOffset11,
+    // Whole word of a .FILL
+    Word,
+}
+
+impl RelocationKind {
+    fn name(&self) -> &'static str {
+        match self {
+            RelocationKind::PCOffset9 => "pc9",
+            RelocationKind::PCOffset11 => "pc11",
+            RelocationKind::Word => "word",
+        }
+    }
+}
+
+/// Reference fixed once the modules are placed: the word at offset in the module refers to
+/// symbol, or to the start of the module itself when symbol is None. The word already holds
+/// the constant added to the symbol.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Relocation {
+    pub offset: u16,
+    pub kind: RelocationKind,
+    pub symbol: Option<String>,
+}
+
+/// Relocatable object, written as a .rel file.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Module {
+    // Name used in link errors, usually the path of the .rel file
+    pub name: String,
+    // Address the module must be loaded at, None to let the linker place it
+    pub orig: Option<u16>,
+    pub words: Vec<u16>,
+    // Labels, with addresses relative to the start of the module
+    pub symbols: Vec<Symbol>,
+    // Labels visible from the other modules
+    pub globals: Vec<String>,
+    pub externals: Vec<String>,
+    pub relocations: Vec<Relocation>,
+}
+
+impl Module {
+    pub fn parse(name: &str, content: &str) -> Result<Module, String> {
+        let mut module = Module {
+            name: name.to_string(),
+            ..Module::default()
+        };
+        for (i, line) in content.lines().enumerate() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let error = || format!("Invalid object {} at line {}: {}", name, i + 1, line);
+            let address = |s: &str| {
+                s.strip_prefix('x')
+                    .and_then(|s| u16::from_str_radix(s, 16).ok())
+                    .ok_or_else(error)
+            };
+            match fields.as_slice() {
+                [] => (),
+                [comment, ..] if comment.starts_with('#') => (),
+                ["orig", a] => module.orig = Some(address(a)?),
+                ["symbol", name, a] => module.symbols.push(Symbol {
+                    name: name.to_string(),
+                    address: address(a)?,
+                }),
+                ["global", name] => module.globals.push(name.to_string()),
+                ["external", name] => module.externals.push(name.to_string()),
+                ["reloc", offset, kind, symbol @ ..] if symbol.len() <= 1 => {
+                    module.relocations.push(Relocation {
+                        offset: address(offset)?,
+                        kind: match *kind {
+                            "pc9" => RelocationKind::PCOffset9,
+                            "pc11" => RelocationKind::PCOffset11,
+                            "word" => RelocationKind::Word,
+                            _ => return Err(error()),
+                        },
+                        symbol: symbol.first().map(|s| s.to_string()),
+                    })
+                }
+                ["words", words @ ..] => {
+                    for word in words {
+                        module.words.push(address(word)?);
+                    }
+                }
+                _ => return Err(error()),
+            }
+        }
+        if let Some(r) = module
+            .relocations
+            .iter()
+            .find(|r| r.offset as usize >= module.words.len())
+        {
+            return Err(format!(
+                "Invalid object {}: relocation at x{:04X} is past the end.",
+                name, r.offset
+            ));
+        }
+        Ok(module)
+    }
+
+    // Content of the .rel file
+    pub fn serialize(&self) -> String {
+        let mut out = String::from("# tdal3 relocatable object\n");
+        if let Some(orig) = self.orig {
+            out.push_str(&format!("orig x{:04X}\n", orig));
+        }
+        for symbol in &self.symbols {
+            out.push_str(&format!("symbol {} x{:04X}\n", symbol.name, symbol.address));
+        }
+        for name in &self.globals {
+            out.push_str(&format!("global {}\n", name));
+        }
+        for name in &self.externals {
+            out.push_str(&format!("external {}\n", name));
+        }
+        for r in &self.relocations {
+            out.push_str(&format!("reloc x{:04X} {}", r.offset, r.kind.name()));
+            if let Some(symbol) = &r.symbol {
+                out.push_str(&format!(" {}", symbol));
+            }
+            out.push('\n');
+        }
+        for words in self.words.chunks(8) {
+            let words: Vec<String> = words.iter().map(|w| format!("x{:04X}", w)).collect();
+            out.push_str(&format!("words {}\n", words.join(" ")));
+        }
+        out
+    }
+}
+
+/// Program made of linked modules.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Linked {
+    // Origin followed by the words, as expected by Core::load_obj
+    pub object: Vec<u16>,
+    // Labels of every module
+    pub symbols: Vec<Symbol>,
+}
+
+impl Linked {
+    pub fn symbol_file(&self) -> String {
+        symbol_file(&self.symbols)
+    }
+}
+
+// Places the modules in memory and patches their references to each other. Modules with
+// .ORIG stay there, the others follow the module before them (the first one going to
+// x3000). Gaps between modules are filled with zeros.
+pub fn link(modules: &[Module]) -> Result<Linked, String> {
+    let mut errors = Vec::new();
+    let mut bases = Vec::new();
+    let mut next = DEFAULT_ORIG as u32;
+    for module in modules {
+        let base = module.orig.map_or(next, u32::from);
+        next = base + module.words.len() as u32;
+        if next > 0x10000 {
+            errors.push(format!("{} does not fit in memory.", module.name));
+        }
+        bases.push(base as u16);
+    }
+
+    let mut sections: Vec<(u16, u32, &str)> = modules
+        .iter()
+        .zip(&bases)
+        .map(|(m, &base)| (base, base as u32 + m.words.len() as u32, m.name.as_str()))
+        .filter(|(start, end, _)| *start as u32 != *end)
+        .collect();
+    sections.sort();
+    for pair in sections.windows(2) {
+        let ((_, end, first), (start, _, second)) = (pair[0], pair[1]);
+        if end > start as u32 {
+            errors.push(format!(
+                "{} and {} overlap at x{:04X}.",
+                first, second, start
+            ));
+        }
+    }
+
+    let mut globals: HashMap<&str, (u16, &str)> = HashMap::new();
+    let mut symbols = Vec::new();
+    for (module, &base) in modules.iter().zip(&bases) {
+        for symbol in &module.symbols {
+            let address = base.wrapping_add(symbol.address);
+            symbols.push(Symbol {
+                name: symbol.name.clone(),
+                address,
+            });
+            if !module.globals.contains(&symbol.name) {
+                continue;
+            }
+            if let Some((_, other)) = globals.insert(&symbol.name, (address, &module.name)) {
+                errors.push(format!(
+                    "{} is defined by both {} and {}.",
+                    symbol.name, other, module.name
+                ));
+            }
+        }
+        for name in &module.globals {
+            if !module.symbols.iter().any(|s| &s.name == name) {
+                errors.push(format!(
+                    "{} declares {} global but does not define it.",
+                    module.name, name
+                ));
+            }
+        }
+    }
+
+    let mut memory: Vec<Option<u16>> = vec![None; 0x10000];
+    for (module, &base) in modules.iter().zip(&bases) {
+        // Already reported as not fitting
+        if base as usize + module.words.len() > 0x10000 {
+            continue;
+        }
+        let mut words = module.words.clone();
+        for r in &module.relocations {
+            let address = base.wrapping_add(r.offset);
+            let target = match &r.symbol {
+                None => base,
+                Some(name) => match globals.get(name.as_str()) {
+                    Some(&(target, _)) => target,
+                    None => {
+                        errors.push(format!(
+                            "Undefined symbol {} referenced by {} at x{:04X}.",
+                            name, module.name, address
+                        ));
+                        continue;
+                    }
+                },
+            };
+            let word = &mut words[r.offset as usize];
+            let size = match r.kind {
+                RelocationKind::Word => {
+                    *word = word.wrapping_add(target);
+                    continue;
+                }
+                RelocationKind::PCOffset9 => 9,
+                RelocationKind::PCOffset11 => 11,
+            };
+            let mask = u16::MAX >> (16 - size);
+            // Sign extends the constant held by the field
+            let addend = ((*word & mask) << (16 - size)) as i16 as i32 >> (16 - size);
+            let offset = target.wrapping_sub(address.wrapping_add(1)) as i16 as i32 + addend;
+            if !(-(1 << (size - 1))..(1 << (size - 1))).contains(&offset) {
+                errors.push(format!(
+                    "{} at x{:04X} in {} is too far: offset {} does not fit in {} bits.",
+                    r.symbol.as_deref().unwrap_or(&module.name),
+                    address,
+                    module.name,
+                    offset,
+                    size
+                ));
+                continue;
+            }
+            *word = *word & !mask | offset as u16 & mask;
+        }
+        for (i, word) in words.into_iter().enumerate() {
+            memory[base as usize + i] = Some(word);
+        }
+    }
+    if !errors.is_empty() {
+        return Err(errors.join("\n"));
+    }
+
+    let start = sections.first().map_or(DEFAULT_ORIG, |s| s.0);
+    let end = sections.iter().map(|s| s.1).max().unwrap_or(start as u32);
+    let mut object = vec![start];
+    object.extend(
+        memory[start as usize..end as usize]
+            .iter()
+            .map(|w| w.unwrap_or(0)),
+    );
+    Ok(Linked { object, symbols })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assemble::{assemble_module, assemble_with};
+    use crate::source::MemoryFiles;
+
+    fn modules(files: &MemoryFiles, names: &[&str]) -> Vec<Module> {
+        names
+            .iter()
+            .map(|name| assemble_module(name, files).unwrap().module(name))
+            .collect()
+    }
+
+    #[test]
+    fn test_serialize() {
+        let module = Module {
+            name: "lib.rel".into(),
+            orig: None,
+            words: (0..10).collect(),
+            symbols: vec![Symbol {
+                name: "PRINT".into(),
+                address: 2,
+            }],
+            globals: vec!["PRINT".into()],
+            externals: vec!["BUFFER".into()],
+            relocations: vec![
+                Relocation {
+                    offset: 3,
+                    kind: RelocationKind::PCOffset9,
+                    symbol: Some("BUFFER".into()),
+                },
+                Relocation {
+                    offset: 9,
+                    kind: RelocationKind::Word,
+                    symbol: None,
+                },
+            ],
+        };
+        assert_eq!(Module::parse("lib.rel", &module.serialize()), Ok(module));
+        assert!(Module::parse("bad.rel", "words x0000\nreloc x0001 word").is_err());
+    }
+
+    #[test]
+    fn test_link() {
+        let mut files = MemoryFiles::new();
+        files.add(
+            "main.asm",
+            ".EXTERNAL PRINT, MSG\n.ORIG x3000\nLEA R0, MSG+1\nJSR PRINT\nHALT\n.FILL PRINT",
+        );
+        files.add(
+            "lib.asm",
+            ".GLOBAL PRINT, MSG\nPRINT PUTS\nRET\nMSG .STRINGZ \"hi\"\nPTR .FILL MSG\n.FILL MSG-PRINT",
+        );
+        let linked = link(&modules(&files, &["main.asm", "lib.asm"])).unwrap();
+        assert_eq!(
+            linked.object,
+            [
+                0x3000, 0xE006, 0x4802, 0xF025, 0x3004, 0xF022, 0xC1C0, 0x0068, 0x0069, 0x0000,
+                0x3006, 0x0002
+            ]
+        );
+        assert!(linked.symbols.contains(&Symbol {
+            name: "PTR".into(),
+            address: 0x3009
+        }));
+        assert!(assemble_with("main.asm", &files)
+            .unwrap_err()
+            .contains("MSG is external"));
+
+        files.add(
+            "far.asm",
+            ".GLOBAL PRINT, MSG\n.ORIG x4000\nPRINT RET\nMSG .FILL 0",
+        );
+        let errors = link(&modules(&files, &["main.asm", "far.asm"])).unwrap_err();
+        let errors: Vec<&str> = errors.lines().collect();
+        assert_eq!(
+            errors,
+            [
+                "MSG at x3000 in main.asm is too far: offset 4097 does not fit in 9 bits.",
+                "PRINT at x3001 in main.asm is too far: offset 4094 does not fit in 11 bits."
+            ]
+        );
+        let errors = link(&modules(&files, &["main.asm", "lib.asm", "lib.asm"])).unwrap_err();
+        assert!(errors.contains("PRINT is defined by both lib.asm and lib.asm."));
+        let errors = link(&modules(&files, &["main.asm"])).unwrap_err();
+        assert!(errors.contains("Undefined symbol MSG referenced by main.asm at x3000."));
+        files.add("over.asm", ".ORIG x3002\nHALT");
+        let errors = link(&modules(&files, &["main.asm", "lib.asm", "over.asm"])).unwrap_err();
+        assert_eq!(errors, "main.asm and over.asm overlap at x3002.");
+        let top = Module {
+            name: "top.rel".into(),
+            orig: Some(0xFFFF),
+            words: vec![0xF025, 0xF025],
+            symbols: Vec::new(),
+            globals: Vec::new(),
+            externals: Vec::new(),
+            relocations: Vec::new(),
+        };
+        assert_eq!(link(&[top]).unwrap_err(), "top.rel does not fit in memory.");
+    }
+
+    #[test]
+    fn test_module_errors() {
+        let mut files = MemoryFiles::new();
+        files.add(
+            "main.asm",
+            ".EXTERNAL EXT\nADD R1, R1, EXT\nLD R0, EXT*2\n.EQU X, EXT\nEXT HALT\n.FILL EXT+1",
+        );
+        let error = assemble_module("main.asm", &files).unwrap_err();
+        let lines: Vec<&str> = error.lines().filter(|l| l.starts_with(" -->")).collect();
+        assert_eq!(
+            lines,
+            [
+                " --> External symbol EXT cannot be used in a constant.",
+                " --> Label EXT is already defined."
+            ]
+        );
+        files.add(
+            "main.asm",
+            ".EXTERNAL EXT\nADD R1, R1, EXT\nLD R0, EXT*2\nLEA R0, EXT-1\n.FILL START*2\nSTART .FILL EXT+1",
+        );
+        let error = assemble_module("main.asm", &files).unwrap_err();
+        let lines: Vec<&str> = error.lines().filter(|l| l.starts_with(" -->")).collect();
+        assert_eq!(
+            lines,
+            [
+                " --> External symbol EXT can only be used by .FILL and PC-relative instructions.",
+                " --> External symbol EXT can only be offset by a constant.",
+                " --> Only addresses plus or minus a constant can be relocated."
+            ]
+        );
+    }
+}
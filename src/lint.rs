@@ -0,0 +1,600 @@
+use std::collections::{HashMap, HashSet};
+
+use wasm_bindgen::prelude::*;
+
+use crate::assemble::indent;
+use crate::diagnostic::{render_all, Diagnostic, Severity};
+use crate::lexer::{tokenize, TokenKind};
+use crate::parser::{parse_files, OperandTypes, ParsedFile, ParsedLine, ParsedOpCode};
+use crate::source::{MemoryFiles, SourceFile, SourceProvider};
+
+/// A check of the linter, named by its id in diagnostics and configurations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rule {
+    // A subroutine overwrites R7 before saving it, so its RET goes astray
+    UnsavedR7,
+    // Code continues into .FILL, .BLKW or .STRINGZ words
+    FallThroughData,
+    // Execution can run past the last line of the program
+    MissingHalt,
+    UnusedLabel,
+    // Unlabelled code after an unconditional branch, jump, RET or HALT
+    UnreachableCode,
+    // BR without flags, which some assemblers encode as a branch never taken
+    BranchWithoutCondition,
+}
+
+impl Rule {
+    pub const ALL: [Rule; 6] = [
+        Rule::UnsavedR7,
+        Rule::FallThroughData,
+        Rule::MissingHalt,
+        Rule::UnusedLabel,
+        Rule::UnreachableCode,
+        Rule::BranchWithoutCondition,
+    ];
+
+    pub fn id(&self) -> &'static str {
+        match self {
+            Rule::UnsavedR7 => "unsaved-r7",
+            Rule::FallThroughData => "fallthrough-into-data",
+            Rule::MissingHalt => "missing-halt",
+            Rule::UnusedLabel => "unused-label",
+            Rule::UnreachableCode => "unreachable-code",
+            Rule::BranchWithoutCondition => "br-without-condition",
+        }
+    }
+
+    pub fn from_id(id: &str) -> Option<Rule> {
+        Rule::ALL.into_iter().find(|rule| rule.id() == id)
+    }
+}
+
+/// Severity each rule reports with, None turning the rule off. Every rule warns by default.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LintConfig {
+    levels: HashMap<Rule, Option<Severity>>,
+}
+
+impl Default for LintConfig {
+    fn default() -> Self {
+        LintConfig {
+            levels: Rule::ALL
+                .into_iter()
+                .map(|rule| (rule, Some(Severity::Warning)))
+                .collect(),
+        }
+    }
+}
+
+impl LintConfig {
+    pub fn set(&mut self, rule: Rule, level: Option<Severity>) {
+        self.levels.insert(rule, level);
+    }
+
+    pub fn level(&self, rule: Rule) -> Option<Severity> {
+        self.levels.get(&rule).copied().flatten()
+    }
+
+    // Default configuration changed by a list such as "unused-label=off, missing-halt=error".
+    pub fn parse(spec: &str) -> Result<LintConfig, String> {
+        let mut config = LintConfig::default();
+        for setting in spec.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+            let Some((id, level)) = setting.split_once('=') else {
+                return Err(format!("Expected rule=level, found {}.", setting));
+            };
+            let (id, level) = (id.trim(), level.trim());
+            let rule = Rule::from_id(id).ok_or_else(|| format!("Unknown lint rule {}.", id))?;
+            let level = match level {
+                "off" => None,
+                "warning" | "warn" => Some(Severity::Warning),
+                "error" => Some(Severity::Error),
+                _ => {
+                    return Err(format!(
+                        "Unknown level {} for {}: expected off, warning or error.",
+                        level, id
+                    ))
+                }
+            };
+            config.set(rule, level);
+        }
+        Ok(config)
+    }
+}
+
+// A line emitting words, with its file and zero based line.
+struct Item<'a> {
+    position: (usize, usize),
+    line: &'a ParsedLine,
+}
+
+// Where execution goes after an item.
+enum Next {
+    Item(usize),
+    // Past the last line of the program
+    End,
+}
+
+// State of R7 along a path through a subroutine.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+enum ReturnAddress {
+    Intact,
+    Saved,
+    // Overwritten by the item at this index
+    Clobbered(usize),
+}
+
+fn register(line: &ParsedLine, index: usize) -> Option<u8> {
+    match line.operands.get(index)?.operand_type {
+        OperandTypes::Register(r) => Some(r),
+        _ => None,
+    }
+}
+
+fn is_data(line: &ParsedLine) -> bool {
+    matches!(
+        line.opcode,
+        ParsedOpCode::FILL | ParsedOpCode::BLKW | ParsedOpCode::STRINGZ | ParsedOpCode::LTORG
+    )
+}
+
+fn is_halt(line: &ParsedLine) -> bool {
+    match line.opcode {
+        ParsedOpCode::HALT => true,
+        ParsedOpCode::TRAP => matches!(
+            line.operands.first().map(|o| &o.operand_type),
+            Some(OperandTypes::Immediate { value: 0x25, .. })
+        ),
+        _ => false,
+    }
+}
+
+// Whether execution may continue with the next line, calls returning there.
+fn falls_through(line: &ParsedLine) -> bool {
+    !is_data(line)
+        && !is_halt(line)
+        && !matches!(
+            line.opcode,
+            ParsedOpCode::BR | ParsedOpCode::JMP | ParsedOpCode::RET | ParsedOpCode::RTI
+        )
+}
+
+// Instructions writing the return address in R7.
+fn writes_r7(line: &ParsedLine) -> bool {
+    match line.opcode {
+        ParsedOpCode::ADD
+        | ParsedOpCode::AND
+        | ParsedOpCode::NOT
+        | ParsedOpCode::LD
+        | ParsedOpCode::LDI
+        | ParsedOpCode::LDR
+        | ParsedOpCode::LEA => register(line, 0) == Some(7),
+        ParsedOpCode::JSR
+        | ParsedOpCode::JSRR
+        | ParsedOpCode::TRAP
+        | ParsedOpCode::GETC
+        | ParsedOpCode::OUT
+        | ParsedOpCode::PUTS
+        | ParsedOpCode::IN
+        | ParsedOpCode::PUTSP
+        | ParsedOpCode::HALT => true,
+        _ => false,
+    }
+}
+
+// Stores of R7 in memory, or copies to another register.
+fn saves_r7(line: &ParsedLine) -> bool {
+    match line.opcode {
+        ParsedOpCode::ST | ParsedOpCode::STI | ParsedOpCode::STR => register(line, 0) == Some(7),
+        ParsedOpCode::ADD | ParsedOpCode::AND => {
+            register(line, 1) == Some(7) && register(line, 0) != Some(7)
+        }
+        _ => false,
+    }
+}
+
+struct Linter<'a> {
+    items: Vec<Item<'a>>,
+    labels: HashMap<&'a str, usize>,
+    // Symbols appearing in operands
+    used: HashSet<&'a str>,
+    files: &'a [SourceFile],
+    config: &'a LintConfig,
+    // Item each diagnostic is about, to sort them in program order
+    found: Vec<(usize, Diagnostic)>,
+}
+
+impl<'a> Linter<'a> {
+    fn new(parsed: &'a ParsedFile, files: &'a [SourceFile], config: &'a LintConfig) -> Self {
+        let mut items = Vec::new();
+        // .LTORG only emits words when literals were loaded since the previous pool
+        let mut literals = false;
+        let lines = parsed.instructions.iter().zip(&parsed.positions);
+        for (line, &position) in lines.flat_map(|(lines, p)| lines.iter().map(move |l| (l, p))) {
+            let emits = match line.opcode {
+                ParsedOpCode::EQU
+                | ParsedOpCode::SET
+                | ParsedOpCode::GLOBAL
+                | ParsedOpCode::EXTERNAL => false,
+                ParsedOpCode::LTORG => std::mem::take(&mut literals),
+                _ => true,
+            };
+            if let Some(OperandTypes::Literal(_)) = line.operands.get(1).map(|o| &o.operand_type) {
+                literals = true;
+            }
+            if emits {
+                items.push(Item { position, line });
+            }
+        }
+        // Lines emitting no word use symbols too, .GLOBAL exporting them
+        let mut used = HashSet::new();
+        for line in parsed.instructions.iter().flatten() {
+            for operand in &line.operands {
+                match &operand.operand_type {
+                    OperandTypes::Label(name) => {
+                        used.insert(name.as_str());
+                    }
+                    OperandTypes::Expression(expr) | OperandTypes::Literal(expr) => {
+                        used.extend(expr.symbols());
+                    }
+                    _ => (),
+                }
+            }
+        }
+        let labels = items
+            .iter()
+            .enumerate()
+            .filter_map(|(i, item)| Some((item.line.label.as_deref()?, i)))
+            .collect();
+        Linter {
+            items,
+            labels,
+            used,
+            files,
+            config,
+            found: Vec::new(),
+        }
+    }
+
+    fn report(&mut self, rule: Rule, item: usize, column: Option<usize>, message: String) {
+        let Some(severity) = self.config.level(rule) else {
+            return;
+        };
+        let (file, line) = self.items[item].position;
+        let column = column.unwrap_or_else(|| indent(&self.files[file].lines[line]));
+        let diagnostic = Diagnostic {
+            severity,
+            file,
+            line: line + 1,
+            column,
+            message,
+            rule: Some(rule.id()),
+        };
+        self.found.push((item, diagnostic));
+    }
+
+    // Item a branch or a call goes to, when it is a label of the program.
+    fn target(&self, line: &ParsedLine) -> Option<usize> {
+        match &line.operands.first()?.operand_type {
+            OperandTypes::Label(name) => self.labels.get(name.as_str()).copied(),
+            _ => None,
+        }
+    }
+
+    fn successors(&self, item: usize) -> Vec<Next> {
+        let line = self.items[item].line;
+        let mut next = Vec::new();
+        let branch = matches!(
+            line.opcode,
+            ParsedOpCode::BR
+                | ParsedOpCode::BRz
+                | ParsedOpCode::BRp
+                | ParsedOpCode::BRn
+                | ParsedOpCode::BRzp
+                | ParsedOpCode::BRzn
+                | ParsedOpCode::BRpn
+        );
+        if branch {
+            next.extend(self.target(line).map(Next::Item));
+        }
+        if falls_through(line) {
+            next.push(match item + 1 {
+                i if i < self.items.len() => Next::Item(i),
+                _ => Next::End,
+            });
+        }
+        next
+    }
+
+    fn unreachable_code(&mut self) {
+        for i in 1..self.items.len() {
+            let (previous, line) = (self.items[i - 1].line, self.items[i].line);
+            if !is_data(previous)
+                && !falls_through(previous)
+                && !is_data(line)
+                && line.label.is_none()
+            {
+                let message = "This instruction can never run: it follows an unconditional branch and has no label.";
+                self.report(Rule::UnreachableCode, i, None, message.into());
+            }
+        }
+    }
+
+    fn fall_through_data(&mut self) {
+        for i in 1..self.items.len() {
+            let (previous, line) = (self.items[i - 1].line, self.items[i].line);
+            if falls_through(previous) && is_data(line) {
+                let message = "Execution continues from the instruction above into this data.";
+                self.report(Rule::FallThroughData, i, None, message.into());
+            }
+        }
+    }
+
+    // Follows the program from its first line, and called subroutines, looking for paths
+    // running past the last line.
+    fn missing_halt(&mut self) {
+        if self.items.first().is_none_or(|item| is_data(item.line)) {
+            return;
+        }
+        let mut visited = HashSet::from([0]);
+        let mut pending = vec![0];
+        while let Some(item) = pending.pop() {
+            let mut next = self.successors(item);
+            let line = self.items[item].line;
+            if line.opcode == ParsedOpCode::JSR {
+                next.extend(self.target(line).map(Next::Item));
+            }
+            for next in next {
+                match next {
+                    Next::Item(i) if visited.insert(i) => pending.push(i),
+                    Next::Item(_) => (),
+                    Next::End => {
+                        let message =
+                            "Execution can run past the end of the program: HALT is missing.";
+                        self.report(Rule::MissingHalt, item, None, message.into());
+                    }
+                }
+            }
+        }
+    }
+
+    fn unused_labels(&mut self) {
+        for i in 0..self.items.len() {
+            let line = self.items[i].line;
+            // Labels of macro bodies are the concern of the macro
+            match &line.label {
+                Some(label) if line.expansion.is_none() && !self.used.contains(label.as_str()) => {
+                    let message = format!("Label {} is never used.", label);
+                    self.report(Rule::UnusedLabel, i, None, message);
+                }
+                _ => (),
+            }
+        }
+    }
+
+    fn branches_without_condition(&mut self) {
+        for i in 0..self.items.len() {
+            let item = &self.items[i];
+            if item.line.opcode != ParsedOpCode::BR {
+                continue;
+            }
+            let (file, line) = item.position;
+            // Expanded lines are reported at the invocation
+            let (text, column) = match &item.line.expansion {
+                Some(text) => (text.as_str(), Some(indent(&self.files[file].lines[line]))),
+                None => (self.files[file].lines[line].as_str(), None),
+            };
+            let written = tokenize(text, line + 1).ok().and_then(|tokens| {
+                tokens.into_iter().find(|token| {
+                    matches!(&token.kind, TokenKind::Word(word) if word.eq_ignore_ascii_case("BR"))
+                })
+            });
+            if let Some(token) = written {
+                let message = "BR without condition flags is taken always here but never by some assemblers: write BRnzp.";
+                self.report(
+                    Rule::BranchWithoutCondition,
+                    i,
+                    column.or(Some(token.column)),
+                    message.into(),
+                );
+            }
+        }
+    }
+
+    // Follows each subroutine called by JSR from its entry to its RETs, tracking whether R7
+    // was overwritten before being saved.
+    fn unsaved_r7(&mut self) {
+        let entries: Vec<usize> = self
+            .items
+            .iter()
+            .filter(|item| item.line.opcode == ParsedOpCode::JSR)
+            .filter_map(|item| self.target(item.line))
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        let mut clobbers = HashMap::new();
+        for entry in entries {
+            let mut visited = HashSet::from([(entry, ReturnAddress::Intact)]);
+            let mut pending = vec![(entry, ReturnAddress::Intact)];
+            while let Some((item, state)) = pending.pop() {
+                let line = self.items[item].line;
+                if line.opcode == ParsedOpCode::RET {
+                    if let ReturnAddress::Clobbered(at) = state {
+                        clobbers.entry(at).or_insert(entry);
+                    }
+                    continue;
+                }
+                let state = match state {
+                    ReturnAddress::Intact if saves_r7(line) => ReturnAddress::Saved,
+                    ReturnAddress::Intact if writes_r7(line) => ReturnAddress::Clobbered(item),
+                    state => state,
+                };
+                for next in self.successors(item) {
+                    if let Next::Item(i) = next {
+                        if visited.insert((i, state)) {
+                            pending.push((i, state));
+                        }
+                    }
+                }
+            }
+        }
+        let mut clobbers: Vec<(usize, usize)> = clobbers.into_iter().collect();
+        clobbers.sort();
+        for (at, entry) in clobbers {
+            let name = self.items[entry].line.label.clone().unwrap_or_default();
+            let message = format!(
+                "This overwrites R7, the return address of subroutine {}, which was not saved: its RET will not return to the caller.",
+                name
+            );
+            self.report(Rule::UnsavedR7, at, None, message);
+        }
+    }
+}
+
+// Lints a parsed program, diagnostics being in program order.
+fn lint_parsed(parsed: &ParsedFile, files: &[SourceFile], config: &LintConfig) -> Vec<Diagnostic> {
+    let mut linter = Linter::new(parsed, files, config);
+    linter.unsaved_r7();
+    linter.fall_through_data();
+    linter.missing_halt();
+    linter.unused_labels();
+    linter.unreachable_code();
+    linter.branches_without_condition();
+    linter.found.sort_by_key(|(item, _)| *item);
+    linter.found.into_iter().map(|(_, d)| d).collect()
+}
+
+/// What the linter found in a program, and the files the diagnostics refer to.
+#[derive(Debug, Clone)]
+pub struct Lints {
+    pub diagnostics: Vec<Diagnostic>,
+    pub files: Vec<SourceFile>,
+}
+
+impl Lints {
+    pub fn has_errors(&self) -> bool {
+        self.diagnostics
+            .iter()
+            .any(|d| d.severity == Severity::Error)
+    }
+
+    pub fn render(&self) -> String {
+        render_all(&self.diagnostics, &self.files)
+    }
+}
+
+// Lints the file at path main and the files it includes. Programs that do not parse give
+// the parse errors.
+pub fn lint_with(
+    main: &str,
+    provider: &dyn SourceProvider,
+    config: &LintConfig,
+) -> Result<Lints, String> {
+    let main = provider.resolve("", main);
+    let content = provider.read(&main)?;
+    let mut files = vec![SourceFile::new(&main, &content)];
+    let parsed = parse_files(&mut files, provider)
+        .map_err(|diagnostics| render_all(&diagnostics, &files))?;
+    Ok(Lints {
+        diagnostics: lint_parsed(&parsed, &files, config),
+        files,
+    })
+}
+
+// Lints the main file of the web editor with rules configured as by LintConfig::parse,
+// giving the rendered diagnostics.
+#[wasm_bindgen]
+pub fn lint_project(main: &str, files: &MemoryFiles, rules: &str) -> Result<String, String> {
+    let config = LintConfig::parse(rules)?;
+    lint_with(main, files, &config).map(|lints| lints.render())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rule and line of each diagnostic on the program.
+    fn lint(lines: &[&str], config: &LintConfig) -> Vec<(&'static str, usize)> {
+        let mut files = MemoryFiles::new();
+        files.add("main.asm", &lines.join("\n"));
+        let lints = lint_with("main.asm", &files, config).unwrap();
+        lints
+            .diagnostics
+            .iter()
+            .map(|d| (d.rule.unwrap(), d.line))
+            .collect()
+    }
+
+    #[test]
+    fn test_rules() {
+        let config = LintConfig::default();
+        let program = [
+            ".ORIG x3000",
+            "        JSR SUB",
+            "        BR DONE",
+            "        ADD R0, R0, #1",
+            "DONE    HALT",
+            "SUB     LEA R0, MSG",
+            "        PUTS",
+            "        RET",
+            "SAFE    ST R7, SAVE",
+            "        OUT",
+            "        LD R7, SAVE",
+            "        RET",
+            "SAVE    .BLKW 1",
+            "MSG     .STRINGZ \"Hi\"",
+            ".END",
+        ];
+        assert_eq!(
+            lint(&program, &config),
+            [
+                ("br-without-condition", 3),
+                ("unreachable-code", 4),
+                ("unsaved-r7", 7),
+                ("unused-label", 9),
+            ]
+        );
+
+        let falls = [
+            ".ORIG x3000",
+            "LOOP ADD R1, R1, #-1",
+            "BRp LOOP",
+            "N .FILL 3",
+        ];
+        assert_eq!(
+            lint(&falls, &config),
+            [("fallthrough-into-data", 4), ("unused-label", 4)]
+        );
+        let end = [
+            ".ORIG x3000",
+            "LD R1, N",
+            "BRz MORE",
+            "HALT",
+            "MORE ADD R1, R1, #1",
+            "N .FILL 3",
+        ];
+        assert_eq!(lint(&end[..5], &config), [("missing-halt", 5)]);
+        assert_eq!(lint(&end, &config), [("fallthrough-into-data", 6)]);
+    }
+
+    #[test]
+    fn test_config() {
+        let config = LintConfig::parse("unused-label=off, br-without-condition = error").unwrap();
+        assert_eq!(config.level(Rule::UnusedLabel), None);
+        assert_eq!(
+            config.level(Rule::BranchWithoutCondition),
+            Some(Severity::Error)
+        );
+        assert_eq!(config.level(Rule::MissingHalt), Some(Severity::Warning));
+        let program = [".ORIG x3000", "L BR L"];
+        assert_eq!(lint(&program, &config), [("br-without-condition", 2)]);
+        assert_eq!(
+            LintConfig::parse("unused=off"),
+            Err("Unknown lint rule unused.".into())
+        );
+        assert_eq!(
+            LintConfig::parse("unused-label=on"),
+            Err("Unknown level on for unused-label: expected off, warning or error.".into())
+        );
+    }
+}
@@ -0,0 +1,100 @@
+use crate::lexer::{Token, TokenKind};
+
+// Guards against macros invoking themselves forever.
+pub const MAX_MACRO_DEPTH: usize = 16;
+
+/// A `.MACRO name params ... .ENDM` definition.
+#[derive(Debug, Clone)]
+pub struct Macro {
+    pub name: String,
+    pub params: Vec<String>,
+    // Tokens of each line of the body, comments removed
+    pub body: Vec<Vec<Token>>,
+}
+
+impl Macro {
+    pub fn new(name: String, params: Vec<String>) -> Self {
+        Macro {
+            name,
+            params,
+            body: Vec::new(),
+        }
+    }
+
+    // Body of the macro for one invocation. Parameters are replaced by the arguments, whatever
+    // their case and also after a #, and the
+    // labels listed in locals get a __<expansion> suffix so that each expansion has its own.
+    pub fn expand(
+        &self,
+        args: &[Vec<Token>],
+        locals: &[String],
+        expansion: usize,
+    ) -> Vec<Vec<Token>> {
+        self.body
+            .iter()
+            .map(|line| {
+                let mut tokens: Vec<Token> = Vec::new();
+                for token in line {
+                    match &token.kind {
+                        TokenKind::Word(word) => {
+                            if let Some(i) = self
+                                .params
+                                .iter()
+                                .position(|p| p.eq_ignore_ascii_case(word))
+                            {
+                                // #n with n = #3 gives #3 rather than ##3
+                                let literal = matches!(
+                                    args[i].first().map(|t| &t.kind),
+                                    Some(TokenKind::Decimal(_) | TokenKind::Hash)
+                                );
+                                if literal
+                                    && tokens.last().map(|t| &t.kind) == Some(&TokenKind::Hash)
+                                {
+                                    tokens.pop();
+                                }
+                                tokens.extend(args[i].iter().cloned());
+                            } else if locals.contains(word) {
+                                tokens.push(Token {
+                                    kind: TokenKind::Word(format!("{}__{}", word, expansion)),
+                                    column: token.column,
+                                });
+                            } else {
+                                tokens.push(token.clone());
+                            }
+                        }
+                        _ => tokens.push(token.clone()),
+                    }
+                }
+                tokens
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::{render, tokenize};
+
+    #[test]
+    fn test_expand() {
+        let mut m = Macro::new("COUNT".into(), vec!["reg".into()]);
+        m.body.push(tokenize("LOOP ADD reg, reg, #-1", 2).unwrap());
+        m.body.push(tokenize("BRp LOOP", 3).unwrap());
+        let args = [tokenize("R3", 5).unwrap()];
+        let lines = m.expand(&args, &["LOOP".into()], 7);
+        let lines: Vec<String> = lines.iter().map(|l| render(l)).collect();
+        assert_eq!(lines, ["LOOP__7 ADD R3, R3, #-1", "BRp LOOP__7"]);
+    }
+
+    #[test]
+    fn test_expand_params() {
+        // Parameters ignore case and can follow #
+        let mut m = Macro::new("INC".into(), vec!["reg".into(), "n".into()]);
+        m.body.push(tokenize("ADD REG, Reg, #N", 2).unwrap());
+        let args = [tokenize("R1", 5).unwrap(), tokenize("#3", 5).unwrap()];
+        let lines = m.expand(&args, &[], 1);
+        let lines: Vec<String> = lines.iter().map(|l| render(l)).collect();
+        assert_eq!(lines, ["ADD R1, R1, #3"]);
+    }
+}
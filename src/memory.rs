@@ -0,0 +1,509 @@
+use wasm_bindgen::prelude::*;
+
+use crate::parser::parse_prefixed;
+use crate::timer::{TMCR, TMRR, TMSR};
+use crate::{Core, StopReason, MEMORY_SIZE};
+
+// Memory mapped device registers
+pub const KBSR: u16 = 0xFE00; // Keyboard status
+pub const KBDR: u16 = 0xFE02; // Keyboard data
+pub const DSR: u16 = 0xFE04; // Display status
+pub const DDR: u16 = 0xFE06; // Display data
+pub const MCR: u16 = 0xFFFE; // Machine control
+
+pub(crate) const READY: u16 = 1 << 15;
+pub(crate) const INTERRUPT_ENABLE: u16 = 1 << 14;
+const CLOCK_ENABLE: u16 = 1 << 15;
+// Device registers start there. They always count as initialized.
+pub(crate) const DEVICE_PAGE: u16 = 0xFE00;
+// User mode may only touch the words from there to the device page.
+const USER_SPACE: u16 = 0x3000;
+// Exception vector of user mode accesses to system space or to protected regions
+pub(crate) const ACV_VECTOR: u8 = 0x02;
+// Accesses kept by Core::uninitialized_accesses, the first ones being the telling ones.
+const MAX_ACCESSES: usize = 256;
+
+/// What to do when an instruction reads, or the PC reaches, a word that neither the loader
+/// nor a store has written.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UninitializedCheck {
+    #[default]
+    Off,
+    // Record the access and go on
+    Warn,
+    // Record the access and stop the run
+    Stop,
+}
+
+/// A word read by LD, LDI, LDR or STI, or fetched, before anything was written to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UninitializedAccess {
+    // Address of the instruction
+    pub pc: u16,
+    pub address: u16,
+    // Whether the word was fetched as an instruction rather than read as data
+    pub fetch: bool,
+}
+
+/// Restriction of a region of memory set by the host, on top of the system space and device
+/// page which user mode may never touch. Breaking it raises an access control violation,
+/// in supervisor mode too.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Protection {
+    ReadOnly,
+    NoExecute,
+    NoAccess,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Protection {
+    fn forbids(self, access: Access) -> bool {
+        match self {
+            Protection::ReadOnly => access == Access::Write,
+            Protection::NoExecute => access == Access::Execute,
+            Protection::NoAccess => true,
+        }
+    }
+}
+
+/// Words from start to end included, restricted by protection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Region {
+    start: u16,
+    end: u16,
+    protection: Protection,
+}
+
+/// Contents of memory and registers before the OS and the program are loaded. Programs
+/// counting on zeroed memory or registers fail deterministically under a fill or a seed.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PowerOn {
+    #[default]
+    Zero,
+    Fill(u16),
+    // Pseudo-random words from the seed, the same for the same seed
+    Random(u64),
+}
+
+impl PowerOn {
+    /// Parses zero, fill=<value> or random=<seed>.
+    pub fn parse(spec: &str) -> Result<PowerOn, String> {
+        match spec.split_once('=') {
+            None if spec == "zero" => Ok(PowerOn::Zero),
+            Some(("fill", value)) => parse_word(value).map(PowerOn::Fill),
+            Some(("random", seed)) => seed
+                .parse()
+                .map(PowerOn::Random)
+                .map_err(|_| format!("Invalid seed {}.", seed)),
+            _ => Err(format!(
+                "Invalid power-on state {}, expected zero, fill=<value> or random=<seed>.",
+                spec
+            )),
+        }
+    }
+}
+
+/// Parses a word written x3000, b101, #-1 or 12.
+pub fn parse_word(text: &str) -> Result<u16, String> {
+    let value = match parse_prefixed(text) {
+        Some(value) => value?,
+        None => text
+            .strip_prefix('#')
+            .unwrap_or(text)
+            .parse()
+            .map_err(|_| format!("Invalid number {}.", text))?,
+    };
+    match value {
+        -0x8000..=0xFFFF => Ok(value as u16),
+        _ => Err(format!("Number {} does not fit in a word.", text)),
+    }
+}
+
+// SplitMix64, good enough to scramble memory and small enough to need no dependency.
+struct SplitMix(u64);
+
+impl SplitMix {
+    fn next(&mut self) -> u16 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        (z ^ (z >> 31)) as u16
+    }
+}
+
+/// One bit per word of memory, set once the word is written.
+#[derive(Debug, Clone)]
+pub(crate) struct Shadow {
+    bits: Vec<u64>,
+}
+
+impl Shadow {
+    pub(crate) fn new() -> Self {
+        Shadow {
+            bits: vec![0; MEMORY_SIZE / 64],
+        }
+    }
+    pub(crate) fn mark(&mut self, address: u16) {
+        self.bits[address as usize / 64] |= 1 << (address % 64);
+    }
+    fn contains(&self, address: u16) -> bool {
+        address >= DEVICE_PAGE || self.bits[address as usize / 64] & 1 << (address % 64) != 0
+    }
+}
+
+impl Core {
+    // Memory read as seen by instructions, going through the devices.
+    pub(crate) fn read(&mut self, address: u16) -> u16 {
+        match address {
+            KBSR => {
+                self.poll_keyboard();
+                self.memory[KBSR as usize]
+            }
+            KBDR => {
+                self.poll_keyboard();
+                // Reading the data register acknowledges the key
+                self.memory[KBSR as usize] &= !READY;
+                self.memory[KBDR as usize]
+            }
+            // The display is always ready
+            DSR => READY,
+            TMSR => self.read_timer_status(),
+            _ => self.memory[address as usize],
+        }
+    }
+
+    // Memory write as seen by instructions, going through the devices.
+    pub(crate) fn write(&mut self, address: u16, value: u16) {
+        match address {
+            // Only the interrupt enable bit is writable
+            KBSR => {
+                let status = &mut self.memory[KBSR as usize];
+                *status = (*status & !INTERRUPT_ENABLE) | (value & INTERRUPT_ENABLE);
+            }
+            KBDR | DSR | TMSR => (),
+            TMCR | TMRR => self.write_timer(address, value),
+            DDR => {
+                self.memory[DDR as usize] = value;
+                self.console.write(value as u8);
+            }
+            _ => {
+                self.memory[address as usize] = value;
+                self.initialized.mark(address);
+                self.video_dirty |= self.is_video(address);
+            }
+        }
+    }
+
+    // Memory read by LD, LDI, LDR and STI, checked against the access rights and the shadow
+    // memory. Reads nothing once the instruction raised an exception.
+    pub(crate) fn load(&mut self, address: u16) -> u16 {
+        if self.exception.is_some() || !self.check_access(address, Access::Read) {
+            return 0;
+        }
+        self.check_initialized(address, false);
+        self.charge(address);
+        self.read(address)
+    }
+
+    // Memory write by ST, STI and STR, checked against the access rights.
+    pub(crate) fn store(&mut self, address: u16, value: u16) {
+        if self.exception.is_none() && self.check_access(address, Access::Write) {
+            self.charge(address);
+            self.write(address, value);
+        }
+    }
+
+    // Whether the current mode may access address. If not, an access control violation is
+    // raised once the instruction is over.
+    pub(crate) fn check_access(&mut self, address: u16, access: Access) -> bool {
+        let system = self.user_mode() && !(USER_SPACE..DEVICE_PAGE).contains(&address);
+        let protected = self.regions.iter().any(|region| {
+            (region.start..=region.end).contains(&address) && region.protection.forbids(access)
+        });
+        if system || protected {
+            self.exception = Some(ACV_VECTOR);
+        }
+        !system && !protected
+    }
+
+    // Records an access to address if it was never written and the check is enabled.
+    // Returns whether the run must stop.
+    pub(crate) fn check_initialized(&mut self, address: u16, fetch: bool) -> bool {
+        if self.uninitialized_check == UninitializedCheck::Off || self.initialized.contains(address)
+        {
+            return false;
+        }
+        let access = UninitializedAccess {
+            pc: self.pc,
+            address,
+            fetch,
+        };
+        if self.uninitialized_accesses.len() < MAX_ACCESSES
+            && !self.uninitialized_accesses.contains(&access)
+        {
+            self.uninitialized_accesses.push(access);
+        }
+        if self.uninitialized_check == UninitializedCheck::Stop {
+            self.stop = Some(StopReason::Uninitialized(access));
+            return true;
+        }
+        false
+    }
+
+    // Fills memory below the device registers and the registers but the stack pointer. The
+    // words stay uninitialized for the shadow memory.
+    pub(crate) fn power_on(&mut self, state: PowerOn) {
+        self.power_on = state;
+        let mut next: Box<dyn FnMut() -> u16> = match state {
+            PowerOn::Zero => return,
+            PowerOn::Fill(value) => Box::new(move || value),
+            PowerOn::Random(seed) => {
+                let mut rng = SplitMix(seed);
+                Box::new(move || rng.next())
+            }
+        };
+        for word in &mut self.memory[..DEVICE_PAGE as usize] {
+            *word = next();
+        }
+        for (r, register) in self.registers.iter_mut().enumerate() {
+            if r != 6 {
+                *register = next();
+            }
+        }
+    }
+
+    pub fn power_on_state(&self) -> PowerOn {
+        self.power_on
+    }
+
+    // Uninitialized words accessed since the check was enabled, without repetitions.
+    pub fn uninitialized_accesses(&self) -> &[UninitializedAccess] {
+        &self.uninitialized_accesses
+    }
+
+    pub(crate) fn poll_keyboard(&mut self) {
+        if self.memory[KBSR as usize] & READY != 0 {
+            return;
+        }
+        if let Some(byte) = self.next_input() {
+            self.memory[KBDR as usize] = byte as u16;
+            self.memory[KBSR as usize] |= READY;
+        }
+    }
+}
+
+#[wasm_bindgen]
+impl Core {
+    // HALT clears the clock enable bit of MCR
+    pub fn halted(&self) -> bool {
+        self.memory[MCR as usize] & CLOCK_ENABLE == 0
+    }
+
+    // Enables reporting reads of words written neither by the loader nor by a store. Words
+    // written before the check was enabled count as written.
+    pub fn set_uninitialized_check(&mut self, check: UninitializedCheck) {
+        self.uninitialized_check = check;
+    }
+    pub fn is_initialized(&self, address: u16) -> bool {
+        self.initialized.contains(address)
+    }
+
+    // Restricts the words from start to end included. Later regions add to earlier ones.
+    pub fn protect(&mut self, start: u16, end: u16, protection: Protection) {
+        self.regions.push(Region {
+            start,
+            end,
+            protection,
+        });
+    }
+    pub fn clear_protections(&mut self) {
+        self.regions.clear();
+    }
+
+    // Seed of a random power-on state, to reproduce the run.
+    pub fn power_on_seed(&self) -> Option<u64> {
+        match self.power_on {
+            PowerOn::Random(seed) => Some(seed),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CoreConfig;
+    use crate::console::BufferConsole;
+    use crate::StopReason;
+
+    #[test]
+    fn test_keyboard() {
+        let console = BufferConsole::with_input(b"hi");
+        let mut c = Core::new();
+        c.set_console(Box::new(console.clone()));
+        assert_eq!(c.read(KBSR), READY);
+        // Status stays ready until the data is read
+        assert_eq!(c.read(KBSR), READY);
+        assert_eq!(c.read(KBDR), b'h' as u16);
+        assert_eq!(c.read(KBDR), b'i' as u16);
+        assert_eq!(c.read(KBSR), 0);
+        c.write(KBSR, 0xFFFF);
+        assert_eq!(c.read(KBSR), INTERRUPT_ENABLE);
+    }
+
+    #[test]
+    fn test_uninitialized() {
+        // LD R1, x3003 then LDR R2, R1, #0
+        let mut c = Core::new();
+        c.set_uninitialized_check(UninitializedCheck::Warn);
+        c.load_obj(&[0x3000, 0x2202, 0x6440, 0x0000, 0x3000])
+            .unwrap();
+        c.pc = 0x3000;
+        assert!(c.is_initialized(0x3003));
+        assert!(!c.is_initialized(0x3004));
+        assert!(c.is_initialized(KBSR));
+        c.run(2);
+        assert!(c.uninitialized_accesses().is_empty());
+        c.write(0x3004, 1);
+        assert!(c.is_initialized(0x3004));
+
+        // Without the pointer, LD reads an uninitialized word and stops after it
+        let mut c = Core::new();
+        c.load_obj(&[0x3000, 0x2202, 0x6440]).unwrap();
+        c.pc = 0x3000;
+        c.set_uninitialized_check(UninitializedCheck::Stop);
+        let read = UninitializedAccess {
+            pc: 0x3000,
+            address: 0x3003,
+            fetch: false,
+        };
+        assert_eq!(c.run(10), StopReason::Uninitialized(read));
+        assert_eq!(c.pc, 0x3001);
+        // Warnings go on, through LDR from x0000, below the trap table, and the fetch of x3002
+        c.set_uninitialized_check(UninitializedCheck::Warn);
+        assert_eq!(c.run(2), StopReason::StepLimit);
+        let null = UninitializedAccess {
+            pc: 0x3001,
+            address: 0x0000,
+            fetch: false,
+        };
+        let fetch = UninitializedAccess {
+            pc: 0x3002,
+            address: 0x3002,
+            fetch: true,
+        };
+        assert_eq!(c.uninitialized_accesses(), [read, null, fetch]);
+        // Fetches stop before executing
+        c.set_uninitialized_check(UninitializedCheck::Stop);
+        c.pc = 0x3002;
+        assert_eq!(c.run(10), StopReason::Uninitialized(fetch));
+        assert_eq!(c.pc, 0x3002);
+    }
+
+    // Runs a program from x3000 until it halts.
+    fn run_user(config: CoreConfig, program: &[u16], protect: &[(u16, u16, Protection)]) -> Core {
+        let mut c = Core::with_config(&config.pc(0x3000));
+        c.set_console(Box::new(BufferConsole::new()));
+        c.load_obj(program).unwrap();
+        for &(start, end, protection) in protect {
+            c.protect(start, end, protection);
+        }
+        assert_eq!(c.run(10_000), StopReason::Halted);
+        c
+    }
+
+    #[test]
+    fn test_access_control() {
+        let console = BufferConsole::new();
+        let program = [
+            0x3000, //
+            0x2003, // LD R0, #3
+            0xF021, // OUT
+            0x23FC, // LD R1, x2FFF
+            0xF025, // HALT
+            b'A' as u16,
+        ];
+        let mut c = Core::with_config(&CoreConfig::new().pc(0x3000).user_mode(true));
+        c.set_console(Box::new(console.clone()));
+        c.load_obj(&program).unwrap();
+        assert_eq!(c.run(10_000), StopReason::Halted);
+        // The trap routine ran in supervisor mode, the load of system space was undone
+        assert_eq!(
+            console.output(),
+            b"A\n--- Access control violation ---\n--- Halting the processor ---\n"
+        );
+        assert!(!c.user_mode());
+        // The faulting load pushed the user mode PSR and the PC following it
+        assert_eq!(c.memory[0x2FFE..0x3000], [0x3003, 0x8001]);
+
+        // Supervisor mode is only bound by the regions set by the host
+        let store = [0x3000, 0x3004, 0xF025]; // ST R0, x3005
+        let c = run_user(CoreConfig::new(), &store, &[]);
+        assert!(c.is_initialized(0x3005));
+        let c = run_user(
+            CoreConfig::new(),
+            &store,
+            &[(0x3005, 0x3005, Protection::ReadOnly)],
+        );
+        assert!(!c.is_initialized(0x3005));
+        assert_eq!(c.memory[0x2FFE..0x3000], [0x3001, 0x0000]);
+        let jump = [0x3000, 0x4C00]; // JSR x2C01
+        let c = run_user(
+            CoreConfig::new(),
+            &jump,
+            &[(0x0000, 0x2FFF, Protection::NoExecute)],
+        );
+        // The word that could not be fetched is pushed
+        assert_eq!(c.memory[0x2FFE..0x3000], [0x2C01, 0x0000]);
+
+        // RTI is privileged
+        let c = run_user(CoreConfig::new().user_mode(true), &[0x3000, 0x8000], &[]);
+        assert_eq!(c.memory[0x2FFE..0x3000], [0x3001, 0x8000]);
+    }
+
+    #[test]
+    fn test_display() {
+        let console = BufferConsole::new();
+        let mut c = Core::new();
+        c.set_console(Box::new(console.clone()));
+        assert_eq!(c.read(DSR), READY);
+        c.write(DDR, b'A' as u16);
+        assert_eq!(console.output(), b"A");
+    }
+
+    #[test]
+    fn test_power_on() {
+        let c = Core::filled(0xDEAD);
+        assert_eq!(c.registers[0], 0xDEAD);
+        assert_eq!(c.registers[6], 0x3000);
+        assert_eq!(c.memory[0x3000], 0xDEAD);
+        assert!(!c.is_initialized(0x3000));
+        // The OS and the devices are loaded over the fill
+        assert_eq!(c.memory[0x1000], 0xA05A);
+        assert!(!c.halted());
+        assert_eq!(c.power_on_seed(), None);
+
+        let (a, b) = (Core::randomized(42), Core::randomized(42));
+        assert_eq!(a.power_on_seed(), Some(42));
+        assert_eq!(a.registers, b.registers);
+        assert_eq!(a.memory, b.memory);
+        assert_ne!(a.memory, Core::randomized(43).memory);
+        assert_ne!(a.memory[0x3000], a.memory[0x3001]);
+
+        assert_eq!(PowerOn::parse("zero"), Ok(PowerOn::Zero));
+        assert_eq!(PowerOn::parse("fill=x7FFF"), Ok(PowerOn::Fill(0x7FFF)));
+        assert_eq!(PowerOn::parse("fill=#-1"), Ok(PowerOn::Fill(0xFFFF)));
+        assert_eq!(PowerOn::parse("random=12"), Ok(PowerOn::Random(12)));
+        assert!(PowerOn::parse("fill=x10000").is_err());
+        assert!(PowerOn::parse("random").is_err());
+    }
+}
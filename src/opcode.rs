@@ -41,9 +41,9 @@ impl From<u16> for OpCode {
     }
 }
 
-impl Into<u16> for OpCode {
-    fn into(self) -> u16 {
-        match self {
+impl From<OpCode> for u16 {
+    fn from(value: OpCode) -> Self {
+        match value {
             OpCode::ADD => 0b0001_0000_0000_0000,
             OpCode::AND => 0b0101_0000_0000_0000,
             OpCode::BR => 0b0000_0000_0000_0000,
@@ -0,0 +1,317 @@
+// Minimal operating system bundled with the core. It only provides the
+// console trap routines (GETC, OUT, PUTS, IN, PUTSP and HALT) which talk to
+// the memory mapped keyboard and display registers, exception handlers
+// which print what went wrong and halt, and a keyboard interrupt handler
+// which echoes the key typed.
+// Routines save the registers they use, R7 excepted as TRAP overwrites it, and
+// return with RET. From user mode, TRAP pushes the PSR and the PC on the supervisor
+// stack and points R7 at TRAP_RETURN, whose RTI goes back to the program.
+
+pub const TRAP_TABLE: [u16; 7] = [
+    0x0020, // .ORIG x0020
+    0x1000, // x20 GETC
+    0x1004, // x21 OUT
+    0x100A, // x22 PUTS
+    0x1019, // x23 IN
+    0x1024, // x24 PUTSP
+    0x1046, // x25 HALT
+];
+
+// Where the trap routines entered from user mode return to
+pub const TRAP_RETURN: u16 = 0x1099;
+
+pub const OS_IMAGE: [u16; 155] = [
+    0x1000, // .ORIG x1000
+    0xA05A, // x1000 GETC LDI R0, OS_KBSR
+    0x07FE, // x1001 BRzp GETC
+    0xA059, // x1002 LDI R0, OS_KBDR
+    0xC1C0, // x1003 RET
+    0x3248, // x1004 OUT ST R1, OUT_R1
+    0xA257, // x1005 OUT_W LDI R1, OS_DSR
+    0x07FE, // x1006 BRzp OUT_W
+    0xB056, // x1007 STI R0, OS_DDR
+    0x2244, // x1008 LD R1, OUT_R1
+    0xC1C0, // x1009 RET
+    0x3043, // x100A PUTS ST R0, PUTS_R0
+    0x3243, // x100B ST R1, PUTS_R1
+    0x3E43, // x100C ST R7, PUTS_R7
+    0x6200, // x100D PUTS_L LDR R1, R0, #0
+    0x0406, // x100E BRz PUTS_E
+    0x3041, // x100F ST R0, PUTS_P
+    0x1060, // x1010 ADD R0, R1, #0
+    0xF021, // x1011 TRAP x21
+    0x203E, // x1012 LD R0, PUTS_P
+    0x1021, // x1013 ADD R0, R0, #1
+    0x0FF8, // x1014 BRnzp PUTS_L
+    0x2038, // x1015 PUTS_E LD R0, PUTS_R0
+    0x2238, // x1016 LD R1, PUTS_R1
+    0x2E38, // x1017 LD R7, PUTS_R7
+    0xC1C0, // x1018 RET
+    0x3E39, // x1019 IN ST R7, IN_R7
+    0xE049, // x101A LEA R0, IN_MSG
+    0xF022, // x101B TRAP x22
+    0xF020, // x101C TRAP x20
+    0xF021, // x101D TRAP x21
+    0x3033, // x101E ST R0, IN_R0
+    0x2043, // x101F LD R0, OS_LF
+    0xF021, // x1020 TRAP x21
+    0x2030, // x1021 LD R0, IN_R0
+    0x2E30, // x1022 LD R7, IN_R7
+    0xC1C0, // x1023 RET
+    0x302F, // x1024 PUTSP ST R0, SP_R0
+    0x322F, // x1025 ST R1, SP_R1
+    0x342F, // x1026 ST R2, SP_R2
+    0x362F, // x1027 ST R3, SP_R3
+    0x382F, // x1028 ST R4, SP_R4
+    0x3E2F, // x1029 ST R7, SP_R7
+    0x6200, // x102A SP_L LDR R1, R0, #0
+    0x0413, // x102B BRz SP_E
+    0x302D, // x102C ST R0, SP_P
+    0x2033, // x102D LD R0, OS_LOW
+    0x5040, // x102E AND R0, R1, R0
+    0xF021, // x102F TRAP x21
+    0x5020, // x1030 AND R0, R0, #0
+    0x1421, // x1031 ADD R2, R0, #1
+    0x262F, // x1032 LD R3, OS_HIGH
+    0x5843, // x1033 SP_B AND R4, R1, R3
+    0x0401, // x1034 BRz SP_S
+    0x1002, // x1035 ADD R0, R0, R2
+    0x1482, // x1036 SP_S ADD R2, R2, R2
+    0x16C3, // x1037 ADD R3, R3, R3
+    0x0BFA, // x1038 BRnp SP_B
+    0x1020, // x1039 ADD R0, R0, #0
+    0x0404, // x103A BRz SP_E
+    0xF021, // x103B TRAP x21
+    0x201D, // x103C LD R0, SP_P
+    0x1021, // x103D ADD R0, R0, #1
+    0x0FEB, // x103E BRnzp SP_L
+    0x2014, // x103F SP_E LD R0, SP_R0
+    0x2214, // x1040 LD R1, SP_R1
+    0x2414, // x1041 LD R2, SP_R2
+    0x2614, // x1042 LD R3, SP_R3
+    0x2814, // x1043 LD R4, SP_R4
+    0x2E14, // x1044 LD R7, SP_R7
+    0xC1C0, // x1045 RET
+    0xE032, // x1046 HALT LEA R0, HALT_MSG
+    0xF022, // x1047 TRAP x22
+    0xA016, // x1048 LDI R0, OS_MCR
+    0x2216, // x1049 LD R1, OS_CLK
+    0x5001, // x104A AND R0, R0, R1
+    0xB013, // x104B STI R0, OS_MCR
+    0x0FF9, // x104C BRnzp HALT
+    0x0000, // x104D OUT_R1 .FILL x0000
+    0x0000, // x104E PUTS_R0 .FILL x0000
+    0x0000, // x104F PUTS_R1 .FILL x0000
+    0x0000, // x1050 PUTS_R7 .FILL x0000
+    0x0000, // x1051 PUTS_P .FILL x0000
+    0x0000, // x1052 IN_R0 .FILL x0000
+    0x0000, // x1053 IN_R7 .FILL x0000
+    0x0000, // x1054 SP_R0 .FILL x0000
+    0x0000, // x1055 SP_R1 .FILL x0000
+    0x0000, // x1056 SP_R2 .FILL x0000
+    0x0000, // x1057 SP_R3 .FILL x0000
+    0x0000, // x1058 SP_R4 .FILL x0000
+    0x0000, // x1059 SP_R7 .FILL x0000
+    0x0000, // x105A SP_P .FILL x0000
+    0xFE00, // x105B OS_KBSR .FILL xFE00
+    0xFE02, // x105C OS_KBDR .FILL xFE02
+    0xFE04, // x105D OS_DSR .FILL xFE04
+    0xFE06, // x105E OS_DDR .FILL xFE06
+    0xFFFE, // x105F OS_MCR .FILL xFFFE
+    0x7FFF, // x1060 OS_CLK .FILL x7FFF
+    0x00FF, // x1061 OS_LOW .FILL x00FF
+    0x0100, // x1062 OS_HIGH .FILL x0100
+    0x000A, // x1063 OS_LF .FILL x000A
+    // x1064 IN_MSG .STRINGZ "\nInput a character> "
+    0x000A, 0x0049, 0x006E, 0x0070, 0x0075, 0x0074, 0x0020, 0x0061, 0x0020, 0x0063, 0x0068, 0x0061,
+    0x0072, 0x0061, 0x0063, 0x0074, 0x0065, 0x0072, 0x003E, 0x0020, 0x0000,
+    // x1079 HALT_MSG .STRINGZ "\n--- Halting the processor ---\n"
+    0x000A, 0x002D, 0x002D, 0x002D, 0x0020, 0x0048, 0x0061, 0x006C, 0x0074, 0x0069, 0x006E, 0x0067,
+    0x0020, 0x0074, 0x0068, 0x0065, 0x0020, 0x0070, 0x0072, 0x006F, 0x0063, 0x0065, 0x0073, 0x0073,
+    0x006F, 0x0072, 0x0020, 0x002D, 0x002D, 0x002D, 0x000A, 0x0000,
+    0x8000, // x1099 TRAP_RET RTI
+];
+
+pub const EXCEPTION_TABLE: [u16; 4] = [
+    0x0100, // .ORIG x0100
+    0x1100, // x00 privilege mode violation
+    0x1102, // x01 illegal opcode
+    0x1104, // x02 access control violation
+];
+
+pub const EXCEPTION_IMAGE: [u16; 100] = [
+    0x1100, // .ORIG x1100
+    0xE006, // x1100 PRIV LEA R0, PRIV_MSG
+    0x0E03, // x1101 BRnzp EXC
+    0xE026, // x1102 ILL LEA R0, ILL_MSG
+    0x0E01, // x1103 BRnzp EXC
+    0xE03C, // x1104 ACV LEA R0, ACV_MSG
+    0xF022, // x1105 EXC TRAP x22
+    0xF025, // x1106 TRAP x25
+    // x1107 PRIV_MSG .STRINGZ "\n--- Privilege mode violation ---"
+    0x000A, 0x002D, 0x002D, 0x002D, 0x0020, 0x0050, 0x0072, 0x0069, 0x0076, 0x0069, 0x006C, 0x0065,
+    0x0067, 0x0065, 0x0020, 0x006D, 0x006F, 0x0064, 0x0065, 0x0020, 0x0076, 0x0069, 0x006F, 0x006C,
+    0x0061, 0x0074, 0x0069, 0x006F, 0x006E, 0x0020, 0x002D, 0x002D, 0x002D, 0x0000,
+    // x1129 ILL_MSG .STRINGZ "\n--- Illegal opcode ---"
+    0x000A, 0x002D, 0x002D, 0x002D, 0x0020, 0x0049, 0x006C, 0x006C, 0x0065, 0x0067, 0x0061, 0x006C,
+    0x0020, 0x006F, 0x0070, 0x0063, 0x006F, 0x0064, 0x0065, 0x0020, 0x002D, 0x002D, 0x002D, 0x0000,
+    // x1141 ACV_MSG .STRINGZ "\n--- Access control violation ---"
+    0x000A, 0x002D, 0x002D, 0x002D, 0x0020, 0x0041, 0x0063, 0x0063, 0x0065, 0x0073, 0x0073, 0x0020,
+    0x0063, 0x006F, 0x006E, 0x0074, 0x0072, 0x006F, 0x006C, 0x0020, 0x0076, 0x0069, 0x006F, 0x006C,
+    0x0061, 0x0074, 0x0069, 0x006F, 0x006E, 0x0020, 0x002D, 0x002D, 0x002D, 0x0000,
+];
+
+pub const INTERRUPT_TABLE: [u16; 2] = [
+    0x0180, // .ORIG x0180
+    0x1200, // x80 keyboard
+];
+
+pub const KEYBOARD_IMAGE: [u16; 15] = [
+    0x1200, // .ORIG x1200
+    0x3008, // x1200 KBISR ST R0, KB_R0
+    0x3208, // x1201 ST R1, KB_R1
+    0xA008, // x1202 LDI R0, KB_KBDR
+    0xA208, // x1203 KB_W LDI R1, KB_DSR
+    0x07FE, // x1204 BRzp KB_W
+    0xB007, // x1205 STI R0, KB_DDR
+    0x2002, // x1206 LD R0, KB_R0
+    0x2202, // x1207 LD R1, KB_R1
+    0x8000, // x1208 RTI
+    0x0000, // x1209 KB_R0 .FILL x0000
+    0x0000, // x120A KB_R1 .FILL x0000
+    0xFE02, // x120B KB_KBDR .FILL xFE02
+    0xFE04, // x120C KB_DSR .FILL xFE04
+    0xFE06, // x120D KB_DDR .FILL xFE06
+];
+
+#[cfg(test)]
+mod tests {
+    use crate::config::CoreConfig;
+    use crate::console::BufferConsole;
+    use crate::{Core, StopReason};
+
+    fn run(program: &[u16], input: &[u8]) -> Vec<u8> {
+        let console = BufferConsole::with_input(input);
+        let mut c = Core::new();
+        c.set_console(Box::new(console.clone()));
+        c.load_obj(program).unwrap();
+        assert_eq!(c.run(100_000), StopReason::Halted);
+        console.output()
+    }
+
+    #[test]
+    fn test_puts() {
+        let program = [
+            0x0200, //
+            0xE002, // LEA R0, #2
+            0xF022, // PUTS
+            0xF025, // HALT
+            b'h' as u16,
+            b'i' as u16,
+            0,
+        ];
+        assert_eq!(run(&program, b""), b"hi\n--- Halting the processor ---\n");
+    }
+
+    #[test]
+    fn test_putsp() {
+        let program = [
+            0x0200, //
+            0xE002, // LEA R0, #2
+            0xF024, // PUTSP
+            0xF025, // HALT
+            0x6968, // "hi"
+            0x0021, // "!"
+            0,
+        ];
+        assert!(run(&program, b"").starts_with(b"hi!\n---"));
+    }
+
+    #[test]
+    fn test_getc_out() {
+        let program = [
+            0x0200, //
+            0xF020, // GETC
+            0xF021, // OUT
+            0xF020, // GETC
+            0xF021, // OUT
+            0xF025, // HALT
+        ];
+        assert!(run(&program, b"ok").starts_with(b"ok\n---"));
+    }
+
+    #[test]
+    fn test_in() {
+        let program = [
+            0x0200, //
+            0xF023, // IN
+            0xF021, // OUT
+            0xF025, // HALT
+        ];
+        assert!(run(&program, b"y").starts_with(b"\nInput a character> y\ny\n---"));
+    }
+
+    #[test]
+    fn test_supervisor_traps() {
+        // In supervisor mode, TRAP leaves the stack alone
+        let program = [
+            0x0200, //
+            0x5DA0, // AND R6, R6, #0
+            0x2003, // LD R0, CH
+            0xF021, // OUT
+            0xF021, // OUT
+            0xF025, // HALT
+            0x0041, // CH
+        ];
+        assert!(run(&program, b"").starts_with(b"AA\n---"));
+    }
+
+    #[test]
+    fn test_user_traps() {
+        let program = [
+            0x3000, //
+            0xE004, // LEA R0, #4
+            0xF022, // PUTS
+            0x1260, // ADD R1, R1, #0
+            0x1BA0, // ADD R5, R6, #0
+            0xF025, // HALT
+            0x006F, // "o"
+            0x006B, // "k"
+            0,
+        ];
+        let console = BufferConsole::new();
+        let mut c = Core::with_config(&CoreConfig::new().pc(0x3000).user_mode(true));
+        c.set_console(Box::new(console.clone()));
+        c.load_obj(&program).unwrap();
+        c.run(100_000);
+        assert!(console.output().starts_with(b"ok\n---"));
+        // PUTS went back to user mode and its stack, then HALT pushed the PC and the PSR
+        assert_eq!(c.register(5), 0xFE00);
+        assert_eq!(c.memory[0x2FFE..0x3000], [0x3005, 0x8004]);
+        // Only the frame of HALT is left
+        let sites: Vec<u16> = c.call_stack().iter().map(|f| f.call_site).collect();
+        assert_eq!(sites, [0x3004]);
+    }
+
+    #[test]
+    fn test_keyboard_interrupt() {
+        // Enables keyboard interrupts then spins, the OS handler echoing the keys
+        let program = [
+            0x0200, //
+            0x2202, // LD R1, IE
+            0xB202, // STI R1, KBSR
+            0x0FFF, // BRnzp -1
+            0x4000, // IE
+            0xFE00, // KBSR
+        ];
+        let console = BufferConsole::new();
+        let mut c = Core::new();
+        c.set_console(Box::new(console.clone()));
+        c.load_obj(&program).unwrap();
+        assert_eq!(c.run(100), StopReason::StepLimit);
+        console.push_input(b"ok");
+        assert_eq!(c.run(100), StopReason::StepLimit);
+        assert_eq!(console.output(), b"ok");
+        assert_eq!((c.pc(), c.priority(), c.register(1)), (0x0202, 0, 0x4000));
+    }
+}
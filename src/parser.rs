@@ -1,14 +1,11 @@
-use nom::{
-    branch::alt,
-    bytes::complete::{tag, take_while1},
-    character::complete::{char, space0, space1},
-    combinator::{map, opt, value},
-    multi::separated_list1,
-    sequence::{delimited, pair, preceded, terminated, tuple},
-    AsChar, IResult,
-};
-
-// ParsedOpCode Enum (no change)
+use std::collections::HashMap;
+
+use crate::diagnostic::Diagnostic;
+use crate::expr::{wrap16, BinaryOp, Expr};
+use crate::lexer::{render, tokenize, Token, TokenKind};
+use crate::macros::{Macro, MAX_MACRO_DEPTH};
+use crate::source::{SourceFile, SourceProvider};
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum ParsedOpCode {
     ADD,
@@ -23,6 +20,7 @@ pub enum ParsedOpCode {
     JMP,
     RET,
     JSR,
+    JSRR,
     LD,
     LDI,
     LDR,
@@ -33,64 +31,56 @@ pub enum ParsedOpCode {
     STI,
     STR,
     TRAP,
-    UNKNOWN,
+    // Trap aliases
+    GETC,
+    OUT,
+    PUTS,
+    IN,
+    PUTSP,
+    HALT,
+    // Directives
+    FILL,
+    BLKW,
+    STRINGZ,
+    // Symbolic constants, operands being the name and the value
+    EQU,
+    SET,
+    // Symbols shared between modules, operands being their names
+    GLOBAL,
+    EXTERNAL,
+    // Emits the literals used since the previous pool
+    LTORG,
 }
 
 // Operand Types
 #[derive(Clone, Debug, PartialEq)]
 pub enum OperandTypes {
     Register(u8),                         // Register type (R followed by u8)
-    Immediate { value: u16, sign: bool }, // Immediate value (hexadecimal)
+    Immediate { value: u16, sign: bool }, // Immediate value (decimal, hex, binary or character)
     Label(String),
+    String(String),
+    // Expression using symbols, evaluated by the assembler
+    Expression(Expr),
+    // =value, a constant the assembler places in a literal pool
+    Literal(Expr),
 }
 
 // Operand struct
 #[derive(Clone, Debug)]
 pub struct Operand {
     pub operand_type: OperandTypes,
+    // Column of the first character of the operand
+    pub column: usize,
 }
 
-impl Operand {
-    // From function to convert operand type and value into Operand struct
-    pub fn from(op_type: &str, value: &str) -> Result<Self, String> {
-        match op_type {
-            "R" => value
-                .parse::<u8>()
-                .map(|reg| Self {
-                    operand_type: OperandTypes::Register(reg),
-                })
-                .map_err(|e| format!("Invalid register value: {} - {}", value, e)),
-            "#" => value
-                .parse::<i16>()
-                .map(|imm| Self {
-                    operand_type: OperandTypes::Immediate {
-                        value: imm as u16,
-                        sign: value.contains("-"),
-                    },
-                })
-                .map_err(|e| format!("Invalid immediate decimal value: {} - {}", value, e)),
-            "x" => i16::from_str_radix(value.trim(), 16)
-                .map(|imm| Self {
-                    operand_type: OperandTypes::Immediate {
-                        value: imm as u16,
-                        sign: value.contains("-"),
-                    },
-                })
-                .map_err(|e| format!("Invalid immediate hexadecimal value: {} - {}", value, e)),
-            "LABEL" => Ok(Self {
-                operand_type: OperandTypes::Label(value.to_string()),
-            }),
-            _ => Err(format!("Unknown operand type: {}", op_type)),
-        }
-    }
-}
-
-// Parsed Line struct (no change)
+// Parsed Line struct
 #[derive(Clone, Debug)]
 pub struct ParsedLine {
     pub label: Option<String>,
     pub opcode: ParsedOpCode,
     pub operands: Vec<Operand>,
+    // Text of the line when it comes from a macro expansion
+    pub expansion: Option<String>,
 }
 
 impl ParsedLine {
@@ -99,173 +89,1211 @@ impl ParsedLine {
             label,
             opcode,
             operands,
+            expansion: None,
+        }
+    }
+}
+
+// Branch opcode from the condition flags following BR, in any order.
+fn parse_branch(flags: &str) -> Option<ParsedOpCode> {
+    let (mut n, mut z, mut p) = (false, false, false);
+    for c in flags.chars() {
+        let flag = match c {
+            'n' | 'N' => &mut n,
+            'z' | 'Z' => &mut z,
+            'p' | 'P' => &mut p,
+            _ => return None,
+        };
+        if *flag {
+            return None;
+        }
+        *flag = true;
+    }
+    Some(match (n, z, p) {
+        (false, false, false) | (true, true, true) => ParsedOpCode::BR,
+        (false, true, false) => ParsedOpCode::BRz,
+        (false, false, true) => ParsedOpCode::BRp,
+        (true, false, false) => ParsedOpCode::BRn,
+        (false, true, true) => ParsedOpCode::BRzp,
+        (true, true, false) => ParsedOpCode::BRzn,
+        (true, false, true) => ParsedOpCode::BRpn,
+    })
+}
+
+// Opcodes are case insensitive.
+pub fn parse_opcode(word: &str) -> Option<ParsedOpCode> {
+    let upper = word.to_ascii_uppercase();
+    Some(match upper.as_str() {
+        "ADD" => ParsedOpCode::ADD,
+        "AND" => ParsedOpCode::AND,
+        "JMP" => ParsedOpCode::JMP,
+        "RET" => ParsedOpCode::RET,
+        "JSR" => ParsedOpCode::JSR,
+        "JSRR" => ParsedOpCode::JSRR,
+        "LD" => ParsedOpCode::LD,
+        "LDI" => ParsedOpCode::LDI,
+        "LDR" => ParsedOpCode::LDR,
+        "LEA" => ParsedOpCode::LEA,
+        "NOT" => ParsedOpCode::NOT,
+        "RTI" => ParsedOpCode::RTI,
+        "ST" => ParsedOpCode::ST,
+        "STI" => ParsedOpCode::STI,
+        "STR" => ParsedOpCode::STR,
+        "TRAP" => ParsedOpCode::TRAP,
+        "GETC" => ParsedOpCode::GETC,
+        "OUT" => ParsedOpCode::OUT,
+        "PUTS" => ParsedOpCode::PUTS,
+        "IN" => ParsedOpCode::IN,
+        "PUTSP" => ParsedOpCode::PUTSP,
+        "HALT" => ParsedOpCode::HALT,
+        _ => return upper.strip_prefix("BR").and_then(parse_branch),
+    })
+}
+
+fn parse_directive(name: &str) -> Option<ParsedOpCode> {
+    match name {
+        "FILL" => Some(ParsedOpCode::FILL),
+        "BLKW" => Some(ParsedOpCode::BLKW),
+        "STRINGZ" => Some(ParsedOpCode::STRINGZ),
+        "EQU" => Some(ParsedOpCode::EQU),
+        "SET" => Some(ParsedOpCode::SET),
+        "GLOBAL" => Some(ParsedOpCode::GLOBAL),
+        "EXTERNAL" => Some(ParsedOpCode::EXTERNAL),
+        "LTORG" => Some(ParsedOpCode::LTORG),
+        _ => None,
+    }
+}
+
+// R0 to R7, in either case.
+pub(crate) fn parse_register(word: &str) -> Option<u8> {
+    match word.as_bytes() {
+        [b'r' | b'R', digit @ b'0'..=b'7'] => Some(digit - b'0'),
+        _ => None,
+    }
+}
+
+fn immediate(value: i32, line: usize, column: usize) -> Result<OperandTypes, Diagnostic> {
+    if !(i16::MIN as i32..=u16::MAX as i32).contains(&value) {
+        return Err(Diagnostic::error(
+            line,
+            column,
+            format!("Value {} does not fit in 16 bits.", value),
+        ));
+    }
+    Ok(OperandTypes::Immediate {
+        value: value as u16,
+        sign: value < 0,
+    })
+}
+
+// x or b prefixed literal (either case). None if the word is not one.
+pub(crate) fn parse_prefixed(word: &str) -> Option<Result<i32, String>> {
+    let mut chars = word.chars();
+    let radix = match chars.next()? {
+        'x' | 'X' => 16,
+        'b' | 'B' => 2,
+        _ => return None,
+    };
+    let digits = chars.as_str();
+    let magnitude = digits.strip_prefix('-').unwrap_or(digits);
+    if magnitude.is_empty() || !magnitude.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    Some(i32::from_str_radix(digits, radix).map_err(|_| format!("Number {} is too large.", word)))
+}
+
+// Symbol names follow lc3as: a letter or an underscore, then letters, digits and underscores.
+fn is_symbol(word: &str) -> bool {
+    let mut chars = word.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+enum Word {
+    Register(u8),
+    Number(i32),
+    Symbol,
+}
+
+// Registers come first, then numbers, then symbols. A word is a number only if the whole
+// word is a valid literal: xCAFE and b101 are numbers while xCount and b12 are symbols.
+fn classify(word: &str) -> Result<Word, String> {
+    if let Some(reg) = parse_register(word) {
+        return Ok(Word::Register(reg));
+    }
+    if let Some(number) = parse_prefixed(word) {
+        return number.map(Word::Number);
+    }
+    if is_symbol(word) {
+        return Ok(Word::Symbol);
+    }
+    Err(format!(
+        "{} is neither a valid number nor a valid label.",
+        word
+    ))
+}
+
+fn operand(token: &Token, line: usize) -> Result<Operand, Diagnostic> {
+    let column = token.column;
+    let operand_type = match &token.kind {
+        TokenKind::Word(word) => match classify(word) {
+            Ok(Word::Register(reg)) => OperandTypes::Register(reg),
+            Ok(Word::Number(value)) => immediate(value, line, column)?,
+            Ok(Word::Symbol) => OperandTypes::Label(word.clone()),
+            Err(e) => return Err(Diagnostic::error(line, column, e)),
+        },
+        TokenKind::Decimal(value) => immediate(*value, line, column)?,
+        TokenKind::Char(c) => immediate(*c as i32, line, column)?,
+        TokenKind::Str(s) => OperandTypes::String(s.clone()),
+        _ => {
+            return Err(Diagnostic::error(line, column, "Expected an operand."));
+        }
+    };
+    Ok(Operand {
+        operand_type,
+        column,
+    })
+}
+
+// Recursive descent parser of constant expressions. Each function parses from tokens[*pos].
+struct ExprParser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+    line: usize,
+}
+
+impl ExprParser<'_> {
+    fn error(&self, message: &str) -> Diagnostic {
+        let column = match self.tokens.get(self.pos) {
+            Some(token) => token.column,
+            None => self.tokens.last().map_or(1, |t| t.column),
+        };
+        Diagnostic::error(self.line, column, message)
+    }
+
+    fn next_if(&mut self, kinds: &[TokenKind]) -> Option<TokenKind> {
+        let token = self.tokens.get(self.pos)?;
+        if kinds.contains(&token.kind) {
+            self.pos += 1;
+            return Some(token.kind.clone());
+        }
+        None
+    }
+
+    // sum := product (('+' | '-') product)*
+    fn sum(&mut self) -> Result<Expr, Diagnostic> {
+        let mut left = self.product()?;
+        while let Some(op) = self.next_if(&[TokenKind::Plus, TokenKind::Minus]) {
+            let op = if op == TokenKind::Plus {
+                BinaryOp::Add
+            } else {
+                BinaryOp::Sub
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.product()?));
+        }
+        Ok(left)
+    }
+
+    // product := unary (('*' | '/') unary)*
+    fn product(&mut self) -> Result<Expr, Diagnostic> {
+        let mut left = self.unary()?;
+        while let Some(op) = self.next_if(&[TokenKind::Star, TokenKind::Slash]) {
+            let op = if op == TokenKind::Star {
+                BinaryOp::Mul
+            } else {
+                BinaryOp::Div
+            };
+            left = Expr::Binary(op, Box::new(left), Box::new(self.unary()?));
+        }
+        Ok(left)
+    }
+
+    // unary := ('-' | '+') unary | '(' sum ')' | value
+    fn unary(&mut self) -> Result<Expr, Diagnostic> {
+        if let Some(op) = self.next_if(&[TokenKind::Plus, TokenKind::Minus]) {
+            let e = self.unary()?;
+            return Ok(if op == TokenKind::Minus {
+                Expr::Neg(Box::new(e))
+            } else {
+                e
+            });
         }
+        if self.next_if(&[TokenKind::LParen]).is_some() {
+            let e = self.sum()?;
+            if self.next_if(&[TokenKind::RParen]).is_none() {
+                return Err(self.error("Expected ')'."));
+            }
+            return Ok(e);
+        }
+        let Some(token) = self.tokens.get(self.pos) else {
+            return Err(self.error("Expected a value."));
+        };
+        let e = match &token.kind {
+            TokenKind::Decimal(value) => Expr::Number(*value),
+            TokenKind::Char(c) => Expr::Number(*c as i32),
+            TokenKind::Word(word) => match classify(word) {
+                Ok(Word::Number(value)) => Expr::Number(value),
+                Ok(Word::Symbol) => Expr::Symbol(word.clone()),
+                Ok(Word::Register(_)) => {
+                    return Err(self.error("Registers cannot be used in expressions."));
+                }
+                Err(e) => return Err(self.error(&e)),
+            },
+            _ => return Err(self.error("Expected a value.")),
+        };
+        self.pos += 1;
+        Ok(e)
     }
 }
 
-// Parsing OpCode (no change)
-pub fn parse_opcode(input: &str) -> IResult<&str, ParsedOpCode> {
-    alt((
-        value(ParsedOpCode::ADD, tag("ADD")),
-        value(ParsedOpCode::AND, tag("AND")),
-        alt((
-            value(ParsedOpCode::BR, tag("BRznp")),
-            value(ParsedOpCode::BR, tag("BRzpn")),
-            value(ParsedOpCode::BR, tag("BRnzp")),
-            value(ParsedOpCode::BR, tag("BRnpz")),
-            value(ParsedOpCode::BR, tag("BRpnz")),
-            value(ParsedOpCode::BR, tag("BRpzn")),
-            value(ParsedOpCode::BRzn, tag("BRzn")),
-            value(ParsedOpCode::BRzn, tag("BRnz")),
-            value(ParsedOpCode::BRzp, tag("BRzp")),
-            value(ParsedOpCode::BRzp, tag("BRpz")),
-            value(ParsedOpCode::BRpn, tag("BRpn")),
-            value(ParsedOpCode::BRpn, tag("BRnp")),
-            value(ParsedOpCode::BRz, tag("BRz")),
-            value(ParsedOpCode::BRn, tag("BRn")),
-            value(ParsedOpCode::BRp, tag("BRp")),
-            value(ParsedOpCode::BR, tag("BR")),
-        )),
-        value(ParsedOpCode::JMP, tag("JMP")),
-        value(ParsedOpCode::RET, tag("RET")),
-        value(ParsedOpCode::JSR, tag("JSR")),
-        value(ParsedOpCode::LD, tag("LD")),
-        value(ParsedOpCode::LDI, tag("LDI")),
-        value(ParsedOpCode::LDR, tag("LDR")),
-        value(ParsedOpCode::LEA, tag("LEA")),
-        value(ParsedOpCode::NOT, tag("NOT")),
-        value(ParsedOpCode::RTI, tag("RTI")),
-        value(ParsedOpCode::ST, tag("ST")),
-        value(ParsedOpCode::STI, tag("STI")),
-        value(ParsedOpCode::STR, tag("STR")),
-        value(ParsedOpCode::TRAP, tag("TRAP")),
-    ))(input)
-}
-
-// Label parser (no change)
-fn label(input: &str) -> IResult<&str, &str> {
-    terminated(
-        take_while1(|c: char| c.is_alphanumeric() || c == '_'),
-        opt(char(':')),
-    )(input)
-}
-
-// Operand parser (updated to handle 3 operand types)
-fn operand(input: &str) -> IResult<&str, (&str, &str)> {
-    alt((
-        pair(tag("R"), take_while1(|c: char| c.is_digit(10))), // Register
-        pair(tag("#"), take_while1(|c: char| c.is_digit(10) || c == '-')), // Immediate decimal value
-        pair(tag("x"), take_while1(|c: char| c.is_digit(16) || c == '-')), // Immediate hexadecimal value
-        map(take_while1(|c: char| c.is_alphanum()), |label| {
-            ("LABEL", label)
-        }),
-    ))(input)
-}
-
-// Instruction parser (no change)
-fn instruction(input: &str) -> IResult<&str, (Option<&str>, ParsedOpCode, Vec<(&str, &str)>)> {
-    let parse_operands = separated_list1(delimited(space0, char(','), space0), operand);
-    tuple((
-        alt((
-            map(parse_opcode, |o| (None, o)),
-            map(pair(label, preceded(space0, parse_opcode)), |(l, o)| {
-                (Some(l), o)
+// An operand made of the tokens between two commas.
+fn operand_group(tokens: &[Token], line: usize) -> Result<Operand, Diagnostic> {
+    let error = |column: usize, message: &str| Diagnostic::error(line, column, message);
+    if let [Token {
+        kind: TokenKind::Equals,
+        column,
+    }, rest @ ..] = tokens
+    {
+        let expr = match rest {
+            [] => None,
+            _ => match operand_group(rest, line)?.operand_type {
+                // The 16 bit pattern, so that =-1 and =xFFFF share their pool entry
+                OperandTypes::Immediate { value, .. } => Some(Expr::Number(value as i32)),
+                OperandTypes::Label(name) => Some(Expr::Symbol(name)),
+                OperandTypes::Expression(e) => Some(e),
+                _ => None,
+            },
+        };
+        return match expr {
+            Some(expr) => Ok(Operand {
+                operand_type: OperandTypes::Literal(expr),
+                column: *column,
             }),
-        )),
-        space1,
-        parse_operands,
-    ))(input)
-    .map(|(remaining, ((label, opcode), _, operands))| (remaining, (label, opcode, operands)))
-}
-
-// Comment parser (no change)
-fn comment(input: &str) -> IResult<&str, &str> {
-    preceded(space0, preceded(char(';'), take_while1(|c| c != '\n')))(input)
-}
-
-// Blank line parser (no change)
-fn blank_line(input: &str) -> IResult<&str, &str> {
-    alt((space0, tag("")))(input)
-}
-
-fn orig(input: &str) -> IResult<&str, u16> {
-    alt((
-        // Handle .ORIG followed by a hexadecimal value (e.g., x65)
-        map(
-            preceded(
-                tag(".ORIG "),
-                preceded(tag("x"), take_while1(|c: char| c.is_digit(16))),
-            ),
-            |hex_str: &str| u16::from_str_radix(hex_str, 16).unwrap(),
-        ),
-        // // Handle .ORIG followed by a decimal value (e.g., #10)
-        map(
-            preceded(
-                tag(".ORIG "),
-                preceded(tag("#"), take_while1(|c: char| c.is_digit(10))),
-            ),
-            |dec_str: &str| dec_str.parse::<u16>().unwrap(),
-        ),
-    ))(input)
-}
-
-// Full line parser
-fn lc3_line(input: &str) -> IResult<&str, Option<ParsedLine>> {
-    if let Ok((remaining, _)) = alt((comment, blank_line))(input) {
-        if remaining.is_empty() {
-            return Ok((input, None));
+            None => Err(error(*column, "Expected a value after '='.")),
+        };
+    }
+    match tokens {
+        [token] => return operand(token, line),
+        [first, second, ..] => {
+            let single = match &first.kind {
+                TokenKind::Str(_) => true,
+                TokenKind::Word(word) => parse_register(word).is_some(),
+                _ => false,
+            };
+            if single {
+                return Err(error(second.column, "Expected ',' between operands."));
+            }
+        }
+        [] => unreachable!("operand groups are never empty"),
+    }
+    let column = tokens[0].column;
+    let expression = match tokens {
+        [Token {
+            kind: TokenKind::Hash,
+            ..
+        }, rest @ ..] => rest,
+        _ => tokens,
+    };
+    let mut parser = ExprParser {
+        tokens: expression,
+        pos: 0,
+        line,
+    };
+    let e = parser.sum()?;
+    if let Some(token) = expression.get(parser.pos) {
+        return Err(error(token.column, "Expected ',' between operands."));
+    }
+    // Expressions without symbols are folded
+    let operand_type = match e.eval(&|_| None) {
+        Ok(value) => immediate(wrap16(value), line, column)?,
+        Err(_) if !e.symbols().is_empty() => OperandTypes::Expression(e),
+        Err(message) => return Err(error(column, &message)),
+    };
+    Ok(Operand {
+        operand_type,
+        column,
+    })
+}
+
+// Splits tokens on commas. Groups are never empty.
+fn split_commas(tokens: &[Token], line: usize) -> Result<Vec<&[Token]>, Diagnostic> {
+    let mut groups = Vec::new();
+    let mut rest = tokens;
+    while !rest.is_empty() {
+        let end = rest
+            .iter()
+            .position(|t| t.kind == TokenKind::Comma)
+            .unwrap_or(rest.len());
+        if end == 0 {
+            return Err(Diagnostic::error(
+                line,
+                rest[0].column,
+                "Expected an operand.",
+            ));
+        }
+        groups.push(&rest[..end]);
+        match rest.get(end) {
+            Some(comma) if end + 1 == rest.len() => {
+                return Err(Diagnostic::error(
+                    line,
+                    comma.column,
+                    "Expected an operand after ','.",
+                ));
+            }
+            Some(_) => rest = &rest[end + 1..],
+            None => rest = &[],
+        }
+    }
+    Ok(groups)
+}
+
+// Comma separated operands, until the end of the line.
+fn operands(tokens: &[Token], line: usize) -> Result<Vec<Operand>, Diagnostic> {
+    split_commas(tokens, line)?
+        .into_iter()
+        .map(|group| operand_group(group, line))
+        .collect()
+}
+
+enum Line {
+    Empty,
+    Orig(Operand),
+    End,
+    Instruction(ParsedLine),
+    // Start of a macro definition, with an empty body
+    MacroStart(Macro),
+    MacroEnd,
+    // .INCLUDE with the path as written and its column
+    Include(String, usize),
+    Invocation {
+        label: Option<String>,
+        name: String,
+        args: Vec<Vec<Token>>,
+        column: usize,
+    },
+}
+
+type Macros = HashMap<String, Macro>;
+
+// Opcodes and macro names are case insensitive.
+fn find_macro<'a>(macros: &'a Macros, word: &str) -> Option<&'a Macro> {
+    macros.get(&word.to_ascii_uppercase())
+}
+
+// Label defined at the start of the line, if any.
+fn leading_label<'a>(tokens: &'a [Token], macros: &Macros) -> Option<&'a str> {
+    match tokens.first() {
+        Some(Token {
+            kind: TokenKind::Word(word),
+            ..
+        }) if parse_opcode(word).is_none() && find_macro(macros, word).is_none() => Some(word),
+        _ => None,
+    }
+}
+
+// .EQU NAME value or .SET NAME value, the comma after the name being optional.
+fn constant(
+    opcode: ParsedOpCode,
+    tokens: &[Token],
+    line: usize,
+    column: usize,
+) -> Result<ParsedLine, Diagnostic> {
+    let directive = format!(".{:?}", opcode);
+    let (name, rest) = match tokens {
+        [Token {
+            kind: TokenKind::Word(name),
+            column,
+        }, rest @ ..] => {
+            if parse_opcode(name).is_some() || !matches!(classify(name), Ok(Word::Symbol)) {
+                return Err(Diagnostic::error(
+                    line,
+                    *column,
+                    format!("{} cannot be a constant name.", name),
+                ));
+            }
+            let name = Operand {
+                operand_type: OperandTypes::Label(name.clone()),
+                column: *column,
+            };
+            match rest {
+                [Token {
+                    kind: TokenKind::Comma,
+                    ..
+                }, rest @ ..] => (name, rest),
+                _ => (name, rest),
+            }
+        }
+        _ => {
+            return Err(Diagnostic::error(
+                line,
+                column,
+                format!("{} expects a name and a value.", directive),
+            ))
         }
+    };
+    let mut operands = operands(rest, line)?;
+    if operands.len() != 1 {
+        return Err(Diagnostic::error(
+            line,
+            name.column,
+            format!("{} expects a single value after the name.", directive),
+        ));
     }
+    operands.insert(0, name);
+    Ok(ParsedLine::from(None, opcode, operands))
+}
+
+// Macro arguments: groups of tokens separated by commas.
+fn arguments(tokens: &[Token], line: usize) -> Result<Vec<Vec<Token>>, Diagnostic> {
+    Ok(split_commas(tokens, line)?
+        .into_iter()
+        .map(<[Token]>::to_vec)
+        .collect())
+}
 
-    instruction(input).map(|(remaining, (label, opcode, operands))| {
-        (
-            remaining,
-            Some(ParsedLine::from(
-                label.map(|s| s.to_string()),
-                opcode,
-                operands
+// .MACRO name followed by its parameters, separated by spaces or commas.
+fn macro_definition(tokens: &[Token], line: usize, column: usize) -> Result<Macro, Diagnostic> {
+    let error = |column: usize, message: String| Diagnostic::error(line, column, message);
+    let (name, params) = match tokens {
+        [Token {
+            kind: TokenKind::Word(name),
+            column,
+        }, params @ ..] => {
+            if parse_opcode(name).is_some() || !matches!(classify(name), Ok(Word::Symbol)) {
+                return Err(error(*column, format!("{} cannot be a macro name.", name)));
+            }
+            (name, params)
+        }
+        _ => return Err(error(column, ".MACRO expects a name.".into())),
+    };
+    let mut names = Vec::new();
+    for token in params {
+        match &token.kind {
+            TokenKind::Comma => (),
+            TokenKind::Word(param) if matches!(classify(param), Ok(Word::Symbol)) => {
+                if names
                     .iter()
-                    .map(|(a, b)| Operand::from(a, b).unwrap()) // Only valid operands
-                    .collect(),
-            )),
+                    .any(|name: &String| name.eq_ignore_ascii_case(param))
+                {
+                    return Err(error(
+                        token.column,
+                        format!("Parameter {} is repeated.", param),
+                    ));
+                }
+                names.push(param.clone());
+            }
+            _ => return Err(error(token.column, "Invalid macro parameter.".into())),
+        }
+    }
+    Ok(Macro::new(name.to_ascii_uppercase(), names))
+}
+
+// Parses the tokens of a line, comments removed. line_number starts at 1.
+fn parse_tokens(tokens: &[Token], line_number: usize, macros: &Macros) -> Result<Line, Diagnostic> {
+    let error = |column: usize, message: String| Diagnostic::error(line_number, column, message);
+
+    let mut rest = tokens;
+    let mut label = None;
+    if let (Some(word), [first, tail @ ..]) = (leading_label(tokens, macros), tokens) {
+        let reserved = match classify(word) {
+            Ok(Word::Symbol) => None,
+            Ok(Word::Register(_)) => Some("a register"),
+            Ok(Word::Number(_)) => Some("a number"),
+            Err(e) => return Err(error(first.column, e)),
+        };
+        if let Some(reserved) = reserved {
+            return Err(error(
+                first.column,
+                format!("{} is {} and cannot be used as a label.", word, reserved),
+            ));
+        }
+        label = Some((word.to_string(), first.column));
+        rest = tail;
+        if let [Token {
+            kind: TokenKind::Colon,
+            ..
+        }, tail @ ..] = rest
+        {
+            rest = tail;
+        }
+    }
+
+    let (opcode, rest) = match rest {
+        [] => {
+            return match label {
+                None => Ok(Line::Empty),
+                Some((label, column)) => Err(error(
+                    column,
+                    format!("Expected an instruction after label {}.", label),
+                )),
+            };
+        }
+        [Token {
+            kind: TokenKind::Word(word),
+            column,
+        }, rest @ ..] => match (parse_opcode(word), find_macro(macros, word)) {
+            (Some(opcode), _) => (opcode, rest),
+            (None, Some(m)) => {
+                return Ok(Line::Invocation {
+                    label: label.map(|(label, _)| label),
+                    name: m.name.clone(),
+                    args: arguments(rest, line_number)?,
+                    column: *column,
+                });
+            }
+            (None, None) => {
+                return Err(error(*column, format!("Unknown instruction {}.", word)));
+            }
+        },
+        [Token {
+            kind: TokenKind::Directive(name),
+            column,
+        }, rest @ ..] => match (name.as_str(), parse_directive(name)) {
+            (
+                "ORIG" | "END" | "MACRO" | "ENDM" | "EQU" | "SET" | "INCLUDE" | "GLOBAL"
+                | "EXTERNAL",
+                _,
+            ) if label.is_some() => {
+                return Err(error(*column, format!(".{} cannot be labelled.", name)));
+            }
+            ("EQU" | "SET", Some(opcode)) => {
+                return constant(opcode, rest, line_number, *column).map(Line::Instruction);
+            }
+            (_, Some(opcode)) => (opcode, rest),
+            ("ORIG", _) => {
+                let operands = operands(rest, line_number)?;
+                return match operands.as_slice() {
+                    [operand] => Ok(Line::Orig(operand.clone())),
+                    _ => Err(error(*column, ".ORIG expects an address.".into())),
+                };
+            }
+            ("END" | "ENDM", _) if !rest.is_empty() => {
+                return Err(error(
+                    rest[0].column,
+                    format!(".{} takes no operand.", name),
+                ));
+            }
+            ("END", _) => return Ok(Line::End),
+            ("ENDM", _) => return Ok(Line::MacroEnd),
+            ("MACRO", _) => {
+                return macro_definition(rest, line_number, *column).map(Line::MacroStart);
+            }
+            ("INCLUDE", _) => {
+                return match rest {
+                    [Token {
+                        kind: TokenKind::Str(path),
+                        column,
+                    }] => Ok(Line::Include(path.clone(), *column)),
+                    _ => Err(error(
+                        *column,
+                        ".INCLUDE expects a file name in quotes.".into(),
+                    )),
+                };
+            }
+            _ => return Err(error(*column, format!("Unknown directive .{}.", name))),
+        },
+        [token, ..] => {
+            return Err(error(
+                token.column,
+                "Expected an instruction or a directive.".into(),
+            ));
+        }
+    };
+
+    Ok(Line::Instruction(ParsedLine::from(
+        label.map(|(label, _)| label),
+        opcode,
+        operands(rest, line_number)?,
+    )))
+}
+
+// Tokens of a line, without the trailing comment.
+fn line_tokens(input: &str, line_number: usize) -> Result<Vec<Token>, Diagnostic> {
+    let mut tokens = tokenize(input, line_number)?;
+    if let Some(Token {
+        kind: TokenKind::Comment(_),
+        ..
+    }) = tokens.last()
+    {
+        tokens.pop();
+    }
+    Ok(tokens)
+}
+
+// Where a macro is being invoked from. Errors in the expansion are reported there.
+struct Invocation<'a> {
+    name: &'a str,
+    line: usize,
+    column: usize,
+    depth: usize,
+}
+
+// Expands a macro invocation into the instructions it stands for. expansions counts the
+// expansions done so far in the file, to name local labels.
+fn expand(
+    invocation: &Invocation,
+    label: Option<String>,
+    args: &[Vec<Token>],
+    macros: &Macros,
+    expansions: &mut usize,
+) -> Result<Vec<ParsedLine>, Diagnostic> {
+    let error = |message: String| {
+        Diagnostic::error(
+            invocation.line,
+            invocation.column,
+            format!("{} (in macro {})", message, invocation.name),
         )
-    })
+    };
+    let m = &macros[invocation.name];
+    if invocation.depth == MAX_MACRO_DEPTH {
+        return Err(error(format!(
+            "Macros are nested more than {} levels deep.",
+            MAX_MACRO_DEPTH
+        )));
+    }
+    if args.len() != m.params.len() {
+        return Err(error(format!(
+            "Expected {} arguments, got {}.",
+            m.params.len(),
+            args.len()
+        )));
+    }
+    *expansions += 1;
+    let locals: Vec<String> = m
+        .body
+        .iter()
+        .filter_map(|line| leading_label(line, macros).map(String::from))
+        .collect();
+
+    let mut lines = Vec::new();
+    for tokens in m.expand(args, &locals, *expansions) {
+        match parse_tokens(&tokens, invocation.line, macros).map_err(|e| error(e.message))? {
+            Line::Empty => (),
+            Line::Instruction(mut line) => {
+                line.expansion = Some(render(&tokens));
+                lines.push(line);
+            }
+            Line::Invocation {
+                label, name, args, ..
+            } => {
+                let nested = Invocation {
+                    name: &name,
+                    depth: invocation.depth + 1,
+                    ..*invocation
+                };
+                lines.extend(expand(&nested, label, &args, macros, expansions)?);
+            }
+            _ => return Err(error("Directives are not allowed in a macro body.".into())),
+        }
+    }
+
+    if let Some(label) = label {
+        match lines.first_mut() {
+            Some(first) if first.label.is_none() => first.label = Some(label),
+            _ => {
+                return Err(error(format!(
+                    "Label {} needs a first instruction without label.",
+                    label
+                )))
+            }
+        }
+    }
+    Ok(lines)
 }
 
 // ParsedFile struct
 #[derive(Debug)]
 pub struct ParsedFile {
-    pub instructions: Vec<Option<ParsedLine>>,
-    pub orig: u16,
+    // One entry per line read, in the order of the program: the lines of an included file
+    // follow its .INCLUDE. Each entry holds the instructions of the line, and macro
+    // invocations hold the instructions of their expansion.
+    pub instructions: Vec<Vec<ParsedLine>>,
+    // File index and zero based line of each entry of instructions
+    pub positions: Vec<(usize, usize)>,
+    // None for a relocatable module
+    pub orig: Option<u16>,
+    // File index and zero based line of the .END directive
+    pub end: Option<(usize, usize)>,
 }
 
-// Parsing LC-3 file (no change)
-pub fn parse_lc3_file(file_content: Vec<String>) -> Result<ParsedFile, String> {
-    if file_content.is_empty() {
-        return Err("File is empty.".into());
+// State shared by a file and the files it includes.
+struct Parser<'a> {
+    provider: &'a dyn SourceProvider,
+    files: &'a mut Vec<SourceFile>,
+    // Files being parsed, the innermost last
+    stack: Vec<usize>,
+    diagnostics: Vec<Diagnostic>,
+    instructions: Vec<Vec<ParsedLine>>,
+    positions: Vec<(usize, usize)>,
+    orig: Option<u16>,
+    // Whether an instruction or a directive emitting words was read
+    started: bool,
+    // Position of the .END directive, after which lines are ignored
+    end: Option<(usize, usize)>,
+    macros: Macros,
+    // Macro being defined, with the file and line of its .MACRO directive
+    definition: Option<(Macro, usize, usize)>,
+    expansions: usize,
+}
+
+impl Parser<'_> {
+    fn parse_file(&mut self, file: usize) {
+        self.stack.push(file);
+        let lines = self.files[file].lines.clone();
+        for (i, content) in lines.iter().enumerate() {
+            self.instructions.push(Vec::new());
+            self.positions.push((file, i));
+            if self.end.is_none() {
+                if let Err(e) = self.parse_line(file, i, content) {
+                    self.diagnostics.push(e.in_file(file));
+                }
+            }
+        }
+        if let Some((m, _, line)) = self.definition.take_if(|(_, f, _)| *f == file) {
+            self.diagnostics.push(
+                Diagnostic::error(
+                    line + 1,
+                    1,
+                    format!("Macro {} is missing its .ENDM.", m.name),
+                )
+                .in_file(file),
+            );
+        }
+        self.stack.pop();
+    }
+
+    fn parse_line(&mut self, file: usize, i: usize, content: &str) -> Result<(), Diagnostic> {
+        let tokens = line_tokens(content, i + 1)?;
+        if let Some((m, _, _)) = self.definition.as_mut() {
+            match tokens.first().map(|t| &t.kind) {
+                Some(TokenKind::Directive(name)) if name == "ENDM" || name == "MACRO" => (),
+                _ => {
+                    m.body.push(tokens);
+                    return Ok(());
+                }
+            }
+        }
+        let error = |column: usize, message: String| Diagnostic::error(i + 1, column, message);
+        match parse_tokens(&tokens, i + 1, &self.macros)? {
+            Line::Empty => (),
+            Line::MacroStart(m) => {
+                if self.definition.is_some() {
+                    return Err(error(
+                        tokens[0].column,
+                        "Macro definitions cannot be nested.".into(),
+                    ));
+                } else if self.macros.contains_key(&m.name) {
+                    return Err(error(
+                        tokens[1].column,
+                        format!("Macro {} is already defined.", m.name),
+                    ));
+                }
+                self.definition = Some((m, file, i));
+            }
+            Line::MacroEnd => match self.definition.take() {
+                Some((m, _, _)) => {
+                    self.macros.insert(m.name.clone(), m);
+                }
+                None => return Err(error(tokens[0].column, ".ENDM without .MACRO.".into())),
+            },
+            Line::Include(path, column) => self.include(file, &path, i, column)?,
+            Line::Orig(operand) if self.orig.is_some() => {
+                return Err(error(
+                    operand.column,
+                    "Only one .ORIG directive is allowed.".into(),
+                ))
+            }
+            Line::Orig(_) if self.started => {
+                return Err(error(
+                    tokens[0].column,
+                    "The file should start with a .ORIG directive".into(),
+                ))
+            }
+            Line::Orig(operand) => match operand.operand_type {
+                OperandTypes::Immediate { value, .. } => self.orig = Some(value),
+                _ => return Err(error(operand.column, ".ORIG expects an address.".into())),
+            },
+            Line::End => self.end = Some((file, i)),
+            Line::Instruction(instruction) => {
+                self.started |= !matches!(
+                    instruction.opcode,
+                    ParsedOpCode::EQU
+                        | ParsedOpCode::SET
+                        | ParsedOpCode::GLOBAL
+                        | ParsedOpCode::EXTERNAL
+                );
+                self.instructions.last_mut().unwrap().push(instruction)
+            }
+            Line::Invocation {
+                label,
+                name,
+                args,
+                column,
+            } => {
+                self.started = true;
+                let invocation = Invocation {
+                    name: &name,
+                    line: i + 1,
+                    column,
+                    depth: 0,
+                };
+                *self.instructions.last_mut().unwrap() = expand(
+                    &invocation,
+                    label,
+                    &args,
+                    &self.macros,
+                    &mut self.expansions,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    // Parses the file named by an .INCLUDE of the given file, unless it was already
+    // included. Including a file that is being parsed is an error.
+    fn include(
+        &mut self,
+        from: usize,
+        path: &str,
+        i: usize,
+        column: usize,
+    ) -> Result<(), Diagnostic> {
+        let path = self.provider.resolve(&self.files[from].name, path);
+        if let Some(start) = self.stack.iter().position(|&f| self.files[f].name == path) {
+            let mut cycle: Vec<&str> = self.stack[start..]
+                .iter()
+                .map(|&f| self.files[f].name.as_str())
+                .collect();
+            cycle.push(&path);
+            return Err(Diagnostic::error(
+                i + 1,
+                column,
+                format!("Include cycle: {}.", cycle.join(" -> ")),
+            ));
+        }
+        if self.files.iter().any(|f| f.name == path) {
+            return Ok(());
+        }
+        let content = self
+            .provider
+            .read(&path)
+            .map_err(|e| Diagnostic::error(i + 1, column, e))?;
+        self.files.push(SourceFile::new(&path, &content));
+        self.parse_file(self.files.len() - 1);
+        Ok(())
     }
+}
 
-    let orig_value = &file_content[0];
-    let orig = match orig(&orig_value) {
-        Ok((_, data)) => data,
-        Err(_) => return Err("The file should start with a .ORIG directive".into()),
+// Parses a program made of files[0] and the files it includes, which are read through the
+// provider and appended to files. Diagnostics refer to the files by their index. Without
+// .ORIG the program is a relocatable module, otherwise only comments, macro definitions,
+// includes and symbol declarations may come before .ORIG. Lines after .END are ignored.
+pub fn parse_files(
+    files: &mut Vec<SourceFile>,
+    provider: &dyn SourceProvider,
+) -> Result<ParsedFile, Vec<Diagnostic>> {
+    let mut parser = Parser {
+        provider,
+        files,
+        stack: Vec::new(),
+        diagnostics: Vec::new(),
+        instructions: Vec::new(),
+        positions: Vec::new(),
+        orig: None,
+        started: false,
+        end: None,
+        macros: Macros::new(),
+        definition: None,
+        expansions: 0,
     };
+    parser.parse_file(0);
 
-    let instructions: Vec<Option<ParsedLine>> = file_content
-        .iter()
-        .skip(1)
-        .map(|line| match lc3_line(&line) {
-            Ok((d, Some(instruction))) => {
-                dbg!(d);
-                Some(instruction)
+    if !parser.diagnostics.is_empty() {
+        return Err(parser.diagnostics);
+    }
+    Ok(ParsedFile {
+        instructions: parser.instructions,
+        positions: parser.positions,
+        orig: parser.orig,
+        end: parser.end,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::MemoryFiles;
+
+    fn parse(content: &[&str]) -> Result<ParsedFile, Vec<Diagnostic>> {
+        parse_files(
+            &mut vec![SourceFile::new("main.asm", &content.join("\n"))],
+            &MemoryFiles::new(),
+        )
+    }
+
+    #[test]
+    fn test_case_insensitive() {
+        let file = parse(&[
+            "; header comment",
+            "\t.orig\tX3000",
+            "loop:\tadd r1, R1, #1\t; increment",
+            "  brNZ loop",
+            "   ; indented comment",
+            "\t.fill b-101",
+            ".FILL 'A'",
+            ".end",
+            "garbage after end",
+        ])
+        .unwrap();
+        assert_eq!(file.orig, Some(0x3000));
+        assert_eq!(file.instructions.len(), 9);
+        let add = &file.instructions[2][0];
+        assert_eq!(add.label.as_deref(), Some("loop"));
+        assert_eq!(add.opcode, ParsedOpCode::ADD);
+        assert_eq!(add.operands[1].operand_type, OperandTypes::Register(1));
+        assert_eq!(add.operands[2].column, 19);
+        let br = &file.instructions[3][0];
+        assert_eq!(br.opcode, ParsedOpCode::BRzn);
+        let fill = &file.instructions[5][0];
+        assert_eq!(
+            fill.operands[0].operand_type,
+            OperandTypes::Immediate {
+                value: -5i16 as u16,
+                sign: true
             }
-            Ok((_, None)) => None,
-            Err(e) => {
-                eprintln!("Error parsing line '{}': {}", line, e);
-                None
+        );
+        let fill = &file.instructions[6][0];
+        assert_eq!(
+            fill.operands[0].operand_type,
+            OperandTypes::Immediate {
+                value: 65,
+                sign: false
             }
-        })
-        .collect();
+        );
+        assert!(file.instructions[4].is_empty());
+        assert!(file.instructions[8].is_empty());
+    }
 
-    Ok(ParsedFile { instructions, orig })
+    #[test]
+    fn test_errors() {
+        let errors = parse(&[
+            ".ORIG x3000",
+            "ADD R1 R1, #1",
+            "FOO: MUL R1, R2",
+            "ADD R1, R2,",
+        ])
+        .unwrap_err();
+        let positions: Vec<(usize, usize)> = errors.iter().map(|e| (e.line, e.column)).collect();
+        assert_eq!(positions, [(2, 8), (3, 6), (4, 11)]);
+        assert!(parse(&["ADD R1, R1, #1", ".ORIG x3000"]).is_err());
+        assert_eq!(parse(&["ADD R1, R1, #1"]).unwrap().orig, None);
+        assert!(parse(&[".ORIG x3000", ".FILL x10000"]).is_err());
+    }
+
+    #[test]
+    fn test_hex_or_label() {
+        let file = parse(&[
+            ".ORIG x3000",
+            "xCount .FILL xCAFE",
+            "LD R0, xCount",
+            "BR b12",
+            ".FILL X-1",
+        ])
+        .unwrap();
+        let fill = &file.instructions[1][0];
+        assert_eq!(fill.label.as_deref(), Some("xCount"));
+        assert_eq!(
+            fill.operands[0].operand_type,
+            OperandTypes::Immediate {
+                value: 0xCAFE,
+                sign: false
+            }
+        );
+        let ld = &file.instructions[2][0];
+        assert_eq!(
+            ld.operands[1].operand_type,
+            OperandTypes::Label("xCount".into())
+        );
+        let br = &file.instructions[3][0];
+        assert_eq!(
+            br.operands[0].operand_type,
+            OperandTypes::Label("b12".into())
+        );
+        let fill = &file.instructions[4][0];
+        assert_eq!(
+            fill.operands[0].operand_type,
+            OperandTypes::Immediate {
+                value: 0xFFFF,
+                sign: true
+            }
+        );
+
+        let errors = parse(&[
+            ".ORIG x3000",
+            "xa ADD R1, R1, #1",
+            "BR x-1G",
+            "R1 ADD R1, R1, #1",
+        ])
+        .unwrap_err();
+        assert_eq!(errors.len(), 3);
+        assert_eq!(
+            errors[0].message,
+            "xa is a number and cannot be used as a label."
+        );
+        assert_eq!((errors[1].line, errors[1].column), (3, 4));
+        assert!(errors[2].message.contains("register"));
+    }
+
+    #[test]
+    fn test_expressions() {
+        let file = parse(&[
+            ".ORIG x3000",
+            "LD R0, LABEL+2",
+            "ADD R1, R1, -(2*3)",
+            ".EQU SIZE, END-START",
+        ])
+        .unwrap();
+        assert_eq!(
+            file.instructions[1][0].operands[1].operand_type,
+            OperandTypes::Expression(Expr::Binary(
+                BinaryOp::Add,
+                Box::new(Expr::Symbol("LABEL".into())),
+                Box::new(Expr::Number(2))
+            ))
+        );
+        assert_eq!(
+            file.instructions[2][0].operands[2].operand_type,
+            OperandTypes::Immediate {
+                value: -6i16 as u16,
+                sign: true
+            }
+        );
+        let equ = &file.instructions[3][0];
+        assert_eq!(equ.opcode, ParsedOpCode::EQU);
+        let file = parse(&[".ORIG x3000", "LD R0, =-1", "LD R1, = LABEL"]).unwrap();
+        assert_eq!(
+            file.instructions[1][0].operands[1].operand_type,
+            OperandTypes::Literal(Expr::Number(0xFFFF))
+        );
+        assert_eq!(
+            file.instructions[2][0].operands[1].operand_type,
+            OperandTypes::Literal(Expr::Symbol("LABEL".into()))
+        );
+        assert_eq!(
+            equ.operands[0].operand_type,
+            OperandTypes::Label("SIZE".into())
+        );
+
+        let errors =
+            parse(&[".ORIG x3000", "LD R0, (A+2", "ADD R1, R1, R2+1", ".EQU X"]).unwrap_err();
+        let positions: Vec<(usize, usize)> = errors.iter().map(|e| (e.line, e.column)).collect();
+        assert_eq!(positions, [(2, 11), (3, 15), (4, 6)]);
+    }
+
+    #[test]
+    fn test_macros() {
+        let file = parse(&[
+            ".MACRO PUSH reg",
+            "  ADD R6, R6, #-1",
+            "  STR reg, R6, #0",
+            ".ENDM",
+            ".macro twice a, b",
+            "  push a",
+            "again push b",
+            "  BRnzp again",
+            ".endm",
+            ".ORIG x3000",
+            "START TWICE R1, R2",
+            "TWICE R3, R4",
+        ])
+        .unwrap();
+        assert!(file.instructions[..10].iter().all(|l| l.is_empty()));
+        let first = &file.instructions[10];
+        assert_eq!(first.len(), 5);
+        assert_eq!(first[0].label.as_deref(), Some("START"));
+        assert_eq!(first[2].expansion.as_deref(), Some("ADD R6, R6, #-1"));
+        assert_eq!(first[3].expansion.as_deref(), Some("STR R2, R6, #0"));
+        assert_eq!(
+            first[4].operands[0].operand_type,
+            OperandTypes::Label("again__1".into())
+        );
+        let second = &file.instructions[11];
+        assert_eq!(second[2].label.as_deref(), Some("again__4"));
+        assert_eq!(second[4].expansion.as_deref(), Some("BRnzp again__4"));
+    }
+
+    #[test]
+    fn test_macro_errors() {
+        let errors = parse(&[
+            ".MACRO LOOP",
+            "LOOP",
+            ".ENDM",
+            ".MACRO TWO a, b",
+            "ADD a, b, #1",
+            ".ENDM",
+            ".MACRO BAD",
+            "ADD R1, R1 R1",
+            ".ENDM",
+            ".ORIG x3000",
+            "  LOOP",
+            "TWO R1",
+            "BAD",
+            ".MACRO OPEN",
+        ])
+        .unwrap_err();
+        let positions: Vec<(usize, usize)> = errors.iter().map(|e| (e.line, e.column)).collect();
+        assert_eq!(positions, [(11, 3), (12, 1), (13, 1), (14, 1)]);
+        assert!(errors[0].message.contains("nested more than"));
+        assert_eq!(
+            errors[2].message,
+            "Expected ',' between operands. (in macro BAD)"
+        );
+    }
+
+    #[test]
+    fn test_include() {
+        let mut provider = MemoryFiles::new();
+        provider.add("lib/defs.asm", ".MACRO CLEAR reg\nAND reg, reg, #0\n.ENDM");
+        provider.add("lib/io.asm", ".INCLUDE \"defs.asm\"\nPRINT CLEAR R0\nRET");
+        let mut files = vec![SourceFile::new(
+            "main.asm",
+            ".include \"lib/defs.asm\"\n.ORIG x3000\nCLEAR R1\n.INCLUDE \"./lib/io.asm\"\nHALT",
+        )];
+        let file = parse_files(&mut files, &provider).unwrap();
+        let names: Vec<&str> = files.iter().map(|f| f.name.as_str()).collect();
+        assert_eq!(names, ["main.asm", "lib/defs.asm", "lib/io.asm"]);
+        assert_eq!(
+            file.positions,
+            [
+                (0, 0),
+                (1, 0),
+                (1, 1),
+                (1, 2),
+                (0, 1),
+                (0, 2),
+                (0, 3),
+                (2, 0),
+                (2, 1),
+                (2, 2),
+                (0, 4)
+            ]
+        );
+        assert_eq!(file.instructions[8][0].label.as_deref(), Some("PRINT"));
+        assert_eq!(file.instructions[10][0].opcode, ParsedOpCode::HALT);
+
+        let mut provider = MemoryFiles::new();
+        provider.add("a.asm", "ADD R1, R1, #1\n.INCLUDE \"b.asm\"");
+        provider.add("b.asm", "\n.INCLUDE \"a.asm\"\nADD R1 R1, #1");
+        let mut files = vec![SourceFile::new(
+            "main.asm",
+            ".ORIG x3000\n.INCLUDE \"a.asm\"\n.INCLUDE \"missing.asm\"\n.INCLUDE io",
+        )];
+        let errors = parse_files(&mut files, &provider).unwrap_err();
+        let positions: Vec<(usize, usize, usize)> =
+            errors.iter().map(|e| (e.file, e.line, e.column)).collect();
+        assert_eq!(positions, [(2, 2, 10), (2, 3, 8), (0, 3, 10), (0, 4, 1)]);
+        assert_eq!(errors[0].message, "Include cycle: a.asm -> b.asm -> a.asm.");
+    }
 }
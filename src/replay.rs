@@ -0,0 +1,188 @@
+use std::collections::VecDeque;
+
+use wasm_bindgen::prelude::*;
+
+use crate::Core;
+
+/// A byte of console input and the number of instructions executed when it became
+/// available to the program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InputEvent {
+    pub instruction: u64,
+    pub byte: u8,
+}
+
+/// Console input of a session, recorded or written by hand. Replaying it gives every byte
+/// to the program at the same instruction as when it was recorded, so that the run is the
+/// same.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InputScript {
+    events: Vec<InputEvent>,
+}
+
+impl InputScript {
+    pub fn events(&self) -> &[InputEvent] {
+        &self.events
+    }
+
+    /// Parses the lines `key <instruction> x<byte>`, in the order of the instructions.
+    pub fn parse(content: &str) -> Result<InputScript, String> {
+        let mut script = InputScript::default();
+        for (i, line) in content.lines().enumerate() {
+            let error = || format!("Invalid input script at line {}: {}", i + 1, line);
+            // Comments run from # to the end of the line
+            let fields = line.split('#').next().unwrap_or_default();
+            match fields.split_whitespace().collect::<Vec<_>>().as_slice() {
+                [] => (),
+                ["key", instruction, byte] => {
+                    let event = InputEvent {
+                        instruction: instruction.parse().map_err(|_| error())?,
+                        byte: byte
+                            .strip_prefix('x')
+                            .and_then(|b| u8::from_str_radix(b, 16).ok())
+                            .ok_or_else(error)?,
+                    };
+                    if script
+                        .events
+                        .last()
+                        .is_some_and(|last| last.instruction > event.instruction)
+                    {
+                        return Err(error());
+                    }
+                    script.events.push(event);
+                }
+                _ => return Err(error()),
+            }
+        }
+        Ok(script)
+    }
+}
+
+#[wasm_bindgen]
+impl InputScript {
+    #[wasm_bindgen(js_name = parse)]
+    pub fn parse_js(content: &str) -> Result<InputScript, String> {
+        InputScript::parse(content)
+    }
+
+    // Content of the script file, printable bytes shown in a comment
+    pub fn serialize(&self) -> String {
+        let mut out = String::from("# tdal3 input\n");
+        for event in &self.events {
+            out.push_str(&format!("key {} x{:02X}", event.instruction, event.byte));
+            if event.byte.is_ascii_graphic() {
+                out.push_str(&format!(" # {}", event.byte as char));
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+impl Core {
+    // Next byte of input: from the script being replayed, once the program reached its
+    // instruction, or else from the console. Recorded if a recording is on.
+    pub(crate) fn next_input(&mut self) -> Option<u8> {
+        let byte = match &mut self.replay {
+            Some(events) => match events.front() {
+                Some(event) if event.instruction <= self.instructions => {
+                    events.pop_front().map(|event| event.byte)
+                }
+                _ => None,
+            },
+            None => self.console.read(),
+        }?;
+        if let Some(recording) = &mut self.recording {
+            recording.events.push(InputEvent {
+                instruction: self.instructions,
+                byte,
+            });
+        }
+        Some(byte)
+    }
+}
+
+#[wasm_bindgen]
+impl Core {
+    // Records the input from now on, dropping what was recorded before.
+    pub fn start_recording(&mut self) {
+        self.recording = Some(InputScript::default());
+    }
+    pub fn recorded_input(&self) -> Option<InputScript> {
+        self.recording.clone()
+    }
+    // Takes the input from script instead of the console, which is then ignored.
+    pub fn replay_input(&mut self, script: &InputScript) {
+        self.replay = Some(VecDeque::from(script.events.clone()));
+    }
+    // Whether bytes of the script being replayed are still to be read
+    pub fn replay_pending(&self) -> bool {
+        self.replay
+            .as_ref()
+            .is_some_and(|events| !events.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CoreConfig;
+    use crate::console::BufferConsole;
+    use crate::StopReason;
+
+    // Echoes keys until a line feed, counting the polls of KBSR in R2.
+    const PROGRAM: [u16; 11] = [
+        0x3000, //
+        0x14A1, // POLL ADD R2, R2, #1
+        0xA206, // LDI R1, KBSR
+        0x07FD, // BRzp POLL
+        0xA005, // LDI R0, KBDR
+        0xF021, // OUT
+        0x1236, // ADD R1, R0, #-10
+        0x0BF9, // BRnp POLL
+        0xF025, // HALT
+        0xFE00, // KBSR
+        0xFE02, // KBDR
+    ];
+
+    fn run(c: &mut Core) -> Vec<u8> {
+        let console = BufferConsole::new();
+        c.set_console(Box::new(console.clone()));
+        c.load_obj(&PROGRAM).unwrap();
+        assert_eq!(c.run(100_000), StopReason::Halted);
+        console.output()
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let mut c = Core::with_config(&CoreConfig::new().pc(0x3000));
+        c.start_recording();
+        let console = BufferConsole::new();
+        c.set_console(Box::new(console.clone()));
+        c.load_obj(&PROGRAM).unwrap();
+        c.run(50);
+        console.push_input(b"hi");
+        c.run(50);
+        console.push_input(b"\n");
+        assert_eq!(c.run(100_000), StopReason::Halted);
+        let script = c.recorded_input().unwrap();
+        assert_eq!(script.events().len(), 3);
+        assert_eq!(script.events()[0].byte, b'h');
+        let polls = c.register(2);
+
+        let content = script.serialize();
+        assert!(content.contains(&format!("key {} x68 # h\n", script.events()[0].instruction)));
+        let script = InputScript::parse(&content).unwrap();
+        let mut replay = Core::with_config(&CoreConfig::new().pc(0x3000));
+        replay.replay_input(&script);
+        assert!(replay.replay_pending());
+        assert!(run(&mut replay).starts_with(b"hi\n"));
+        assert!(!replay.replay_pending());
+        assert_eq!(replay.register(2), polls);
+        assert_eq!(replay.instructions(), c.instructions());
+
+        assert!(InputScript::parse("key 5 x61\nkey 4 x62").is_err());
+        assert!(InputScript::parse("key 5 97").is_err());
+    }
+}
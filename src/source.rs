@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+
+use wasm_bindgen::prelude::*;
+
+/// A file of the program being assembled, split in lines.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceFile {
+    pub name: String,
+    pub lines: Vec<String>,
+}
+
+impl SourceFile {
+    pub fn new(name: &str, content: &str) -> Self {
+        SourceFile {
+            name: name.to_string(),
+            lines: content.lines().map(String::from).collect(),
+        }
+    }
+}
+
+// Removes the . and .. components of a / separated path.
+pub fn normalize(path: &str) -> String {
+    let mut parts: Vec<&str> = Vec::new();
+    for part in path.split('/') {
+        match part {
+            "." => (),
+            ".." if parts.last().is_some_and(|p| !p.is_empty() && *p != "..") => {
+                parts.pop();
+            }
+            _ => parts.push(part),
+        }
+    }
+    parts.join("/")
+}
+
+/// Where `.INCLUDE` finds the files it names.
+pub trait SourceProvider {
+    /// Path of the file included as path by the file at from. Relative paths start from
+    /// the directory of the including file.
+    fn resolve(&self, from: &str, path: &str) -> String {
+        if path.starts_with('/') {
+            return normalize(path);
+        }
+        match from.rfind('/') {
+            Some(i) => normalize(&format!("{}/{}", &from[..i], path)),
+            None => normalize(path),
+        }
+    }
+    fn read(&self, path: &str) -> Result<String, String>;
+}
+
+/// In memory files, such as the tabs of the web editor.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Default)]
+pub struct MemoryFiles {
+    files: HashMap<String, String>,
+}
+
+#[wasm_bindgen]
+impl MemoryFiles {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> MemoryFiles {
+        MemoryFiles::default()
+    }
+    pub fn add(&mut self, path: &str, content: &str) {
+        self.files.insert(normalize(path), content.to_string());
+    }
+}
+
+impl SourceProvider for MemoryFiles {
+    fn read(&self, path: &str) -> Result<String, String> {
+        self.files
+            .get(&normalize(path))
+            .cloned()
+            .ok_or_else(|| format!("File {} not found.", path))
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub use disk::DiskFiles;
+
+#[cfg(not(target_arch = "wasm32"))]
+mod disk {
+    use std::fs;
+
+    use super::SourceProvider;
+
+    /// Files read from disk, relative paths starting from the working directory.
+    #[derive(Debug, Clone, Copy, Default)]
+    pub struct DiskFiles;
+
+    impl SourceProvider for DiskFiles {
+        fn read(&self, path: &str) -> Result<String, String> {
+            fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let files = MemoryFiles::new();
+        assert_eq!(files.resolve("main.asm", "io.asm"), "io.asm");
+        assert_eq!(
+            files.resolve("src/main.asm", "./lib/io.asm"),
+            "src/lib/io.asm"
+        );
+        assert_eq!(
+            files.resolve("src/lib/io.asm", "../math.asm"),
+            "src/math.asm"
+        );
+        assert_eq!(files.resolve("main.asm", "../x.asm"), "../x.asm");
+        assert_eq!(files.resolve("src/main.asm", "/abs/x.asm"), "/abs/x.asm");
+    }
+
+    #[test]
+    fn test_memory_files() {
+        let mut files = MemoryFiles::new();
+        files.add("./lib/io.asm", "HALT");
+        assert_eq!(files.read("lib/io.asm"), Ok("HALT".into()));
+        assert!(files.read("io.asm").is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use crate::interrupt::InterruptRequest;
+use crate::memory::{INTERRUPT_ENABLE, READY};
+use crate::Core;
+
+// Memory mapped timer registers
+pub const TMCR: u16 = 0xFE08; // Control: enable, interrupt enable, priority and vector
+pub const TMRR: u16 = 0xFE0A; // Reload: instructions, or cycles, between two ticks
+pub const TMSR: u16 = 0xFE0C; // Status: ready once the count reaches zero
+
+// TMCR bits. Bit 14 enables interrupts like in KBSR, bits 10-8 hold the priority and bits
+// 7-0 the vector.
+const TIMER_ENABLE: u16 = 1 << 15;
+const PRIORITY_SHIFT: u16 = 8;
+
+impl Core {
+    // Counts down elapsed instructions, or cycles with a timing model. Reaching zero sets the
+    // ready bit of TMSR and starts counting again from TMRR. A zero reload stops the timer.
+    pub(crate) fn tick_timer(&mut self, elapsed: u16) {
+        let control = self.memory[TMCR as usize];
+        let reload = self.memory[TMRR as usize];
+        if control & TIMER_ENABLE == 0 || reload == 0 {
+            return;
+        }
+        // Registers loaded without going through write_timer
+        if self.timer_count == 0 {
+            self.timer_count = reload;
+        }
+        let mut elapsed = elapsed;
+        while elapsed >= self.timer_count {
+            elapsed -= self.timer_count;
+            self.timer_count = reload;
+            self.memory[TMSR as usize] |= READY;
+        }
+        self.timer_count -= elapsed;
+    }
+
+    // Writes to the control or reload registers restart the count.
+    pub(crate) fn write_timer(&mut self, address: u16, value: u16) {
+        self.memory[address as usize] = value;
+        self.timer_count = self.memory[TMRR as usize];
+    }
+
+    // Reading the status acknowledges the tick.
+    pub(crate) fn read_timer_status(&mut self) -> u16 {
+        let status = self.memory[TMSR as usize];
+        self.memory[TMSR as usize] &= !READY;
+        status
+    }
+
+    // Raised as long as a tick is not acknowledged and TMCR enables interrupts.
+    pub(crate) fn timer_request(&self) -> Option<InterruptRequest> {
+        let control = self.memory[TMCR as usize];
+        let ready = self.memory[TMSR as usize] & READY != 0;
+        (ready && control & INTERRUPT_ENABLE != 0).then_some(InterruptRequest {
+            priority: ((control >> PRIORITY_SHIFT) & 0b111) as u8,
+            vector: control as u8,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CoreConfig;
+
+    #[test]
+    fn test_polling() {
+        let mut c = Core::new();
+        c.write(TMRR, 3);
+        c.tick_timer(10);
+        assert_eq!(c.read(TMSR), 0);
+        c.write(TMCR, TIMER_ENABLE);
+        c.tick_timer(2);
+        assert_eq!(c.read(TMSR), 0);
+        c.tick_timer(1);
+        assert_eq!(c.read(TMSR), READY);
+        // Acknowledged by the read
+        assert_eq!(c.read(TMSR), 0);
+        c.tick_timer(7);
+        assert_eq!(c.read(TMSR), READY);
+        c.tick_timer(1);
+        assert_eq!(c.read(TMSR), 0);
+        c.tick_timer(1);
+        assert_eq!(c.read(TMSR), READY);
+    }
+
+    #[test]
+    fn test_loaded_registers() {
+        // Registers set by an object rather than by stores still count from TMRR
+        let mut c = Core::new();
+        c.load_obj(&[TMCR, TIMER_ENABLE, 0, 2]).unwrap();
+        c.tick_timer(1);
+        assert_eq!(c.read(TMSR), 0);
+        c.tick_timer(1);
+        assert_eq!(c.read(TMSR), READY);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        // Every 4 instructions, the handler counts the ticks in R2
+        let handler = [
+            0x4000, //
+            0xA202, // LDI R1, TMSR
+            0x14A1, // ADD R2, R2, #1
+            0x8000, // RTI
+            TMSR,
+        ];
+        let mut c = Core::with_config(&CoreConfig::new().pc(0x3000));
+        c.load_obj(&[0x0181, 0x4000]).unwrap();
+        c.load_obj(&handler).unwrap();
+        c.write(TMRR, 4);
+        c.write(
+            TMCR,
+            TIMER_ENABLE | INTERRUPT_ENABLE | 2 << PRIORITY_SHIFT | 0x81,
+        );
+        // NOPs from x3000
+        c.run(4);
+        assert_eq!((c.pc(), c.priority(), c.register(2)), (0x3004, 0, 0));
+        c.step();
+        assert_eq!((c.pc(), c.priority()), (0x4000, 2));
+        c.run(3);
+        assert_eq!((c.pc(), c.priority(), c.register(2)), (0x3004, 0, 1));
+        // A tick every 5 steps: the interrupt, the handler and one NOP
+        c.run(40);
+        assert_eq!(c.register(2), 9);
+    }
+}
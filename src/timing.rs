@@ -0,0 +1,245 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use wasm_bindgen::prelude::*;
+
+use crate::memory::DEVICE_PAGE;
+use crate::{Core, MEMORY_SIZE};
+
+// Addresses listed by the profile report
+const HOT_SPOTS: usize = 10;
+
+/// Cycles charged per instruction: a base cost for executing it, plus a latency for each
+/// memory access, fetch included, and wait states for the accesses to the device page.
+/// LDI thus costs base + 3 * memory_latency, and interrupts and exceptions cost their
+/// pushes and vector read.
+#[wasm_bindgen]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimingModel {
+    base: u16,
+    memory_latency: u16,
+    device_wait: u16,
+}
+
+impl Default for TimingModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[wasm_bindgen]
+impl TimingModel {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> TimingModel {
+        TimingModel {
+            base: 1,
+            memory_latency: 1,
+            device_wait: 0,
+        }
+    }
+    pub fn base(mut self, base: u16) -> TimingModel {
+        self.base = base;
+        self
+    }
+    pub fn memory_latency(mut self, memory_latency: u16) -> TimingModel {
+        self.memory_latency = memory_latency;
+        self
+    }
+    pub fn device_wait(mut self, device_wait: u16) -> TimingModel {
+        self.device_wait = device_wait;
+        self
+    }
+}
+
+impl TimingModel {
+    /// Parses base=<n>,memory=<n>,device=<n>, the fields left out keeping their defaults.
+    pub fn parse(spec: &str) -> Result<TimingModel, String> {
+        let mut model = TimingModel::new();
+        for field in spec.split(',').filter(|field| !field.is_empty()) {
+            let (name, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("Invalid timing {}, expected <name>=<cycles>.", field))?;
+            let value = value
+                .parse()
+                .map_err(|_| format!("Invalid number of cycles {}.", value))?;
+            model = match name {
+                "base" => model.base(value),
+                "memory" => model.memory_latency(value),
+                "device" => model.device_wait(value),
+                _ => return Err(format!("Unknown timing {}.", name)),
+            };
+        }
+        Ok(model)
+    }
+}
+
+/// Instructions executed and cycles spent at each address.
+#[derive(Debug, Clone)]
+pub struct Profile {
+    executions: Vec<u64>,
+    cycles: Vec<u64>,
+}
+
+impl Profile {
+    fn new() -> Self {
+        Profile {
+            executions: vec![0; MEMORY_SIZE],
+            cycles: vec![0; MEMORY_SIZE],
+        }
+    }
+    pub fn executions(&self, address: u16) -> u64 {
+        self.executions[address as usize]
+    }
+    pub fn cycles(&self, address: u16) -> u64 {
+        self.cycles[address as usize]
+    }
+
+    // Totals, then the addresses where most cycles went, or most instructions without a
+    // timing model, named after the label before them.
+    fn report(&self, symbols: &BTreeMap<u16, String>) -> String {
+        let executions: u64 = self.executions.iter().sum();
+        let cycles: u64 = self.cycles.iter().sum();
+        let mut out = format!("{} instructions", executions);
+        if cycles > 0 {
+            let _ = write!(
+                out,
+                ", {} cycles, {:.2} cycles per instruction",
+                cycles,
+                cycles as f64 / executions.max(1) as f64
+            );
+        }
+        out.push('\n');
+        let mut addresses: Vec<usize> = (0..MEMORY_SIZE)
+            .filter(|&a| self.executions[a] > 0 || self.cycles[a] > 0)
+            .collect();
+        addresses.sort_by_key(|&a| std::cmp::Reverse((self.cycles[a], self.executions[a])));
+        for &address in addresses.iter().take(HOT_SPOTS) {
+            let _ = write!(
+                out,
+                "x{:04X} {:>10} {:>10}",
+                address, self.executions[address], self.cycles[address]
+            );
+            if let Some((&start, name)) = symbols.range(..=address as u16).next_back() {
+                let _ = write!(out, "  {}+{}", name, address as u16 - start);
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+impl Core {
+    // Charges the latency of an access to address to the current step.
+    pub(crate) fn charge(&mut self, address: u16) {
+        if let Some(model) = self.timing {
+            self.step_cycles += model.memory_latency as u64;
+            if address >= DEVICE_PAGE {
+                self.step_cycles += model.device_wait as u64;
+            }
+        }
+    }
+
+    // Closes a step spent at address, executing an instruction or entering a handler.
+    // The timer counts cycles when there is a timing model, instructions otherwise.
+    pub(crate) fn account(&mut self, address: u16, instruction: bool) {
+        let mut cycles = std::mem::take(&mut self.step_cycles);
+        if let Some(model) = self.timing {
+            cycles += if instruction { model.base as u64 } else { 0 };
+        }
+        self.cycles += cycles;
+        self.instructions += instruction as u64;
+        if let Some(profile) = &mut self.profile {
+            profile.executions[address as usize] += instruction as u64;
+            profile.cycles[address as usize] += cycles;
+        }
+        let elapsed = match self.timing {
+            Some(_) => cycles,
+            None => instruction as u64,
+        };
+        self.tick_timer(elapsed.min(u16::MAX as u64) as u16);
+    }
+
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+}
+
+#[wasm_bindgen]
+impl Core {
+    pub fn set_timing_model(&mut self, model: &TimingModel) {
+        self.timing = Some(*model);
+    }
+    pub fn clear_timing_model(&mut self) {
+        self.timing = None;
+    }
+    // Instructions executed since reset
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+    // Cycles spent since reset, counted while there is a timing model
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+    // Starts counting instructions and cycles per address, from zero.
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Profile::new());
+    }
+    pub fn profile_report(&self) -> Option<String> {
+        Some(self.profile.as_ref()?.report(&self.symbols))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::CoreConfig;
+
+    #[test]
+    fn test_cycles() {
+        let program = [
+            0x3000, //
+            0xA203, // LDI R1, PTR
+            0x3402, // ST R2, x3004
+            0x1261, // ADD R1, R1, #1
+            0xF025, // HALT
+            0xFE00, // PTR
+        ];
+        let mut c = Core::with_config(&CoreConfig::new().pc(0x3000));
+        c.load_obj(&program).unwrap();
+        c.set_timing_model(&TimingModel::parse("base=2,memory=3,device=10").unwrap());
+        c.enable_profiling();
+        c.run(3);
+        let profile = c.profile().unwrap();
+        // Fetch, pointer and KBSR
+        assert_eq!(profile.cycles(0x3000), 2 + 3 * 3 + 10);
+        assert_eq!(profile.cycles(0x3001), 2 + 2 * 3);
+        assert_eq!(profile.cycles(0x3002), 2 + 3);
+        assert_eq!((c.instructions(), c.cycles()), (3, 21 + 8 + 5));
+        // TRAP reads its vector, and pushes nothing in supervisor mode
+        c.step();
+        assert_eq!(c.profile().unwrap().cycles(0x3003), 2 + 2 * 3);
+
+        c.load_symbols("// Symbol table\n//\tMAIN              3000\n");
+        let report = c.profile_report().unwrap();
+        assert!(report.starts_with("4 instructions, 42 cycles, 10.50 cycles per instruction\n"));
+        assert!(report.contains("x3000          1         21  MAIN+0\n"));
+        assert!(report.contains("x3003          1          8  MAIN+3\n"));
+
+        assert!(TimingModel::parse("memory=x").is_err());
+        assert!(TimingModel::parse("cache=1").is_err());
+        assert_eq!(TimingModel::parse(""), Ok(TimingModel::new()));
+    }
+
+    #[test]
+    fn test_timer_cycles() {
+        // The timer counts cycles with a timing model
+        let mut c = Core::with_config(&CoreConfig::new().pc(0x3000));
+        c.set_timing_model(&TimingModel::new().memory_latency(4));
+        c.write(crate::timer::TMRR, 10);
+        c.write(crate::timer::TMCR, 0x8000);
+        c.step();
+        assert_eq!(c.read(crate::timer::TMSR), 0);
+        c.step();
+        assert_eq!(c.read(crate::timer::TMSR), 0x8000);
+    }
+}
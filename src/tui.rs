@@ -0,0 +1,352 @@
+use std::io;
+use std::time::Duration;
+
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+
+use tdal3::console::BufferConsole;
+use tdal3::disasm::disassemble;
+use tdal3::{Core, StopReason};
+
+// Instructions executed between two redraws while running.
+const STEPS_PER_FRAME: usize = 10_000;
+const MEMORY_ROW_WORDS: u16 = 8;
+// Program output kept for the console pane, in bytes
+const OUTPUT_LIMIT: usize = 64 * 1024;
+
+struct App {
+    core: Core,
+    console: BufferConsole,
+    running: bool,
+    quit: bool,
+    // Keystrokes go to the program instead of the debugger
+    input_mode: bool,
+    // Address selected in the disassembly pane
+    cursor: u16,
+    // First address shown in the memory pane
+    memory_base: u16,
+    output: String,
+    message: String,
+}
+
+pub fn run(core: Core) -> io::Result<Core> {
+    // ratatui::init installs a panic hook restoring the terminal.
+    let mut terminal = ratatui::init();
+    let mut app = App::new(core);
+    let result = app.run(&mut terminal);
+    ratatui::restore();
+    result.map(|()| app.core)
+}
+
+impl App {
+    fn new(mut core: Core) -> App {
+        let console = BufferConsole::new();
+        core.attach_console(&console);
+        let pc = core.pc();
+        App {
+            core,
+            console,
+            running: false,
+            quit: false,
+            input_mode: false,
+            cursor: pc,
+            memory_base: pc & !(MEMORY_ROW_WORDS - 1),
+            output: String::new(),
+            message: String::new(),
+        }
+    }
+
+    fn run(&mut self, terminal: &mut DefaultTerminal) -> io::Result<()> {
+        while !self.quit {
+            terminal.draw(|frame| self.draw(frame))?;
+            let timeout = if self.running {
+                Duration::from_millis(16)
+            } else {
+                Duration::from_millis(250)
+            };
+            if event::poll(timeout)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind == KeyEventKind::Press {
+                        if self.input_mode {
+                            self.handle_input(key.code);
+                        } else {
+                            self.handle_key(key.code);
+                        }
+                    }
+                }
+            }
+            if self.running {
+                let stop = self.core.run(STEPS_PER_FRAME);
+                self.report(stop);
+                self.cursor = self.core.pc();
+            }
+            self.collect_output();
+        }
+        Ok(())
+    }
+
+    // Shows why the program stopped, pausing it.
+    fn report(&mut self, stop: StopReason) {
+        self.message = match stop {
+            StopReason::Breakpoint(address) => format!("Breakpoint hit at x{:04X}", address),
+            StopReason::Halted => "Machine halted".into(),
+            StopReason::IllegalOpcode { address, word } => {
+                format!("Illegal opcode x{:04X} at x{:04X}", word, address)
+            }
+            StopReason::Uninitialized(access) => format!(
+                "Uninitialized word x{:04X} accessed at x{:04X}",
+                access.address, access.pc
+            ),
+            _ => return,
+        };
+        self.running = false;
+    }
+
+    fn collect_output(&mut self) {
+        let output = self.console.take_output();
+        self.output.push_str(&String::from_utf8_lossy(&output));
+        if self.output.len() > OUTPUT_LIMIT {
+            let mut cut = self.output.len() - OUTPUT_LIMIT;
+            while !self.output.is_char_boundary(cut) {
+                cut += 1;
+            }
+            self.output.drain(..cut);
+        }
+    }
+
+    fn handle_input(&mut self, key: KeyCode) {
+        match key {
+            KeyCode::Esc => self.input_mode = false,
+            KeyCode::Char(c) if c.is_ascii() => self.console.push_input(&[c as u8]),
+            KeyCode::Enter => self.console.push_input(b"\n"),
+            KeyCode::Backspace => self.console.push_input(&[0x08]),
+            KeyCode::Tab => self.console.push_input(b"\t"),
+            _ => (),
+        }
+    }
+
+    fn handle_key(&mut self, key: KeyCode) {
+        match key {
+            KeyCode::Char('q') => self.quit = true,
+            KeyCode::Char('s') if !self.running => {
+                // Single steps report where they land like runs do
+                let stop = match self.core.run(1) {
+                    StopReason::StepLimit if self.core.halted() => StopReason::Halted,
+                    StopReason::StepLimit if self.core.has_breakpoint(self.core.pc()) => {
+                        StopReason::Breakpoint(self.core.pc())
+                    }
+                    stop => stop,
+                };
+                self.report(stop);
+                self.cursor = self.core.pc();
+            }
+            KeyCode::Char('i') => self.input_mode = true,
+            KeyCode::Char('c') => self.running = true,
+            KeyCode::Char(' ') => self.running = false,
+            KeyCode::Char('b') => {
+                let set = self.core.toggle_breakpoint(self.cursor);
+                let action = if set { "set" } else { "removed" };
+                self.message = format!("Breakpoint {} at x{:04X}", action, self.cursor);
+            }
+            KeyCode::Char('.') => self.cursor = self.core.pc(),
+            KeyCode::Up => self.cursor = self.cursor.wrapping_sub(1),
+            KeyCode::Down => self.cursor = self.cursor.wrapping_add(1),
+            KeyCode::PageUp => {
+                self.memory_base = self.memory_base.wrapping_sub(MEMORY_ROW_WORDS * 8)
+            }
+            KeyCode::PageDown => {
+                self.memory_base = self.memory_base.wrapping_add(MEMORY_ROW_WORDS * 8)
+            }
+            KeyCode::Char('m') => self.memory_base = self.cursor & !(MEMORY_ROW_WORDS - 1),
+            _ => (),
+        }
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [main, console, help] = Layout::vertical([
+            Constraint::Min(0),
+            Constraint::Length(8),
+            Constraint::Length(1),
+        ])
+        .areas(frame.area());
+        let [left, disasm, memory] = Layout::horizontal([
+            Constraint::Length(26),
+            Constraint::Min(0),
+            Constraint::Length(48),
+        ])
+        .areas(main);
+        let [registers, calls] =
+            Layout::vertical([Constraint::Length(14), Constraint::Min(0)]).areas(left);
+
+        self.draw_registers(frame, registers);
+        self.draw_call_stack(frame, calls);
+        self.draw_disassembly(frame, disasm);
+        self.draw_memory(frame, memory);
+        self.draw_console(frame, console);
+
+        let status = if self.core.halted() {
+            "HALTED"
+        } else if self.running {
+            "RUNNING"
+        } else {
+            "PAUSED"
+        };
+        let keys = if self.input_mode {
+            " Typing to the program, Esc to stop"
+        } else {
+            " s step  c continue  space pause  b breakpoint  \u{2191}/\u{2193} move  . goto PC  m memory at cursor  PgUp/PgDn scroll memory  i input  q quit"
+        };
+        let help_line = Line::from(vec![
+            Span::styled(format!(" {} ", status), Style::new().reversed()),
+            Span::styled(format!(" {}", self.message), Style::new().bold()),
+            Span::raw(keys),
+        ]);
+        frame.render_widget(Paragraph::new(help_line), help);
+    }
+
+    fn draw_registers(&self, frame: &mut Frame, area: Rect) {
+        let c = &self.core;
+        let mut lines: Vec<Line> = (0..8)
+            .map(|i| {
+                let value = c.register(i);
+                Line::from(format!("R{}  x{:04X} {:>7}", i, value, value as i16))
+            })
+            .collect();
+        let psr = c.psr();
+        let cc = [(c.N(), 'N'), (c.Z(), 'Z'), (c.P(), 'P')]
+            .iter()
+            .map(|(set, flag)| if *set { *flag } else { '-' })
+            .collect::<String>();
+        let mode = if get_bit(psr, 15) {
+            "User"
+        } else {
+            "Supervisor"
+        };
+        lines.push(Line::from(""));
+        lines.push(Line::from(format!("PC  x{:04X}", c.pc())));
+        lines.push(Line::from(format!("PSR x{:04X}  CC {}", psr, cc)));
+        lines.push(Line::from(format!(
+            "{} priority {}",
+            mode,
+            (psr >> 8) & 0b111
+        )));
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Registers")),
+            area,
+        );
+    }
+
+    fn draw_call_stack(&self, frame: &mut Frame, area: Rect) {
+        let lines: Vec<Line> = self
+            .core
+            .call_stack()
+            .iter()
+            .rev()
+            .map(|f| {
+                let entry = match self.core.symbol_at(f.entry) {
+                    Some(name) => name,
+                    None => format!("x{:04X}", f.entry),
+                };
+                Line::from(format!("{} from x{:04X}", entry, f.call_site))
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Call stack")),
+            area,
+        );
+    }
+
+    fn draw_disassembly(&self, frame: &mut Frame, area: Rect) {
+        let height = area.height.saturating_sub(2);
+        // Keep the cursor a third of the way down the pane
+        let start = self.cursor.wrapping_sub(height / 3);
+        let pc = self.core.pc();
+        let lines: Vec<Line> = (0..height)
+            .map(|i| {
+                let address = start.wrapping_add(i);
+                let word = self.core.memory_at(address);
+                let marker = if self.core.has_breakpoint(address) {
+                    Span::styled("\u{25CF}", Style::new().fg(Color::Red))
+                } else {
+                    Span::raw(" ")
+                };
+                let arrow = if address == pc { "\u{25B6}" } else { " " };
+                let label = self.core.symbol_at(address).unwrap_or_default();
+                // Source line the word comes from, when debug info is loaded
+                let line = self
+                    .core
+                    .debug_info()
+                    .and_then(|info| info.location(address))
+                    .map(|l| l.line.to_string())
+                    .unwrap_or_default();
+                let mut style = Style::new();
+                if address == pc {
+                    style = style.fg(Color::Black).bg(Color::Yellow);
+                }
+                if address == self.cursor {
+                    style = style.bold().underlined();
+                }
+                Line::from(vec![
+                    marker,
+                    Span::styled(
+                        format!(
+                            "{} x{:04X}  {:04X} {:>5}  {:<12} {}",
+                            arrow,
+                            address,
+                            word,
+                            line,
+                            label,
+                            disassemble(word, address)
+                        ),
+                        style,
+                    ),
+                ])
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Disassembly")),
+            area,
+        );
+    }
+
+    fn draw_memory(&self, frame: &mut Frame, area: Rect) {
+        let rows = area.height.saturating_sub(2);
+        let lines: Vec<Line> = (0..rows)
+            .map(|row| {
+                let base = self.memory_base.wrapping_add(row * MEMORY_ROW_WORDS);
+                let mut text = format!("x{:04X}", base);
+                for i in 0..MEMORY_ROW_WORDS {
+                    text.push_str(&format!(
+                        " {:04X}",
+                        self.core.memory_at(base.wrapping_add(i))
+                    ));
+                }
+                Line::from(text)
+            })
+            .collect();
+        frame.render_widget(
+            Paragraph::new(lines).block(Block::bordered().title("Memory")),
+            area,
+        );
+    }
+
+    fn draw_console(&self, frame: &mut Frame, area: Rect) {
+        let height = area.height.saturating_sub(2) as usize;
+        let lines: Vec<&str> = self.output.split('\n').collect();
+        let skip = lines.len().saturating_sub(height);
+        let lines: Vec<Line> = lines[skip..].iter().map(|l| Line::from(*l)).collect();
+        let mut block = Block::bordered().title("Console");
+        if self.input_mode {
+            block = block.border_style(Style::new().fg(Color::Green));
+        }
+        frame.render_widget(Paragraph::new(lines).block(block), area);
+    }
+}
+
+fn get_bit(value: u16, bit: u16) -> bool {
+    (value >> bit) & 1 == 1
+}
@@ -0,0 +1,183 @@
+use wasm_bindgen::prelude::*;
+
+use crate::Core;
+
+// Framebuffer of 128x124 pixels at xC000, row after row, up to the device page. Pixels are
+// 15-bit RGB, 5 bits per channel with red in bits 14-10.
+pub const VIDEO_START: u16 = 0xC000;
+pub const VIDEO_WIDTH: usize = 128;
+pub const VIDEO_HEIGHT: usize = 124;
+const VIDEO_SIZE: usize = VIDEO_WIDTH * VIDEO_HEIGHT;
+
+// 5-bit channel to 8 bits, white staying white
+fn channel(pixel: u16, shift: u16) -> u8 {
+    let value = ((pixel >> shift) & 0x1F) as u8;
+    (value << 3) | (value >> 2)
+}
+
+impl Core {
+    pub(crate) fn is_video(&self, address: u16) -> bool {
+        self.video
+            && (VIDEO_START as usize..VIDEO_START as usize + VIDEO_SIZE)
+                .contains(&(address as usize))
+    }
+
+    fn framebuffer(&self) -> &[u16] {
+        let start = VIDEO_START as usize;
+        &self.memory[start..start + VIDEO_SIZE]
+    }
+}
+
+#[wasm_bindgen]
+impl Core {
+    // Treats xC000-xFDFF as a framebuffer. Its words count as initialized, the screen
+    // starting black or with the power-on contents.
+    pub fn enable_video(&mut self) {
+        self.video = true;
+        for address in VIDEO_START as usize..VIDEO_START as usize + VIDEO_SIZE {
+            self.initialized.mark(address as u16);
+        }
+        self.video_dirty = true;
+    }
+    pub fn video_enabled(&self) -> bool {
+        self.video
+    }
+    // Whether a store changed the framebuffer since the last call, to redraw only then.
+    pub fn take_video_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.video_dirty)
+    }
+    // Pixels as 8-bit RGBA, row after row, e.g. for an ImageData.
+    pub fn video_rgba(&self) -> Vec<u8> {
+        self.framebuffer()
+            .iter()
+            .flat_map(|&pixel| {
+                [
+                    channel(pixel, 10),
+                    channel(pixel, 5),
+                    channel(pixel, 0),
+                    0xFF,
+                ]
+            })
+            .collect()
+    }
+    #[cfg(target_arch = "wasm32")]
+    pub unsafe fn video_view(&self) -> js_sys::Uint16Array {
+        js_sys::Uint16Array::view(self.framebuffer())
+    }
+}
+
+/// Binary PPM (P6) of an RGBA image, alpha dropped.
+pub fn to_ppm(rgba: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut out = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    for pixel in rgba.chunks(4) {
+        out.extend_from_slice(&pixel[..3]);
+    }
+    out
+}
+
+// CRC-32 of PNG chunks (ISO 3309)
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    !crc
+}
+
+fn adler32(bytes: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in bytes {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    (b << 16) | a
+}
+
+// zlib stream of stored (uncompressed) deflate blocks, which every decoder reads.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        out.push(blocks.peek().is_none() as u8);
+        let length = block.len() as u16;
+        out.extend_from_slice(&length.to_le_bytes());
+        out.extend_from_slice(&(!length).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn png_chunk(out: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(kind);
+    out.extend_from_slice(data);
+    let crc = crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// PNG of an RGBA image. Not compressed, which keeps it simple and the framebuffer small
+/// enough anyway.
+pub fn to_png(rgba: &[u8], width: usize, height: usize) -> Vec<u8> {
+    let mut header = Vec::new();
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // 8 bits per channel, RGBA, then the default compression, filter and no interlace
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+    // Each row starts with its filter, none here
+    let mut rows = Vec::with_capacity((width * 4 + 1) * height);
+    for row in rgba.chunks(width * 4) {
+        rows.push(0);
+        rows.extend_from_slice(row);
+    }
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    png_chunk(&mut out, b"IHDR", &header);
+    png_chunk(&mut out, b"IDAT", &zlib_stored(&rows));
+    png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_framebuffer() {
+        let mut c = Core::new();
+        assert!(!c.is_initialized(VIDEO_START));
+        c.enable_video();
+        assert!(c.is_initialized(0xFDFF));
+        assert!(c.take_video_dirty());
+        assert!(!c.take_video_dirty());
+        // Red, then white at the end of the first row
+        c.write(VIDEO_START, 0x7C00);
+        c.write(VIDEO_START + 127, 0x7FFF);
+        assert!(c.take_video_dirty());
+        let rgba = c.video_rgba();
+        assert_eq!(rgba.len(), VIDEO_WIDTH * VIDEO_HEIGHT * 4);
+        assert_eq!(rgba[..8], [0xFF, 0, 0, 0xFF, 0, 0, 0, 0xFF]);
+        assert_eq!(rgba[127 * 4..128 * 4], [0xFF; 4]);
+    }
+
+    #[test]
+    fn test_images() {
+        let rgba = [0xFF, 0, 0, 0xFF, 0, 0x80, 0xFF, 0xFF];
+        assert_eq!(to_ppm(&rgba, 2, 1), b"P6\n2 1\n255\n\xFF\0\0\0\x80\xFF");
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+        let png = to_png(&rgba, 2, 1);
+        assert!(png.starts_with(b"\x89PNG\r\n\x1a\n\0\0\0\x0dIHDR\0\0\0\x02\0\0\0\x01"));
+        assert!(png.ends_with(b"\0\0\0\0IEND\xAE\x42\x60\x82"));
+        // A single stored block of the filter byte and the two pixels
+        let idat = &png[33..];
+        assert_eq!(idat[..8], [0, 0, 0, 20, b'I', b'D', b'A', b'T']);
+        assert_eq!(idat[8..15], [0x78, 0x01, 1, 9, 0, 0xF6, 0xFF]);
+    }
+}
@@ -1,11 +1,48 @@
 import { createSignal, For } from 'solid-js'
 import './App.css'
-import { Core,assemble_file } from '../../pkg/tdal3.js'
+import { BufferConsole,Core,CoreConfig,InputScript,assemble } from '../../pkg/tdal3.js'
+// Keys typed in the console go to the program through it, and what it prints comes back
+let terminal = new BufferConsole();
+const decoder = new TextDecoder();
+
 function App() {
   const [core, setCore] = createSignal(new Core(), {equals: () => false});
-  
+  const [source, setSource] = createSignal<string[]>([]);
+  const [output, setOutput] = createSignal("");
+  core().attach_console(terminal);
+  const refresh = () => {
+    setOutput(output() + decoder.decode(terminal.take_output()));
+    setCore(core);
+  };
+  // Assembles the program into a fresh core, starting from its origin
+  const load = () => {
+    //@ts-ignore
+    let content = document.getElementById("code")?.value.split("\n");
+    console.log(content);
+    let assembly = assemble(content);
+    let object = assembly.object();
+    let newCore = Core.with_config(new CoreConfig().pc(object[0]));
+    newCore.load_obj(object);
+    newCore.load_debug_info(assembly.debug_info("main.asm"));
+    // Keys typed for the previous program are dropped
+    terminal = new BufferConsole();
+    newCore.attach_console(terminal);
+    setOutput("");
+    setSource(content);
+    setCore(newCore);
+    return newCore;
+  };
+  const typeKey = (e: KeyboardEvent) => {
+    const byte = e.key == "Enter" ? 10 : e.key == "Backspace" ? 8 : e.key.length == 1 ? e.key.charCodeAt(0) : -1;
+    if (byte >= 0 && byte < 128) {
+      terminal.push_input(new Uint8Array([byte]));
+      e.preventDefault();
+    }
+  };
+
   const registers = () => core().registers_view();
   const pc = () => core().pc();
+  const currentLine = () => core().current_line();
   return (
     <>
       <div>
@@ -13,14 +50,7 @@ function App() {
         <textarea id="code" style="width: 500px; height: 200px;" value={[".ORIG x200\nADD R2, R7, #7\nADD R2, R2, #3"]}/>
         <br/>
         <button on:click={() => {
-          //@ts-ignore
-          let content = document.getElementById("code")?.value.split("\n");
-          console.log(content);
-          let assembled = assemble_file(content);
-          console.log(assembled);
-          let newCore = new Core();
-          newCore.load_obj(assembled);
-          setCore(newCore);
+          load();
         }}>Assemble !</button>
         <h1>Pc: 0x{pc().toString(16)} </h1>
         <h1>Registers: </h1>
@@ -28,21 +58,59 @@ function App() {
           <span> {val} </span>
         )}
         </For>
+        <h1>Source: </h1>
+        <pre>
+          <For each={source()}>{(line, i) => (
+            // Clicking a line toggles a breakpoint on it
+            <div
+              style={{ background: currentLine() == i() + 1 ? "yellow" : "none", cursor: "pointer" }}
+              on:click={() => {
+                core().toggle_line_breakpoint(i() + 1);
+                setCore(core);
+              }}>
+              <span style="color: red">{core().has_line_breakpoint(i() + 1) ? "●" : " "}</span>
+              {String(i() + 1).padStart(4)} {line}
+            </div>
+          )}
+          </For>
+        </pre>
         <button on:click={() => {
           core().step()
-          setCore(core);
+          refresh();
         }} >Step</button>
         <button on:click={() => {
           //@ts-ignore
           window.i = setInterval(() => {
-          core().step()
-          setCore(core);
+          // Stops on breakpoints and when the machine halts
+          if (!core().run_for(100)) {
+            //@ts-ignore
+            clearInterval(window.i)
+          }
+          refresh();
           }, 5)
         }} >Loop</button>
         <button on:click={() => {
           //@ts-ignore
-          clearInterval(window.i)            
+          clearInterval(window.i)
         }} >stop</button>
+        <h1>Console: </h1>
+        <pre tabIndex={0} style="width: 500px; min-height: 100px; border: 1px solid gray;" on:keydown={typeKey}>{output()}</pre>
+        <h1>Input: </h1>
+        <textarea id="input" style="width: 500px; height: 100px;"/>
+        <br/>
+        <button on:click={() => {
+          core().start_recording()
+        }} >Record</button>
+        <button on:click={() => {
+          // Shows what was typed since Record, to save or replay it
+          //@ts-ignore
+          document.getElementById("input").value = core().recorded_input()?.serialize() ?? ""
+        }} >Show recording</button>
+        <button on:click={() => {
+          // Runs the program again from the start with the keys of the script
+          //@ts-ignore
+          load().replay_input(InputScript.parse(document.getElementById("input")?.value))
+        }} >Replay</button>
       </div>
     </>
   )